@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+
+use clap::ArgMatches;
+use r_jq::serde_json::{self, Value};
+use r_jq::variables::Variables;
+
+use crate::Cli;
+
+/// How a trailing command line argument is used.  `--args` and `--jsonargs`
+/// apply to every argument after them, until the other one is given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Positional {
+    File,
+    String,
+    Json,
+}
+
+/// The values of an option that takes `NAME VALUE` pairs, with the position
+/// of each pair on the command line
+fn named_pairs<'c>(
+    values: &'c [String],
+    matches: &ArgMatches,
+    id: &str,
+) -> Vec<(usize, &'c str, &'c str)> {
+    let indices: Vec<usize> = matches.indices_of(id).into_iter().flatten().collect();
+    values
+        .chunks(2)
+        .zip(indices.chunks(2))
+        .map(|(pair, index)| (index[0], pair[0].as_str(), pair[1].as_str()))
+        .collect()
+}
+
+fn parse_json(text: &str, option: &str) -> Result<Value, Box<dyn Error>> {
+    serde_json::from_str(text)
+        .map_err(|error| format!("Invalid JSON text passed to --{}: {}", option, error).into())
+}
+
+/// Every JSON text in a file, ie: for `--slurpfile`
+pub fn read_json_texts(path: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+    let contents = fs::read(path).map_err(|error| format!("{}: {}", path, error))?;
+    serde_json::Deserializer::from_slice(&contents)
+        .into_iter::<Value>()
+        .collect::<Result<_, _>>()
+        .map_err(|error| format!("{}: {}", path, error).into())
+}
+
+/// The variables set by `--arg`, `--argjson`, `--slurpfile`, `--rawfile`, and
+/// the positional arguments after `--args` or `--jsonargs`.  Also returns the
+/// remaining arguments, which are input files.
+pub fn command_line_vars(
+    cli: &Cli,
+    matches: &ArgMatches,
+) -> Result<(Variables, Vec<String>), Box<dyn Error>> {
+    let mut named: Vec<(usize, &str, Value)> = Vec::new();
+    for (index, name, value) in named_pairs(&cli.arg, matches, "arg") {
+        named.push((index, name, Value::from(value)));
+    }
+    for (index, name, text) in named_pairs(&cli.argjson, matches, "argjson") {
+        named.push((index, name, parse_json(text, "argjson")?));
+    }
+    for (index, name, path) in named_pairs(&cli.slurpfile, matches, "slurpfile") {
+        named.push((index, name, Value::from(read_json_texts(path)?)));
+    }
+    for (index, name, path) in named_pairs(&cli.rawfile, matches, "rawfile") {
+        let contents = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        named.push((index, name, Value::from(contents)));
+    }
+    // As in jq, the first option for a name wins, and later ones are ignored
+    named.sort_by_key(|(index, _, _)| *index);
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut vars = named
+        .into_iter()
+        .filter(|(_, name, _)| seen.insert(name))
+        .fold(Variables::new(), |vars, (_, name, value)| {
+            vars.with_var(name, value)
+        });
+
+    let mut modes: Vec<(usize, Positional)> = Vec::new();
+    for (id, mode) in [("args", Positional::String), ("jsonargs", Positional::Json)] {
+        modes.extend(
+            matches
+                .indices_of(id)
+                .into_iter()
+                .flatten()
+                .map(|index| (index, mode)),
+        );
+    }
+    modes.sort_by_key(|(index, _)| *index);
+
+    let indices = matches.indices_of("files").into_iter().flatten();
+    let mut positional: Vec<Value> = Vec::new();
+    let mut files: Vec<String> = Vec::new();
+    for (index, value) in indices.zip(&cli.files) {
+        let mode = modes
+            .iter()
+            .take_while(|(mode_index, _)| *mode_index < index)
+            .last()
+            .map_or(Positional::File, |(_, mode)| *mode);
+        match mode {
+            Positional::File => files.push(value.to_string()),
+            Positional::String => positional.push(Value::from(value.as_str())),
+            Positional::Json => positional.push(parse_json(value, "jsonargs")?),
+        }
+    }
+    if !positional.is_empty() {
+        vars = vars.with_positional(positional);
+    }
+    Ok((vars, files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+    use r_jq::serde_json::json;
+
+    fn parse(args: &[&str]) -> Result<(Variables, Vec<String>), Box<dyn Error>> {
+        let matches = Cli::command().try_get_matches_from(args)?;
+        let cli = Cli::from_arg_matches(&matches)?;
+        command_line_vars(&cli, &matches)
+    }
+
+    #[test]
+    fn test_named() {
+        let (vars, files) = parse(&[
+            "rjq",
+            "--arg",
+            "a",
+            "1",
+            "--argjson",
+            "b",
+            "{\"c\": -1}",
+            ".",
+            "--arg",
+            "a",
+            "-2",
+        ])
+        .expect("failed");
+        assert_eq!(vars.get("a"), Some(&json!("1")));
+        assert_eq!(vars.get("b"), Some(&json!({"c": -1})));
+        assert!(files.is_empty());
+        assert_eq!(vars.args()["named"], json!({"a": "1", "b": {"c": -1}}));
+    }
+
+    #[test]
+    fn test_invalid_argjson() {
+        let error = parse(&["rjq", "--argjson", "a", "{", "."]).expect_err("should fail");
+        assert!(error
+            .to_string()
+            .starts_with("Invalid JSON text passed to --argjson"));
+    }
+
+    #[test]
+    fn test_positional() {
+        let (vars, files) = parse(&[
+            "rjq",
+            ".",
+            "input.json",
+            "--args",
+            "a",
+            "1",
+            "--jsonargs",
+            "1",
+            "{}",
+            "--args",
+            "b",
+        ])
+        .expect("failed");
+        assert_eq!(files, ["input.json"]);
+        assert_eq!(vars.args()["positional"], json!(["a", "1", 1, {}, "b"]));
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir();
+        let json_path = dir.join("rjq_test_slurpfile.json");
+        let raw_path = dir.join("rjq_test_rawfile.txt");
+        fs::write(&json_path, "1 {\"a\": 2}\n[3]").expect("failed to write");
+        fs::write(&raw_path, "line 1\nline 2\n").expect("failed to write");
+
+        let (vars, _) = parse(&[
+            "rjq",
+            "--slurpfile",
+            "s",
+            json_path.to_str().expect("path"),
+            "--rawfile",
+            "r",
+            raw_path.to_str().expect("path"),
+            ".",
+        ])
+        .expect("failed");
+        assert_eq!(vars.get("s"), Some(&json!([1, {"a": 2}, [3]])));
+        assert_eq!(vars.get("r"), Some(&json!("line 1\nline 2\n")));
+
+        assert!(parse(&["rjq", "--rawfile", "r", "/nonexistent/rjq", "."]).is_err());
+    }
+}
@@ -1,18 +1,13 @@
 use std::io::{stdin, Read};
 
-use clap::Parser;
+use arguments::*;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use pretty_print::*;
-use r_jq::jq;
+use r_jq::compiled::{CompileOptions, Query};
+use r_jq::serde_json::Value;
+mod arguments;
 mod pretty_print;
-/*
-  --tab            use tabs for indentation;
-  --arg a v        set variable $a to value <v>;
-  --argjson a v    set variable $a to JSON value <v>;
-  --slurpfile a f  set variable $a to an array of JSON texts read from <f>;
-  --rawfile a f    set variable $a to a string consisting of the contents of <f>;
-  --args           remaining arguments are string arguments, not files;
-  --jsonargs       remaining arguments are JSON arguments, not files;
-*/
+
 #[derive(Parser)]
 #[clap(author, version, long_about = None)]
 #[clap(about("commandline JSON processor "))]
@@ -61,33 +56,134 @@ pub struct Cli {
     #[clap(short, long)]
     version: bool,
 
+    /// set variable $a to value <v>;
+    #[clap(
+        long,
+        value_names = &["a", "v"],
+        number_of_values = 2,
+        multiple_occurrences = true,
+        allow_hyphen_values = true
+    )]
+    arg: Vec<String>,
+
+    /// set variable $a to JSON value <v>;
+    #[clap(
+        long,
+        value_names = &["a", "v"],
+        number_of_values = 2,
+        multiple_occurrences = true,
+        allow_hyphen_values = true
+    )]
+    argjson: Vec<String>,
+
+    /// set variable $a to an array of JSON texts read from <f>;
+    #[clap(
+        long,
+        value_names = &["a", "f"],
+        number_of_values = 2,
+        multiple_occurrences = true
+    )]
+    slurpfile: Vec<String>,
+
+    /// set variable $a to a string consisting of the contents of <f>;
+    #[clap(
+        long,
+        value_names = &["a", "f"],
+        number_of_values = 2,
+        multiple_occurrences = true
+    )]
+    rawfile: Vec<String>,
+
+    /// search <directory> for modules, instead of the default search path;
+    #[clap(
+        short = 'L',
+        long = "library-path",
+        value_name = "directory",
+        multiple_occurrences = true
+    )]
+    library_path: Vec<String>,
+
+    /// remaining arguments are string arguments, not files;
+    #[clap(long, parse(from_occurrences))]
+    args: u64,
+
+    /// remaining arguments are JSON arguments, not files;
+    #[clap(long, parse(from_occurrences))]
+    jsonargs: u64,
+
     /// JQ style query statement
     #[clap(value_parser)]
     query: String,
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+    /// input files, or positional arguments after --args or --jsonargs
+    #[clap(value_parser)]
+    files: Vec<String>,
+}
 
-    let mut buffer = Vec::new();
-    let mut stdin = stdin();
+/// How deeply a query may nest calls of functions and filter parameters
+const MAX_DEPTH: usize = 10_000;
+
+/// The stack size of the thread that runs the query.  It leaves room for
+/// [MAX_DEPTH] nested calls, even in a debug build.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+fn main() {
+    let query_thread = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| run().map_err(|error| format!("{:?}", error)))
+        .expect("failed to start the query thread");
+    // Report an error as returning it from main would
+    match query_thread.join() {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => {
+            eprintln!("Error: {}", error);
+            std::process::exit(1);
+        }
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
 
-    // Read and parse the json input.
-    let _ = stdin.read_to_end(&mut buffer)?;
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    let (vars, files) = command_line_vars(&cli, &matches)?;
+    let options = vars.names().into_iter().fold(
+        CompileOptions::new().with_max_depth(MAX_DEPTH),
+        CompileOptions::with_var,
+    );
+    let options = cli
+        .library_path
+        .iter()
+        .fold(options, |options, dir| options.with_search_path(dir));
+    let query = Query::compile_with_options(&cli.query, &options)?;
+
+    // Read and parse the json input, from the files if there are any.
+    let inputs = if cli.null {
+        vec![Value::Null]
+    } else if files.is_empty() {
+        let mut buffer = Vec::new();
+        let _ = stdin().read_to_end(&mut buffer)?;
+        vec![r_jq::serde_json::from_slice(&buffer)?]
+    } else {
+        let mut inputs = Vec::new();
+        for file in &files {
+            inputs.extend(read_json_texts(file)?);
+        }
+        inputs
+    };
 
-    let results = jq(&buffer, &cli.query)?;
+    let mut results = Vec::new();
+    for input in &inputs {
+        results.extend(query.run_with(input, &vars)?);
+    }
     let mut pretty = PrettyPrint::new()
         .with_compact(cli.compact)
         .with_use_tabs(cli.tab);
 
     for result in results {
         match &result {
-            r_jq::serde_json::Value::String(s) => {
-                if cli.raw_out {
-                    print!("{}", s);
-                } else {
-                    let _ = pretty.print(&result, true);
-                }
+            r_jq::serde_json::Value::String(s) if cli.raw_out => {
+                print!("{}", s);
             }
             _ => {
                 let _ = pretty.print(&result, true);
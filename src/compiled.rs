@@ -0,0 +1,438 @@
+/// A compiled query.
+///
+/// Parsing is paid for once in [Query::compile], and the resulting [Query]
+/// can be run against any number of inputs, from any number of threads.
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    parse, parse_with_options, parse_with_vars, predefined, query_iter, Env, Expr,
+    FunctionRegistry, Input, JQError, Loader, Variables, MAX_CALL_DEPTH,
+};
+
+/// Options for [Query::compile_with_options]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompileOptions {
+    vars: Vec<String>,
+    loader: Loader,
+    functions: FunctionRegistry,
+    max_depth: Option<usize>,
+}
+
+impl CompileOptions {
+    /// No variables besides the predefined ones, and the default module search path
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow the query to use `$name`.  Its value is given to [Query::run_with].
+    pub fn with_var(mut self, name: &str) -> Self {
+        self.vars.push(name.to_string());
+        self
+    }
+
+    /// Add a directory to the search path for modules named by `import` and
+    /// `include`.  Once a directory is added, the default search path is not used.
+    pub fn with_search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.loader = self.loader.with_search_path(dir);
+        self
+    }
+
+    /// Native functions that the query can call.  The [Query] keeps them to
+    /// call when it runs.
+    pub fn with_functions(mut self, functions: FunctionRegistry) -> Self {
+        self.functions = functions;
+        self
+    }
+
+    /// How deeply the query may nest calls of functions and filter parameters
+    /// when it runs, instead of [MAX_CALL_DEPTH].  Each nested call uses more
+    /// of the native stack, so a larger limit needs a thread with a larger stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// The names of the variables
+    pub fn vars(&self) -> impl Iterator<Item = &str> {
+        self.vars.iter().map(String::as_str)
+    }
+
+    /// The module loader
+    pub fn loader(&self) -> &Loader {
+        &self.loader
+    }
+
+    /// The native functions
+    pub fn functions(&self) -> &FunctionRegistry {
+        &self.functions
+    }
+
+    /// How deeply the query may nest calls
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.unwrap_or(MAX_CALL_DEPTH)
+    }
+}
+
+/// A parsed query that is ready to run.  Native functions and the call depth
+/// limit are not serialized, so a deserialized query that calls native
+/// functions fails when it runs, and may nest calls [MAX_CALL_DEPTH] deep.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Query {
+    expr: Expr,
+    #[serde(skip)]
+    functions: FunctionRegistry,
+    #[serde(skip, default = "max_call_depth")]
+    max_depth: usize,
+}
+
+fn max_call_depth() -> usize {
+    MAX_CALL_DEPTH
+}
+
+impl Query {
+    /// Parse a query string.  Returns [JQError::ParseError] if the query is not valid.
+    pub fn compile(query_str: &str) -> Result<Self, JQError> {
+        Ok(Self::from(parse(query_str)?))
+    }
+
+    /// Parse a query string that uses the variables named in `vars`, such as
+    /// `["name"]` for `select(.name == $name)`.  Their values are given to
+    /// [Query::run_with].
+    pub fn compile_with_vars(query_str: &str, vars: &[&str]) -> Result<Self, JQError> {
+        Ok(Self::from(parse_with_vars(query_str, vars)?))
+    }
+
+    /// Parse a query string with [CompileOptions].  Modules that the query
+    /// imports are loaded now, so the [Query] does not need them to run.
+    pub fn compile_with_options(
+        query_str: &str,
+        options: &CompileOptions,
+    ) -> Result<Self, JQError> {
+        Ok(Self {
+            expr: parse_with_options(query_str, options)?,
+            functions: options.functions.clone(),
+            max_depth: options.max_depth(),
+        })
+    }
+
+    /// The parsed expression tree
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    /// Run the query against a single input, and return all of the results
+    pub fn run(&self, input: &Value) -> Result<Vec<Value>, JQError> {
+        self.run_ref(input)
+            .map(|result| result.map(Cow::into_owned))
+            .collect()
+    }
+
+    /// Run the query against a single input, with values for its variables
+    pub fn run_with(&self, input: &Value, vars: &Variables) -> Result<Vec<Value>, JQError> {
+        self.run_ref_with(input, vars)
+            .map(|result| result.map(Cow::into_owned))
+            .collect()
+    }
+
+    /// Run the query against a single input, and lazily iterate the results.
+    /// Each result is computed as it is pulled.  An error is returned as the final item.
+    pub fn run_iter<'a>(
+        &'a self,
+        input: &'a Value,
+    ) -> impl Iterator<Item = Result<Value, JQError>> + 'a {
+        self.run_ref(input)
+            .map(|result| result.map(Cow::into_owned))
+    }
+
+    /// Run the query against a single input, and lazily iterate the results.
+    /// Results that are part of the input are borrowed rather than cloned.
+    /// An error is returned as the final item.
+    pub fn run_ref<'a>(
+        &'a self,
+        input: &'a Value,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, JQError>> + 'a {
+        self.run_in(input, predefined())
+    }
+
+    /// [Query::run_ref], with values for the variables of the query
+    pub fn run_ref_with<'a>(
+        &'a self,
+        input: &'a Value,
+        vars: &'a Variables,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, JQError>> + 'a {
+        self.run_in(input, vars.env())
+    }
+
+    /// Run the query in `env`, with the native functions and call depth limit
+    /// of the query
+    fn run_in<'a>(
+        &'a self,
+        input: &'a Value,
+        env: Env<'a>,
+    ) -> impl Iterator<Item = Result<Cow<'a, Value>, JQError>> + 'a {
+        query_iter(
+            Input::from(input),
+            &self.expr,
+            &env.with_functions(&self.functions)
+                .with_max_depth(self.max_depth),
+        )
+        .map(|result| result.map(Input::into_cow))
+        .scan(false, stop_after_error)
+    }
+}
+
+/// Used with `scan` to end the results after the first error
+fn stop_after_error<T>(
+    failed: &mut bool,
+    result: Result<T, JQError>,
+) -> Option<Result<T, JQError>> {
+    if *failed {
+        return None;
+    }
+    *failed = result.is_err();
+    Some(result)
+}
+
+impl From<Expr> for Query {
+    fn from(expr: Expr) -> Self {
+        Self {
+            expr,
+            functions: FunctionRegistry::new(),
+            max_depth: MAX_CALL_DEPTH,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_run_many() {
+        let query = Query::compile(".a + 1").expect("failed to compile");
+        for n in 0..3 {
+            let result = query.run(&json!({ "a": n })).expect("failed");
+            assert_eq!(result, [json!(n + 1)]);
+        }
+    }
+
+    #[test]
+    fn test_compile_error() {
+        assert!(matches!(Query::compile(".a |"), Err(JQError::ParseError)));
+    }
+
+    #[test]
+    fn test_run_iter() {
+        let query = Query::compile(".[]").expect("failed to compile");
+        let input = json!([1, 2]);
+        let result: Vec<Value> = query
+            .run_iter(&input)
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert_eq!(result, [json!(1), json!(2)]);
+
+        let query = Query::compile(".a, 1").expect("failed to compile");
+        let mut results = query.run_iter(&json!(1)).collect::<Vec<_>>();
+        assert_eq!(results.len(), 1);
+        assert!(results.remove(0).is_err());
+
+        let query = Query::compile("1, .a, 2").expect("failed to compile");
+        let input = json!(1);
+        let mut results = query.run_iter(&input);
+        assert_eq!(results.next().expect("missing").expect("failed"), json!(1));
+        assert!(results.next().expect("missing").is_err());
+        assert!(results.next().is_none());
+    }
+
+    #[test]
+    fn test_compile_with_vars() {
+        assert!(matches!(
+            Query::compile(".a + $n"),
+            Err(JQError::UndefinedVariable(_))
+        ));
+        let query = Query::compile_with_vars(".a + $n", &["n"]).expect("failed to compile");
+        for n in 0..3 {
+            let vars = Variables::new().with_var("n", json!(n));
+            let result = query.run_with(&json!({"a": 1}), &vars).expect("failed");
+            assert_eq!(result, [json!(n + 1)]);
+        }
+
+        // A declared variable without a value is an error when the query runs
+        let result = query.run_with(&json!({"a": 1}), &Variables::new());
+        assert!(matches!(result, Err(JQError::UndefinedVariable(_))));
+    }
+
+    #[test]
+    fn test_predefined_vars() {
+        let query =
+            Query::compile("$__prog_args, ($ENV | length >= 0)").expect("failed to compile");
+        assert_eq!(
+            query.run(&Value::Null).expect("failed"),
+            [json!([]), json!(true)]
+        );
+
+        let vars = Variables::new().with_positional(vec![json!(1), json!("two")]);
+        let result = query.run_with(&Value::Null, &vars).expect("failed");
+        assert_eq!(result[0], json!([1, "two"]));
+    }
+
+    #[test]
+    fn test_run_ref_with() {
+        // Values from both the input and the variables are borrowed
+        let query = Query::compile_with_vars(".a, ($v | .b)", &["v"]).expect("failed to compile");
+        let input = json!({"a": [1]});
+        let vars = Variables::new().with_var("v", json!({"b": [2]}));
+        let result: Vec<Cow<Value>> = query
+            .run_ref_with(&input, &vars)
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert!(matches!(result[0], Cow::Borrowed(value) if std::ptr::eq(value, &input["a"])));
+        let v = vars.get("v").expect("missing");
+        assert!(matches!(result[1], Cow::Borrowed(value) if std::ptr::eq(value, &v["b"])));
+    }
+
+    #[test]
+    fn test_compile_with_options() {
+        let options = CompileOptions::new()
+            .with_var("n")
+            .with_search_path(concat!(env!("CARGO_MANIFEST_DIR"), "/test/modules"));
+        let query = Query::compile_with_options(
+            r#"import "lib" as lib; import "data" as $d; $n | lib::double, $d::d[1].a"#,
+            &options,
+        )
+        .expect("failed to compile");
+        let vars = Variables::new().with_var("n", json!(3));
+        let result = query.run_with(&Value::Null, &vars).expect("failed");
+        assert_eq!(result, [json!(6), json!(2)]);
+
+        assert!(matches!(
+            Query::compile_with_options(r#"import "missing" as m; ."#, &options),
+            Err(JQError::ModuleNotFound(_))
+        ));
+
+        let query_str = "def f: if . < 10 then .+1|f else . end; f";
+        let options = CompileOptions::new().with_max_depth(5);
+        let query = Query::compile_with_options(query_str, &options).expect("failed to compile");
+        assert!(matches!(
+            query.run(&json!(0)),
+            Err(JQError::RecursionLimit(5))
+        ));
+        assert_eq!(query.run(&json!(6)).expect("failed"), [json!(10)]);
+        assert!(matches!(
+            Query::compile(r#"import "lib" as lib; lib::double"#),
+            Err(JQError::ModuleNotFound(_))
+        ));
+    }
+
+    fn natives() -> CompileOptions {
+        let users = json!({"1": "ann", "2": "bob"});
+        let functions = FunctionRegistry::new()
+            .with_function("lookup_user", 1, move |_: &Value, args: &[Value]| {
+                let id = args[0].to_string();
+                Ok(users.get(&id).cloned().unwrap_or_default())
+            })
+            .with_function("pair", 2, |input: &Value, args: &[Value]| {
+                Ok(json!([input, args[0], args[1]]))
+            })
+            .with_function(
+                "triple_native",
+                0,
+                |input: &Value, _: &[Value]| match input.as_i64() {
+                    Some(n) => Ok(Value::from(n * 3)),
+                    None => Err(JQError::InvalidArgument(
+                        input.to_string(),
+                        "triple_native".to_string(),
+                    )),
+                },
+            );
+        CompileOptions::new().with_functions(functions)
+    }
+
+    #[test]
+    fn test_native_functions() {
+        let query = Query::compile_with_options(".[] | lookup_user(.id)", &natives())
+            .expect("failed to compile");
+        let result = query
+            .run(&json!([{"id": 2}, {"id": 1}, {"id": 3}]))
+            .expect("failed");
+        assert_eq!(result, [json!("bob"), json!("ann"), Value::Null]);
+
+        // Called for each combination of the argument results
+        let query =
+            Query::compile_with_options("[pair(1, 2; .)]", &natives()).expect("failed to compile");
+        assert_eq!(
+            query.run(&json!("x")).expect("failed"),
+            [json!([["x", 1, "x"], ["x", 2, "x"]])]
+        );
+
+        let query =
+            Query::compile_with_options("triple_native", &natives()).expect("failed to compile");
+        assert_eq!(query.run(&json!(2)).expect("failed"), [json!(6)]);
+        assert!(matches!(
+            query.run(&json!("a")),
+            Err(JQError::InvalidArgument(..))
+        ));
+        let vars = Variables::new();
+        assert_eq!(
+            query.run_with(&json!(1), &vars).expect("failed"),
+            [json!(3)]
+        );
+
+        // Resolved by name and arity when the query is compiled
+        assert!(matches!(
+            Query::compile("lookup_user(1)"),
+            Err(JQError::UndefinedFunction(_))
+        ));
+        assert!(matches!(
+            Query::compile_with_options("lookup_user", &natives()),
+            Err(JQError::UndefinedFunction(_))
+        ));
+    }
+
+    #[test]
+    fn test_native_shadowing() {
+        let options = natives().with_functions(FunctionRegistry::new().with_function(
+            "length",
+            0,
+            |_: &Value, _: &[Value]| Ok(json!(42)),
+        ));
+        let query = Query::compile_with_options("length, (def length: 0; length)", &options)
+            .expect("failed to compile");
+        assert_eq!(
+            query.run(&json!([])).expect("failed"),
+            [json!(42), json!(0)]
+        );
+    }
+
+    #[test]
+    fn test_native_in_module() {
+        let options =
+            natives().with_search_path(concat!(env!("CARGO_MANIFEST_DIR"), "/test/modules"));
+        let query = Query::compile_with_options(r#"import "native" as n; n::quadruple"#, &options)
+            .expect("failed to compile");
+        assert_eq!(query.run(&json!(2)).expect("failed"), [json!(8)]);
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Query>();
+
+        let query = Arc::new(Query::compile(".id").expect("failed to compile"));
+        let handles: Vec<_> = (0..4)
+            .map(|n| {
+                let query = Arc::clone(&query);
+                std::thread::spawn(move || query.run(&json!({ "id": n })).expect("failed"))
+            })
+            .collect();
+        for (n, handle) in handles.into_iter().enumerate() {
+            assert_eq!(handle.join().expect("thread failed"), [json!(n)]);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Expr;
+
+/// A parameter of a user defined function
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum Param {
+    /// `f`, a filter that is evaluated against the input of each call to `f`
+    /// inside the body, in the scope of the caller
+    Filter(String),
+    /// `$x`, the body is evaluated once for each result of the argument, with
+    /// the result bound to `$x`.  `x` can also be called as a filter.
+    Variable(String),
+}
+
+impl Param {
+    /// The name of the parameter, without a leading `$`
+    pub fn name(&self) -> &str {
+        match self {
+            Param::Filter(name) | Param::Variable(name) => name,
+        }
+    }
+}
+
+/// A user defined function: `def name(params): body;`
+///
+/// A function is identified by its name and number of parameters, so `f` and
+/// `f(g)` are different functions.  The body can call the function itself,
+/// and sees the variables and functions that were in scope where it was
+/// defined, not those of the caller.
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Definition {
+    name: String,
+    params: Vec<Param>,
+    body: Expr,
+}
+
+impl Definition {
+    /// Create a function definition
+    pub fn new(name: &str, params: Vec<Param>, body: Expr) -> Self {
+        Self {
+            name: name.to_string(),
+            params,
+            body,
+        }
+    }
+
+    /// The name of the function
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The parameters of the function, in order
+    pub fn params(&self) -> &[Param] {
+        &self.params
+    }
+
+    /// The number of parameters
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    /// The body of the function
+    pub fn body(&self) -> &Expr {
+        &self.body
+    }
+
+    pub(crate) fn body_mut(&mut self) -> &mut Expr {
+        &mut self.body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_definition() {
+        let params = vec![
+            Param::Filter("f".to_string()),
+            Param::Variable("x".to_string()),
+        ];
+        let definition = Definition::new("apply", params, Expr::Identity);
+        assert_eq!(definition.name(), "apply");
+        assert_eq!(definition.arity(), 2);
+        assert_eq!(definition.params()[1].name(), "x");
+        assert_eq!(definition.body(), &Expr::Identity);
+    }
+}
@@ -0,0 +1,355 @@
+/// The lexical environment of a query.
+///
+/// Variables are bound by `source as $name | body`, and are visible in `body`
+/// only.  Functions are defined by `def name: body; rest`, and are visible in
+/// `rest` and in their own body.  The functions of a module imported by
+/// `import "path" as name;` are called as `name::f`, and are found in the
+/// module's own environment.  Binding a name returns a new [Env] that shares
+/// its parent, so an environment is cheap to clone into lazily evaluated
+/// closures.  Native functions are found after every binding, so they are
+/// shadowed by user defined functions.  Each call of a function or filter
+/// parameter is evaluated one level deeper than its caller.  Calls are
+/// evaluated recursively, so a call nested more than [Env::max_depth] deep
+/// is a [JQError::RecursionLimit], rather than a stack overflow.
+use std::rc::Rc;
+
+use crate::{Definition, Expr, FunctionRegistry, Input, JQError, NativeFunction};
+
+/// The default [Env::max_depth].  It leaves room for the rest of a query on a
+/// thread with Rust's default 2 MiB stack, even in a debug build.
+pub const MAX_CALL_DEPTH: usize = 256;
+
+/// Variables and functions in scope while evaluating an expression
+#[derive(Clone, Debug)]
+pub struct Env<'a> {
+    head: Option<Rc<Node<'a>>>,
+    functions: Option<&'a FunctionRegistry>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Default for Env<'_> {
+    fn default() -> Self {
+        Self {
+            head: None,
+            functions: None,
+            depth: 0,
+            max_depth: MAX_CALL_DEPTH,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node<'a> {
+    binding: Binding<'a>,
+    next: Option<Rc<Node<'a>>>,
+}
+
+#[derive(Debug)]
+enum Binding<'a> {
+    Variable(&'a str, Input<'a>),
+    Function(&'a Definition),
+    Closure(&'a str, &'a Expr, Env<'a>),
+    Module(&'a str, Env<'a>),
+    Label(&'a str, usize),
+}
+
+/// A function found by [Env::function]
+#[derive(Debug)]
+pub enum Callable<'a> {
+    /// A user defined function.  The environment is the scope of the
+    /// definition, including the function itself.
+    Definition(&'a Definition, Env<'a>),
+    /// A filter parameter, which is evaluated in the scope of the caller
+    Closure(&'a Expr, Env<'a>),
+    /// A function implemented in Rust
+    Native(&'a dyn NativeFunction),
+}
+
+impl<'a> Env<'a> {
+    /// An empty environment
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&self, binding: Binding<'a>) -> Self {
+        Self {
+            head: Some(Rc::new(Node {
+                binding,
+                next: self.head.clone(),
+            })),
+            functions: self.functions,
+            depth: self.depth,
+            max_depth: self.max_depth,
+        }
+    }
+
+    /// Returns the same environment, where functions that are not bound are
+    /// looked up in `functions`
+    pub fn with_functions(mut self, functions: &'a FunctionRegistry) -> Self {
+        self.functions = Some(functions);
+        self
+    }
+
+    /// The native functions of the environment
+    pub fn functions(&self) -> Option<&'a FunctionRegistry> {
+        self.functions
+    }
+
+    /// Returns the same environment, where calls may be nested at most
+    /// `max_depth` deep.  Each nested call uses more of the native stack, so
+    /// a larger limit needs a thread with a larger stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// How many calls deep the environment is evaluated
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// How many calls deep the environment may be evaluated
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// An error if a call from this environment would be nested more than
+    /// [Env::max_depth] deep
+    pub fn check_depth(&self) -> Result<(), JQError> {
+        if self.depth >= self.max_depth {
+            return Err(JQError::RecursionLimit(self.depth));
+        }
+        Ok(())
+    }
+
+    /// Returns the same environment, evaluated one call deeper than `caller`,
+    /// and with its limit
+    fn called_from(mut self, caller: &Env<'a>) -> Self {
+        self.depth = caller.depth + 1;
+        self.max_depth = caller.max_depth;
+        self
+    }
+
+    fn nodes(&self) -> impl Iterator<Item = &Rc<Node<'a>>> {
+        std::iter::successors(self.head.as_ref(), |node| node.next.as_ref())
+    }
+
+    /// Returns a new environment, with `name` bound to `value`.  An existing
+    /// variable with the same name is shadowed.
+    pub fn bind(&self, name: &'a str, value: Input<'a>) -> Self {
+        self.push(Binding::Variable(name, value))
+    }
+
+    /// Returns a new environment, with the function defined.  An existing
+    /// function with the same name and arity is shadowed.
+    pub fn define(&self, definition: &'a Definition) -> Self {
+        self.push(Binding::Function(definition))
+    }
+
+    /// Returns a new environment, with the filter parameter `name` bound to
+    /// `filter`, which is evaluated in `env`
+    pub fn bind_closure(&self, name: &'a str, filter: &'a Expr, env: Env<'a>) -> Self {
+        self.push(Binding::Closure(name, filter, env))
+    }
+
+    /// Returns a new environment, where `alias::f` calls the function `f` of
+    /// `module`, the environment of an imported module
+    pub fn import(&self, alias: &'a str, module: Env<'a>) -> Self {
+        self.push(Binding::Module(alias, module))
+    }
+
+    /// Returns a new environment, where `break $name` stops the label identified by `id`
+    pub fn label(&self, name: &'a str, id: usize) -> Self {
+        self.push(Binding::Label(name, id))
+    }
+
+    /// The id of the innermost label called `name`
+    pub fn get_label(&self, name: &str) -> Option<usize> {
+        self.nodes().find_map(|node| match node.binding {
+            Binding::Label(label, id) if label == name => Some(id),
+            _ => None,
+        })
+    }
+
+    /// The value of the innermost variable called `name`
+    pub fn get(&self, name: &str) -> Option<&Input<'a>> {
+        self.nodes().find_map(|node| match &node.binding {
+            Binding::Variable(var, value) if *var == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// The innermost function called `name` with `arity` parameters.  Filter
+    /// parameters have no parameters of their own.  The environment of a
+    /// definition or filter parameter is one call deeper than this one.
+    pub fn function(&self, name: &str, arity: usize) -> Option<Callable<'a>> {
+        let bound = self.nodes().find_map(|node| match &node.binding {
+            Binding::Function(definition)
+                if definition.name() == name && definition.arity() == arity =>
+            {
+                let scope = Env {
+                    head: Some(Rc::clone(node)),
+                    functions: self.functions,
+                    depth: self.depth,
+                    max_depth: self.max_depth,
+                };
+                Some(Callable::Definition(definition, scope))
+            }
+            Binding::Closure(param, filter, env) if *param == name && arity == 0 => {
+                Some(Callable::Closure(filter, env.clone()))
+            }
+            Binding::Module(alias, module) => name
+                .strip_prefix(alias)
+                .and_then(|name| name.strip_prefix("::"))
+                .and_then(|name| module.function(name, arity)),
+            _ => None,
+        });
+        match bound {
+            Some(Callable::Definition(definition, scope)) => {
+                Some(Callable::Definition(definition, scope.called_from(self)))
+            }
+            Some(Callable::Closure(filter, scope)) => {
+                Some(Callable::Closure(filter, scope.called_from(self)))
+            }
+            bound => bound.or_else(|| Some(Callable::Native(self.functions?.get(name, arity)?))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Param;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_bind() {
+        let env = Env::new();
+        assert!(env.get("x").is_none());
+
+        let env = env.bind("x", Input::from(json!(1)));
+        let inner = env.bind("x", Input::from(json!(2)));
+        assert_eq!(inner.get("x").map(|x| &**x), Some(&json!(2)));
+        assert_eq!(env.get("x").map(|x| &**x), Some(&json!(1)));
+    }
+
+    #[test]
+    fn test_get_outer() {
+        let value = json!("root");
+        let env = Env::new()
+            .bind("root", Input::from(&value))
+            .bind("item", Input::from(Value::from(1)));
+        assert_eq!(env.get("root").map(|x| &**x), Some(&value));
+        assert!(env.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_label() {
+        let env = Env::new()
+            .label("out", 1)
+            .bind("out", Input::from(json!(1)));
+        let inner = env.label("out", 2);
+        assert_eq!(inner.get_label("out"), Some(2));
+        assert_eq!(env.get_label("out"), Some(1));
+        assert!(env.get_label("x").is_none());
+    }
+
+    #[test]
+    fn test_depth() {
+        let f = Definition::new("f", Vec::new(), Expr::Identity);
+        let env = Env::new().define(&f);
+        let scope = match env.function("f", 0) {
+            Some(Callable::Definition(_, scope)) => scope,
+            _ => panic!("f is not defined"),
+        };
+        assert_eq!(env.depth(), 0);
+        assert_eq!(scope.depth(), 1);
+        assert!(
+            matches!(scope.function("f", 0), Some(Callable::Definition(_, inner)) if inner.depth() == 2)
+        );
+        assert_eq!(scope.max_depth(), MAX_CALL_DEPTH);
+        assert!(scope.check_depth().is_ok());
+
+        // The limit is kept by the calls that it applies to
+        let env = Env::new().with_max_depth(2).define(&f);
+        let scope = match env.function("f", 0) {
+            Some(Callable::Definition(_, scope)) => scope,
+            _ => panic!("f is not defined"),
+        };
+        assert_eq!(scope.max_depth(), 2);
+        assert!(scope.check_depth().is_ok());
+        let inner = match scope.function("f", 0) {
+            Some(Callable::Definition(_, inner)) => inner,
+            _ => panic!("f is not defined"),
+        };
+        assert!(matches!(
+            inner.check_depth(),
+            Err(JQError::RecursionLimit(2))
+        ));
+    }
+
+    #[test]
+    fn test_function() {
+        let f0 = Definition::new("f", Vec::new(), Expr::Identity);
+        let f1 = Definition::new("f", vec![Param::Filter("g".to_string())], Expr::Identity);
+        let env = Env::new().define(&f0).define(&f1);
+        assert!(matches!(env.function("f", 0), Some(Callable::Definition(d, _)) if d == &f0));
+        assert!(matches!(env.function("f", 1), Some(Callable::Definition(d, _)) if d == &f1));
+        assert!(env.function("f", 2).is_none());
+
+        // The scope of a definition includes the definition itself, but not
+        // anything defined after it
+        if let Some(Callable::Definition(_, scope)) = env.function("f", 0) {
+            assert!(scope.function("f", 0).is_some());
+            assert!(scope.function("f", 1).is_none());
+        }
+    }
+
+    #[test]
+    fn test_closure() {
+        let filter = Expr::Identity;
+        let caller = Env::new().bind("x", Input::from(json!(1)));
+        let env = Env::new().bind_closure("g", &filter, caller);
+        assert!(env.get("x").is_none());
+        match env.function("g", 0) {
+            Some(Callable::Closure(expr, scope)) => {
+                assert_eq!(expr, &Expr::Identity);
+                assert!(scope.get("x").is_some());
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(env.function("g", 1).is_none());
+    }
+
+    #[test]
+    fn test_import() {
+        let f = Definition::new("f", Vec::new(), Expr::Identity);
+        let module = Env::new().define(&f);
+        let env = Env::new().import("m", module);
+        assert!(matches!(env.function("m::f", 0), Some(Callable::Definition(d, _)) if d == &f));
+        assert!(env.function("f", 0).is_none());
+        assert!(env.function("m::f", 1).is_none());
+        assert!(env.function("mm::f", 0).is_none());
+    }
+
+    #[test]
+    fn test_native() {
+        let registry =
+            FunctionRegistry::new().with_function("f", 0, |_: &Value, _: &[Value]| Ok(json!(1)));
+        let env = Env::new().with_functions(&registry);
+        assert!(matches!(env.function("f", 0), Some(Callable::Native(_))));
+        assert!(env.function("f", 1).is_none());
+
+        // User defined functions shadow native functions
+        let f = Definition::new("f", Vec::new(), Expr::Identity);
+        let env = env.bind("x", Input::from(json!(1))).define(&f);
+        assert!(matches!(
+            env.function("f", 0),
+            Some(Callable::Definition(..))
+        ));
+        if let Some(Callable::Definition(_, scope)) = env.function("f", 0) {
+            assert!(scope.functions().is_some());
+        }
+    }
+}
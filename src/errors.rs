@@ -1,67 +1,138 @@
 /// Crate errors.
+use serde_json::Value;
 use thiserror::Error;
 
+/// The message of [JQError::UserError].  A string is shown as it is, and any
+/// other value as JSON.
+fn user_error_message(value: &Value) -> String {
+    match value {
+        Value::String(message) => message.clone(),
+        value => format!("{} (not a string)", value),
+    }
+}
+
 /// Use [thiserror] to create crate errors.
 #[derive(Error, Debug)]
 pub enum JQError {
-    ///
+    /// General purpose error
     #[error("Error: {0}")]
     GeneralError(String),
-    ///
+    /// Error reading input
     #[error("IO error")]
     IOError(#[from] std::io::Error),
-    ///
+    /// Error parsing or serializing JSON
     #[error("JSON parsing error ")]
     JSONError(#[from] serde_json::Error),
-    ///
+    /// The query string could not be parsed
     #[error("Parse error")]
     ParseError,
-    ///
-    #[error("Action Mismatch, expecting {0}")]
-    ActionMismatch(String),
-    ///
-    #[error("Token Mismatch, expecting {0}")]
-    TokenMismatch(String),
-    ///
+    /// The [Expr](crate::Expr) was not the expected variant
+    #[error("Expr Mismatch, expecting {0}")]
+    ExprMismatch(String),
+    /// The value is not an object
     #[error("This Value is not an object")]
     NotAnObject,
-    ///
+    /// The value is not an array
     #[error("This Value is not an array")]
     NotAnArray,
-    ///
+    /// The value variant is not supported
     #[error("Value variant is not supported for this function")]
     UnsupportedValue,
-    ///
+    /// The value does not support range operations
     #[error("Element does not support range operations")]
     UnsupportedRange,
-    ///
+    /// The range is out of bounds
     #[error("Range boundary error: {0}:{1}-{2}")]
     RangeOutOfBounds(isize, isize, isize),
-    ///
+    /// The value does not support string indexes
     #[error("Element does not support string index")]
     UnsupportedObjectIndex,
-    ///
+    /// The [IndexType](crate::IndexType) was not the expected variant
     #[error("Unexpected IndexType")]
     BadIndexType,
-    ///
+    /// The [IndexType](crate::IndexType) is malformed
     #[error("Keytype must have either identifier or range")]
     MalformedIndexType,
-    ///
+    /// Error querying an object
     #[error("Error querying object: {0}")]
     ObjectQuery(String),
-    ///
+    /// Error querying an array
     #[error("Error querying array: {0}")]
     ArrayQuery(String),
-    ///
+    /// The value has no length
     #[error("{0} has no length")]
     FnLength(String),
-    ///
+    /// The [Function](crate::Function) was not the expected variant
     #[error("Wrong function type")]
     WrongFunction,
-    ///
+    /// The [HasType](crate::HasType) is malformed
     #[error("HasType: {0}")]
     HasTypeError(String),
-    ///
+    /// The value cannot be indexed by an identifier
     #[error("{0}")]
     IdentMismatch(String),
+    /// The operand types are not supported by the [Operator](crate::Operator)
+    #[error("{0} and {1} cannot be {2}")]
+    OperandMismatch(String, String, String),
+    /// The value is not an array or object
+    #[error("Cannot iterate over {0}")]
+    CannotIterate(String),
+    /// The value cannot be indexed by the key, ie: an object by a number
+    #[error("Cannot index {0} with {1}")]
+    IndexMismatch(String, String),
+    /// The variable is used outside of the scope of its binding
+    #[error("${0} is not defined")]
+    UndefinedVariable(String),
+    /// No function with this name and arity exists
+    #[error("{0} is not defined")]
+    UndefinedFunction(String),
+    /// Object keys must be strings
+    #[error("Object keys must be strings, not {0}")]
+    ObjectKey(String),
+    /// A function argument has the wrong type
+    #[error("{0} is not a valid argument for {1}")]
+    InvalidArgument(String, String),
+    /// No module with this path was found in the search path
+    #[error("module not found: {0}")]
+    ModuleNotFound(String),
+    /// A module file could not be read or parsed
+    #[error("{0}: {1}")]
+    ModuleError(String, String),
+    /// Raised by `error` or `error(message)`, with a value for `catch`
+    #[error("{}", user_error_message(.0))]
+    UserError(Value),
+    /// `break $name` is used outside of `label $name | ...`
+    #[error("$*label-{0} is not defined")]
+    UndefinedLabel(String),
+    /// Raised by `break $name`, and caught by the label it refers to, which
+    /// is identified by its evaluation.  It never reaches the caller of a query.
+    #[error("break")]
+    Break(usize),
+    /// A path was asked for, ie: by `path(f)`, but `f` produced a value that
+    /// is not part of its input
+    #[error("Invalid path expression with result {0}")]
+    InvalidPathExpression(String),
+    /// Calls were nested too deeply to evaluate, ie: by a recursive function
+    /// that does not stop
+    #[error("Recursion limit reached after {0} nested calls")]
+    RecursionLimit(usize),
+    /// Division or modulo by zero
+    #[error("{0} and {1} cannot be divided because the divisor is zero")]
+    DivisionByZero(String, String),
+}
+
+impl JQError {
+    /// The value that `catch` receives: the value given to `error`, or the
+    /// message of any other error
+    pub fn into_value(self) -> Value {
+        match self {
+            JQError::UserError(value) => value,
+            error => Value::String(error.to_string()),
+        }
+    }
+
+    /// True for [JQError::Break], which `try` and `?` do not catch
+    pub(crate) fn is_break(&self) -> bool {
+        matches!(self, JQError::Break(_))
+    }
 }
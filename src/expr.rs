@@ -0,0 +1,603 @@
+/// Expressions are the nodes of a parsed query.  Paths, functions and
+/// literals are the leaves, and operators, pipes and constructors
+/// combine them into a tree that both filters and transforms input.
+///
+/// An [Expr] owns all of its data, so a parsed query can outlive the query
+/// string, be shared between threads, and be serialized with [serde].
+use crate::{
+    CompileOptions, Definition, FilterArg, Function, HasType, IndexType, JQError, Loader, Operator,
+    Param, Pattern, RangeType, PREDEFINED,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// An expression is a node in the parsed query tree
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expr {
+    /// `.`, represents the entire input
+    Identity,
+    /// `.foo`, represents an object key.  Bool represents whether to suppress errors
+    Ident(String, bool),
+    /// `.[isize]` or `.["foo"]`, represents an array index or object key
+    Index(IndexType),
+    /// `.[]` or `.[isize:isize]`, iterates an array or object, or slices an array
+    Range(RangeType),
+    /// [Function]
+    Function(Function),
+    /// A constant value, such as `"abc"`, `42` or `null`
+    Literal(Value),
+    /// Binary [Operator]s applied from left to right, ie: `1 - 2 + 3` is the
+    /// first operand `1` followed by `[(-, 2), (+, 3)]`.  A chain of operators is
+    /// kept flat rather than nested, so a long one does not need a deep tree.
+    Operation(Box<Expr>, Vec<OperatorOperand>),
+    /// Short-circuiting `and`
+    And(Box<Expr>, Box<Expr>),
+    /// Short-circuiting `or`
+    Or(Box<Expr>, Box<Expr>),
+    /// `lhs // rhs`, the results of `lhs` that are not `false` or `null`, or
+    /// the results of `rhs` if there are none.  Errors in `lhs` are dropped.
+    Alternative(Box<Expr>, Box<Expr>),
+    /// Array construction.  Collects every result of the filter into an array.
+    Array(FilterArg),
+    /// Object construction.  Each entry is a key filter and a value filter.
+    Object(Vec<ObjectEntry>),
+    /// `lhs | rhs`, each result of `lhs` is the input for `rhs`
+    Pipe(Box<Expr>, Box<Expr>),
+    /// `a, b, c`, the results of each expression in turn
+    Comma(Vec<Expr>),
+    /// `if cond then a else b end`, evaluates `a` for each truthy result of
+    /// `cond`, and `b` for each false one.  `elif` is a nested `If` in the
+    /// `else` branch, and a missing `else` is `.`.
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `try body catch handler`, the results of `body` until its first error,
+    /// then the results of `handler` with the error as input.  `body?` and
+    /// `try body` have no handler, and drop the error.
+    Try(Box<Expr>, Option<Box<Expr>>),
+    /// `$name`, the value of a variable
+    Variable(String),
+    /// `source as $name | body`, evaluates `body` once for each result of
+    /// `source`, with the result bound to `$name`.  When there is more than one
+    /// [Pattern], they are alternatives separated by `?//`.
+    Bind(Box<Expr>, Vec<Pattern>, Box<Expr>),
+    /// `reduce source as $name (init; update)`, starts with the result of
+    /// `init` and replaces it with the last result of `update`, evaluated
+    /// against it, for each result of `source` bound to `$name`.  An `update`
+    /// with no results makes it `null`.
+    Reduce(Box<Expr>, Vec<Pattern>, Box<Expr>, Box<Expr>),
+    /// `foreach source as $name (init; update; extract)`, like [Expr::Reduce],
+    /// but every result of `update` is passed through `extract` and produced.
+    /// Without `extract`, the results of `update` are produced as they are.
+    Foreach(
+        Box<Expr>,
+        Vec<Pattern>,
+        Box<Expr>,
+        Box<Expr>,
+        Option<Box<Expr>>,
+    ),
+    /// `label $name | body`, the results of `body` until `break $name` is
+    /// evaluated in it
+    Label(String, Box<Expr>),
+    /// `break $name`, stops the innermost `label $name`
+    Break(String),
+    /// `def name(params): body; rest`, the function is visible in `rest`
+    Define(Box<Definition>, Box<Expr>),
+    /// `name(args)`, a call to a user defined function or a filter parameter
+    Call(String, Vec<Expr>),
+    /// `import "path" as name; rest`, the functions defined by the library
+    /// are called as `name::f` in `rest`.  The library is the module's
+    /// definitions, nested around `.`, and only sees the predefined variables.
+    Import(Box<Expr>, String, Box<Expr>),
+}
+
+/// The variables and functions in scope while resolving a query
+#[derive(Debug, Default)]
+pub(crate) struct Scope {
+    variables: Vec<String>,
+    functions: Vec<(String, usize)>,
+    labels: Vec<String>,
+    loader: Loader,
+    natives: Vec<(String, usize)>,
+}
+
+impl Scope {
+    fn new(globals: &[&str], loader: &Loader, natives: Vec<(String, usize)>) -> Self {
+        Scope {
+            variables: globals.iter().map(|name| name.to_string()).collect(),
+            functions: natives.clone(),
+            labels: Vec::new(),
+            loader: loader.clone(),
+            natives,
+        }
+    }
+
+    /// The scope of an imported module, which only sees the predefined
+    /// variables and the native functions
+    fn module(&self) -> Self {
+        Scope::new(&PREDEFINED, &self.loader, self.natives.clone())
+    }
+
+    fn has_function(&self, name: &str, arity: usize) -> bool {
+        self.functions
+            .iter()
+            .any(|(function, n)| function == name && *n == arity)
+    }
+
+    pub(crate) fn push_variable(&mut self, name: &str) {
+        self.variables.push(name.to_string());
+    }
+
+    fn push_function(&mut self, name: &str, arity: usize) {
+        self.functions.push((name.to_string(), arity));
+    }
+
+    /// Run `f`, then remove anything that it added to the scope
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let (variables, functions) = (self.variables.len(), self.functions.len());
+        let labels = self.labels.len();
+        let result = f(self);
+        self.variables.truncate(variables);
+        self.functions.truncate(functions);
+        self.labels.truncate(labels);
+        result
+    }
+}
+
+/// A `key: value` pair in an object construction, such as `{(.k): .v}`.
+/// Identifier and string keys are represented as literals.
+pub type ObjectEntry = (Expr, Expr);
+
+/// An [Operator] and its right hand operand in an [Expr::Operation]
+pub type OperatorOperand = (Operator, Expr);
+
+impl Expr {
+    /// Join the components of a path, such as `.a[0].b`, into a pipe.
+    /// Identity components are dropped, unless the path is only `.`
+    pub fn path(keys: Vec<Expr>) -> Self {
+        keys.into_iter()
+            .filter(|key| !key.is_identity())
+            .rev()
+            .reduce(|rhs, lhs| Expr::Pipe(Box::new(lhs), Box::new(rhs)))
+            .unwrap_or(Expr::Identity)
+    }
+
+    /// Returns the ident component, or error
+    pub fn as_ident(&self) -> Result<(&str, bool), JQError> {
+        match self {
+            Expr::Ident(ident, silent) => Ok((ident, *silent)),
+            _ => Err(JQError::ExprMismatch("Ident".to_string())),
+        }
+    }
+
+    /// Returns the index, or error
+    pub fn as_index(&self) -> Result<&IndexType, JQError> {
+        match self {
+            Expr::Index(index) => Ok(index),
+            _ => Err(JQError::ExprMismatch("Index".to_string())),
+        }
+    }
+
+    /// Returns the range, or error
+    pub fn as_range(&self) -> Result<&RangeType, JQError> {
+        match self {
+            Expr::Range(range) => Ok(range),
+            _ => Err(JQError::ExprMismatch("Range".to_string())),
+        }
+    }
+
+    /// Return the inner [Function], or error
+    pub fn as_function(&self) -> Result<&Function, JQError> {
+        match self {
+            Expr::Function(function) => Ok(function),
+            _ => Err(JQError::ExprMismatch("Function".to_string())),
+        }
+    }
+
+    /// Return the inner literal [Value], or error
+    pub fn as_literal(&self) -> Result<&Value, JQError> {
+        match self {
+            Expr::Literal(value) => Ok(value),
+            _ => Err(JQError::ExprMismatch("Literal".to_string())),
+        }
+    }
+
+    /// Return the first operand and the following [Operator]s and operands,
+    /// or error
+    pub fn as_operation(&self) -> Result<(&Expr, &[OperatorOperand]), JQError> {
+        match self {
+            Expr::Operation(first, rest) => Ok((first, rest)),
+            _ => Err(JQError::ExprMismatch("Operation".to_string())),
+        }
+    }
+
+    /// True if the [Expr] is an Identity
+    pub fn is_identity(&self) -> bool {
+        matches!(self, Expr::Identity)
+    }
+
+    /// True if the [Expr] is an Ident
+    pub fn is_ident(&self) -> bool {
+        matches!(self, Expr::Ident(..))
+    }
+
+    /// True if the [Expr] is an Index
+    pub fn is_index(&self) -> bool {
+        matches!(self, Expr::Index(_))
+    }
+
+    /// True if the [Expr] is a Range
+    pub fn is_range(&self) -> bool {
+        matches!(self, Expr::Range(_))
+    }
+
+    /// True if the [Expr] is a [Function]
+    pub fn is_function(&self) -> bool {
+        matches!(self, Expr::Function(_))
+    }
+
+    /// True if the [Expr] is a literal [Value]
+    pub fn is_literal(&self) -> bool {
+        matches!(self, Expr::Literal(_))
+    }
+
+    /// True if the [Expr] is an array construction
+    pub fn is_array(&self) -> bool {
+        matches!(self, Expr::Array(_))
+    }
+
+    /// True if the [Expr] is an object construction
+    pub fn is_object(&self) -> bool {
+        matches!(self, Expr::Object(_))
+    }
+
+    /// True if the [Expr] is a pipe
+    pub fn is_pipe(&self) -> bool {
+        matches!(self, Expr::Pipe(..))
+    }
+
+    /// True if the [Expr] is a comma
+    pub fn is_comma(&self) -> bool {
+        matches!(self, Expr::Comma(..))
+    }
+
+    /// True if the [Expr] is an operation
+    pub fn is_operation(&self) -> bool {
+        matches!(self, Expr::Operation(..))
+    }
+
+    /// True if the [Expr] is a conditional
+    pub fn is_if(&self) -> bool {
+        matches!(self, Expr::If(..))
+    }
+
+    /// True if the [Expr] is an alternative, `lhs // rhs`
+    pub fn is_alternative(&self) -> bool {
+        matches!(self, Expr::Alternative(..))
+    }
+
+    /// True if the [Expr] is a `try`, or a postfix `?`
+    pub fn is_try(&self) -> bool {
+        matches!(self, Expr::Try(..))
+    }
+
+    /// True if the [Expr] is a variable
+    pub fn is_variable(&self) -> bool {
+        matches!(self, Expr::Variable(_))
+    }
+
+    /// True if the [Expr] is a variable binding
+    pub fn is_bind(&self) -> bool {
+        matches!(self, Expr::Bind(..))
+    }
+
+    /// True if the [Expr] is a `reduce`
+    pub fn is_reduce(&self) -> bool {
+        matches!(self, Expr::Reduce(..))
+    }
+
+    /// True if the [Expr] is a `foreach`
+    pub fn is_foreach(&self) -> bool {
+        matches!(self, Expr::Foreach(..))
+    }
+
+    /// True if the [Expr] is a `label`
+    pub fn is_label(&self) -> bool {
+        matches!(self, Expr::Label(..))
+    }
+
+    /// True if the [Expr] is a function definition
+    pub fn is_define(&self) -> bool {
+        matches!(self, Expr::Define(..))
+    }
+
+    /// True if the [Expr] is a call to a user defined function
+    pub fn is_call(&self) -> bool {
+        matches!(self, Expr::Call(..))
+    }
+
+    /// True if the [Expr] is a module import
+    pub fn is_import(&self) -> bool {
+        matches!(self, Expr::Import(..))
+    }
+
+    /// The name and arity of each function defined by a library, ie: the
+    /// first expression of an [Expr::Import]
+    pub fn exports(&self) -> Vec<(&str, usize)> {
+        let mut exports = Vec::new();
+        let mut expr = self;
+        loop {
+            match expr {
+                Expr::Define(definition, rest) => {
+                    exports.push((definition.name(), definition.arity()));
+                    expr = rest;
+                }
+                Expr::Bind(_, _, rest) | Expr::Import(_, _, rest) => expr = rest,
+                _ => return exports,
+            }
+        }
+    }
+
+    /// Check that every variable is bound, and every function is defined,
+    /// before it is used.  The [PREDEFINED] variables, and the variables and
+    /// native functions of `options`, are defined outside of the query.  A
+    /// builtin that is shadowed by a native or user defined function with the
+    /// same name and arity becomes a [Expr::Call].  `modulemeta` looks up
+    /// modules with the search path of `options`.
+    pub fn resolve(&mut self, options: &CompileOptions) -> Result<(), JQError> {
+        let globals: Vec<&str> = PREDEFINED.iter().copied().chain(options.vars()).collect();
+        let natives = options
+            .functions()
+            .signatures()
+            .into_iter()
+            .map(|(name, arity)| (name.to_string(), arity))
+            .collect();
+        self.resolve_scope(&mut Scope::new(&globals, options.loader(), natives))
+    }
+
+    pub(crate) fn resolve_scope(&mut self, scope: &mut Scope) -> Result<(), JQError> {
+        match self {
+            Expr::Variable(name) => {
+                if !scope.variables.contains(name) {
+                    return Err(JQError::UndefinedVariable(name.to_string()));
+                }
+            }
+            Expr::Bind(source, patterns, body) => {
+                source.resolve_scope(scope)?;
+                scope.nested(|scope| {
+                    for pattern in patterns.iter_mut() {
+                        pattern.resolve_scope(scope)?;
+                    }
+                    body.resolve_scope(scope)
+                })?;
+            }
+            Expr::Reduce(source, patterns, init, update) => {
+                source.resolve_scope(scope)?;
+                init.resolve_scope(scope)?;
+                scope.nested(|scope| {
+                    for pattern in patterns.iter_mut() {
+                        pattern.resolve_scope(scope)?;
+                    }
+                    update.resolve_scope(scope)
+                })?;
+            }
+            Expr::Foreach(source, patterns, init, update, extract) => {
+                source.resolve_scope(scope)?;
+                init.resolve_scope(scope)?;
+                scope.nested(|scope| {
+                    for pattern in patterns.iter_mut() {
+                        pattern.resolve_scope(scope)?;
+                    }
+                    update.resolve_scope(scope)?;
+                    match extract {
+                        Some(extract) => extract.resolve_scope(scope),
+                        None => Ok(()),
+                    }
+                })?;
+            }
+            Expr::Function(function) => {
+                if scope.has_function(function.name(), function.arity()) {
+                    let (name, args) = function.clone().into_call();
+                    *self = Expr::Call(name, args);
+                    return self.resolve_scope(scope);
+                }
+                if let Function::ModuleMeta(loader) = function {
+                    *loader = scope.loader.clone();
+                }
+                for arg in function.args_mut() {
+                    arg.resolve_scope(scope)?;
+                }
+            }
+            Expr::Call(name, args) => {
+                if !scope.has_function(name, args.len()) {
+                    return Err(JQError::UndefinedFunction(format!(
+                        "{}/{}",
+                        name,
+                        args.len()
+                    )));
+                }
+                for arg in args {
+                    arg.resolve_scope(scope)?;
+                }
+            }
+            Expr::Label(name, body) => {
+                scope.nested(|scope| {
+                    scope.labels.push(name.to_string());
+                    body.resolve_scope(scope)
+                })?;
+            }
+            Expr::Break(name) => {
+                if !scope.labels.contains(name) {
+                    return Err(JQError::UndefinedLabel(name.to_string()));
+                }
+            }
+            Expr::Define(definition, rest) => {
+                scope.nested(|scope| {
+                    scope.push_function(definition.name(), definition.arity());
+                    let params = definition.params().to_vec();
+                    scope.nested(|scope| {
+                        for param in &params {
+                            scope.push_function(param.name(), 0);
+                            if let Param::Variable(name) = param {
+                                scope.push_variable(name);
+                            }
+                        }
+                        definition.body_mut().resolve_scope(scope)
+                    })?;
+                    rest.resolve_scope(scope)
+                })?;
+            }
+            Expr::Import(library, alias, rest) => {
+                library.resolve_scope(&mut scope.module())?;
+                scope.nested(|scope| {
+                    for (name, arity) in library.exports() {
+                        scope.push_function(&format!("{}::{}", alias, name), arity);
+                    }
+                    rest.resolve_scope(scope)
+                })?;
+            }
+            Expr::Operation(first, rest) => {
+                first.resolve_scope(scope)?;
+                for (_, operand) in rest {
+                    operand.resolve_scope(scope)?;
+                }
+            }
+            Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs)
+            | Expr::Alternative(lhs, rhs)
+            | Expr::Pipe(lhs, rhs) => {
+                lhs.resolve_scope(scope)?;
+                rhs.resolve_scope(scope)?;
+            }
+            Expr::Comma(exprs) => {
+                for expr in exprs {
+                    expr.resolve_scope(scope)?;
+                }
+            }
+            Expr::If(cond, then, otherwise) => {
+                cond.resolve_scope(scope)?;
+                then.resolve_scope(scope)?;
+                otherwise.resolve_scope(scope)?;
+            }
+            Expr::Try(body, handler) => {
+                body.resolve_scope(scope)?;
+                if let Some(handler) = handler {
+                    handler.resolve_scope(scope)?;
+                }
+            }
+            Expr::Array(filter) => filter.resolve_scope(scope)?,
+            Expr::Object(entries) => {
+                for (key, value) in entries {
+                    key.resolve_scope(scope)?;
+                    value.resolve_scope(scope)?;
+                }
+            }
+            Expr::Identity
+            | Expr::Ident(..)
+            | Expr::Index(_)
+            | Expr::Range(_)
+            | Expr::Literal(_) => {}
+        }
+        Ok(())
+    }
+}
+
+impl From<Function> for Expr {
+    fn from(function: Function) -> Self {
+        Self::Function(function)
+    }
+}
+
+impl From<Value> for Expr {
+    fn from(value: Value) -> Self {
+        Self::Literal(value)
+    }
+}
+
+impl From<&HasType> for Expr {
+    /// HasType is guaranteed to have either an ident or an index.
+    fn from(has: &HasType) -> Self {
+        if has.is_ident() {
+            return Expr::Ident(has.as_ident().unwrap().to_string(), false);
+        }
+        Expr::Index(IndexType::from((has.as_index().unwrap(), false)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ident(s: &str) -> Expr {
+        Expr::Ident(s.to_string(), false)
+    }
+
+    #[test]
+    fn test_from_function() {
+        let length = Function::Length;
+        let expr = Expr::from(length);
+        assert_eq!(expr, Expr::Function(Function::Length));
+    }
+
+    #[test]
+    fn test_from_value() {
+        let expr = Expr::from(Value::from("abc"));
+        assert!(expr.is_literal());
+        assert_eq!(expr.as_literal().expect("failed"), &Value::from("abc"));
+    }
+
+    #[test]
+    fn test_from_ident_has() {
+        let has = HasType::from("elem1");
+        assert_eq!(Expr::from(&has), ident("elem1"));
+    }
+
+    #[test]
+    fn test_from_index_has() {
+        let has = HasType::from(0);
+        assert_eq!(Expr::from(&has), Expr::Index(IndexType::from((0, false))));
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(Expr::path(vec![Expr::Identity]), Expr::Identity);
+        assert_eq!(
+            Expr::path(vec![Expr::Identity, Expr::Range(RangeType::new())]),
+            Expr::Range(RangeType::new())
+        );
+        assert_eq!(
+            Expr::path(vec![ident("a"), ident("b"), ident("c")]),
+            Expr::Pipe(
+                Box::new(ident("a")),
+                Box::new(Expr::Pipe(Box::new(ident("b")), Box::new(ident("c"))))
+            )
+        );
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let expr = Expr::Pipe(
+            Box::new(Expr::Range(RangeType::new())),
+            Box::new(Expr::Object(vec![(
+                Expr::Literal(Value::from("id")),
+                Expr::Operation(
+                    Box::new(ident("id")),
+                    vec![(Operator::Add, Expr::Literal(Value::from(1)))],
+                ),
+            )])),
+        );
+        let json = serde_json::to_string(&expr).expect("failed to serialize");
+        let result: Expr = serde_json::from_str(&json).expect("failed to deserialize");
+        assert_eq!(result, expr);
+
+        // Within serde_json's recursion limit, however long the list or chain
+        for query_str in [
+            format!("[{}]", vec![".x"; 500].join(", ")),
+            vec![".x"; 500].join(" + "),
+            vec![".x"; 500].join(" * 2 - "),
+        ] {
+            let expr = crate::parse(&query_str).expect("failed to parse");
+            let json = serde_json::to_string(&expr).expect("failed to serialize");
+            let result: Expr = serde_json::from_str(&json).expect("failed to deserialize");
+            assert_eq!(result, expr);
+        }
+    }
+}
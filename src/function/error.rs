@@ -0,0 +1,43 @@
+/// `error` and `error(message)` functions
+///
+use crate::{once, query_iter, Env, Expr, Input, JQError, ValueIter};
+
+/// Raises an error carrying a JSON value, which `try ... catch` passes to its
+/// handler.  `error` raises the input, and `error(message)` raises each result
+/// of `message`, which is evaluated against the input.
+pub fn fn_error<'a>(input: Input<'a>, message: Option<&'a Expr>, env: &Env<'a>) -> ValueIter<'a> {
+    match message {
+        None => once(Err(JQError::UserError(input.into_value()))),
+        Some(message) => Box::new(
+            query_iter(input, message, env)
+                .map(|message| Err(JQError::UserError(message?.into_value()))),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_error() {
+        let value = json!({"a": 1});
+        let mut results = fn_error(Input::from(&value), None, &Env::new());
+        assert!(matches!(results.next(), Some(Err(JQError::UserError(v))) if v == value));
+
+        let message = Expr::Literal(json!("bad"));
+        let mut results = fn_error(Input::from(Value::Null), Some(&message), &Env::new());
+        let error = results.next().expect("missing").expect_err("should fail");
+        assert_eq!(error.to_string(), "bad");
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(JQError::UserError(json!("x")).to_string(), "x");
+        assert_eq!(
+            JQError::UserError(json!({"a": 1})).to_string(),
+            r#"{"a":1} (not a string)"#
+        );
+    }
+}
@@ -0,0 +1,49 @@
+/// `first` function
+///
+use crate::{query_iter, Env, FilterArg, Input, ValueIter};
+
+/// Emits only the first result of the sub-query.  The rest of the sub-query
+/// is never evaluated, so `first(.[] | select(.id == 1))` stops at the first match.
+pub fn fn_first<'a>(input: Input<'a>, filter: &'a FilterArg, env: &Env<'a>) -> ValueIter<'a> {
+    Box::new(query_iter(input, filter, env).take(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_first() {
+        let value = json!([1, 2, 3]);
+        let filter = Box::new(parse(".[]").expect("failed to parse"));
+        let result: Vec<Value> = fn_first(Input::from(&value), &filter, &Env::new())
+            .map(|value| value.map(Input::into_value))
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert_eq!(result, [json!(1)]);
+    }
+
+    #[test]
+    fn test_first_stops_early() {
+        // The error in the second result is never reached
+        let value = json!({"a": 1});
+        let filter = Box::new(parse(".a, .a.b").expect("failed to parse"));
+        let result: Vec<Value> = fn_first(Input::from(&value), &filter, &Env::new())
+            .map(|value| value.map(Input::into_value))
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert_eq!(result, [json!(1)]);
+    }
+
+    #[test]
+    fn test_first_empty() {
+        let value = json!([]);
+        let filter = Box::new(parse(".[]").expect("failed to parse"));
+        assert_eq!(
+            fn_first(Input::from(&value), &filter, &Env::new()).count(),
+            0
+        );
+    }
+}
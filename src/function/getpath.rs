@@ -0,0 +1,395 @@
+/// `getpath`, `setpath` and `delpaths` functions
+///
+/// A path is an array of object keys and array indexes, such as the paths
+/// produced by `path(f)`.  As in jq, a path may also contain a slice, such as
+/// `path(.[1:])`, which is an object with a `start` and an `end`, either of
+/// which may be null.  Only `getpath` can slice a string.
+use std::borrow::Cow;
+
+use serde_json::Map;
+
+use crate::{
+    compare_values, once, query_iter, value_name, Env, FilterArg, Input, JQError, Value, ValueIter,
+};
+
+/// Returned for missing keys and indexes
+static NULL: Value = Value::Null;
+
+/// The largest index `setpath` will pad an array to, the same as jq's
+pub const MAX_ARRAY_INDEX: usize = (i32::MAX >> 2) as usize;
+
+fn index_mismatch(value: &Value, key: &Value) -> JQError {
+    JQError::IndexMismatch(value_name(value).to_string(), key.to_string())
+}
+
+/// The index of `index` in an array of `len`.  A negative index counts back
+/// from the end, and is `None` if it is before the start.
+fn array_index(index: f64, len: usize) -> Option<usize> {
+    let index = index.floor() as isize;
+    let index = if index < 0 {
+        index + len as isize
+    } else {
+        index
+    };
+    usize::try_from(index).ok()
+}
+
+/// The bounds of the slice `{"start": s, "end": e}` of an array of `len`,
+/// clamped to the array.  A null bound is open.
+fn slice_bounds(slice: &Map<String, Value>, len: usize) -> Option<(usize, usize)> {
+    let bound = |name, default: usize| match slice.get(name) {
+        None | Some(Value::Null) => Some(default),
+        Some(Value::Number(bound)) => {
+            let bound = bound.as_f64()?.floor() as isize;
+            let bound = if bound < 0 {
+                bound + len as isize
+            } else {
+                bound
+            };
+            Some(bound.clamp(0, len as isize) as usize)
+        }
+        Some(_) => None,
+    };
+    let start = bound("start", 0)?;
+    let end = bound("end", len)?;
+    Some((start, end.max(start)))
+}
+
+/// The value at `path` in `value`.  Missing keys and indexes, indexes that
+/// are not integers, and everything below a null, are null.
+pub fn getpath<'v>(value: &'v Value, path: &[Value]) -> Result<Cow<'v, Value>, JQError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(Cow::Borrowed(value)),
+    };
+    match (value, key) {
+        (Value::Null, _) => Ok(Cow::Borrowed(&NULL)),
+        (Value::Object(object), Value::String(name)) => {
+            getpath(object.get(name).unwrap_or(&NULL), rest)
+        }
+        (Value::Array(array), Value::Number(index)) => {
+            let child = index
+                .as_f64()
+                .filter(|index| index.fract() == 0.0)
+                .and_then(|index| array_index(index, array.len()))
+                .and_then(|index| array.get(index));
+            getpath(child.unwrap_or(&NULL), rest)
+        }
+        (Value::Array(array), Value::Object(slice)) => {
+            let (start, end) =
+                slice_bounds(slice, array.len()).ok_or_else(|| index_mismatch(value, key))?;
+            let child = Value::from(array[start..end].to_vec());
+            Ok(Cow::Owned(getpath(&child, rest)?.into_owned()))
+        }
+        (Value::String(string), Value::Object(slice)) => {
+            let (start, end) =
+                slice_bounds(slice, string.len()).ok_or_else(|| index_mismatch(value, key))?;
+            let child = Value::from(string.get(start..end).unwrap_or(""));
+            Ok(Cow::Owned(getpath(&child, rest)?.into_owned()))
+        }
+        _ => Err(index_mismatch(value, key)),
+    }
+}
+
+/// `value`, with the value at `path` replaced by `new`.  Objects and arrays
+/// are created for the missing parts of the path, and arrays are padded with
+/// null, up to [MAX_ARRAY_INDEX].
+pub fn setpath(value: Value, path: &[Value], new: Value) -> Result<Value, JQError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(new),
+    };
+    match (value, key) {
+        (Value::Null, Value::String(_)) => setpath(Value::Object(Map::new()), path, new),
+        (Value::Null, Value::Number(_) | Value::Object(_)) => {
+            setpath(Value::Array(Vec::new()), path, new)
+        }
+        (Value::Object(mut object), Value::String(name)) => {
+            let child = object.get_mut(name).map(std::mem::take).unwrap_or_default();
+            object.insert(name.clone(), setpath(child, rest, new)?);
+            Ok(Value::Object(object))
+        }
+        (Value::Array(mut array), Value::Number(index)) => {
+            let index =
+                array_index(index.as_f64().unwrap_or_default(), array.len()).ok_or_else(|| {
+                    JQError::ArrayQuery("Out of bounds negative array index".to_string())
+                })?;
+            if index > MAX_ARRAY_INDEX {
+                return Err(JQError::ArrayQuery("Array index too large".to_string()));
+            }
+            if index >= array.len() {
+                array.resize(index + 1, Value::Null);
+            }
+            let child = std::mem::take(&mut array[index]);
+            array[index] = setpath(child, rest, new)?;
+            Ok(Value::Array(array))
+        }
+        (Value::Array(mut array), Value::Object(slice)) => {
+            let (start, end) = match slice_bounds(slice, array.len()) {
+                Some(bounds) => bounds,
+                None => return Err(index_mismatch(&Value::Array(array), key)),
+            };
+            let child = Value::from(array[start..end].to_vec());
+            match setpath(child, rest, new)? {
+                Value::Array(items) => {
+                    array.splice(start..end, items);
+                    Ok(Value::Array(array))
+                }
+                other => Err(JQError::InvalidArgument(
+                    value_name(&other).to_string(),
+                    "a slice of an array".to_string(),
+                )),
+            }
+        }
+        (value, key) => Err(index_mismatch(&value, key)),
+    }
+}
+
+/// `value`, without the value at `path`.  Deleting a missing key or index
+/// does nothing, and deleting the empty path leaves null.
+pub fn delpath(value: Value, path: &[Value]) -> Result<Value, JQError> {
+    let (key, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return Ok(Value::Null),
+    };
+    match (value, key) {
+        (Value::Null, _) => Ok(Value::Null),
+        (Value::Object(mut object), Value::String(name)) => {
+            if rest.is_empty() {
+                // `remove` would move the last key into its place
+                object.retain(|existing, _| existing != name);
+            } else if let Some(child) = object.get_mut(name) {
+                *child = delpath(std::mem::take(child), rest)?;
+            }
+            Ok(Value::Object(object))
+        }
+        (Value::Array(mut array), Value::Number(index)) => {
+            let index = index
+                .as_f64()
+                .and_then(|index| array_index(index, array.len()))
+                .filter(|index| *index < array.len());
+            match index {
+                Some(index) if rest.is_empty() => {
+                    array.remove(index);
+                }
+                Some(index) => {
+                    let child = std::mem::take(&mut array[index]);
+                    array[index] = delpath(child, rest)?;
+                }
+                None => {}
+            }
+            Ok(Value::Array(array))
+        }
+        (Value::Array(mut array), Value::Object(slice)) => {
+            let (start, end) = match slice_bounds(slice, array.len()) {
+                Some(bounds) => bounds,
+                None => return Err(index_mismatch(&Value::Array(array), key)),
+            };
+            if rest.is_empty() {
+                array.drain(start..end);
+                return Ok(Value::Array(array));
+            }
+            match delpath(Value::from(array[start..end].to_vec()), rest)? {
+                Value::Array(items) => {
+                    array.splice(start..end, items);
+                    Ok(Value::Array(array))
+                }
+                _ => Ok(Value::Array(array)),
+            }
+        }
+        (value, key) => Err(index_mismatch(&value, key)),
+    }
+}
+
+/// A path argument, which must be an array
+fn as_path<'v>(path: &'v Value, function: &str) -> Result<&'v [Value], JQError> {
+    match path {
+        Value::Array(path) => Ok(path),
+        path => Err(JQError::InvalidArgument(
+            value_name(path).to_string(),
+            function.to_string(),
+        )),
+    }
+}
+
+/// Emits the value at each path produced by the sub-query, ie:
+/// `getpath(["a", 0])` is `.a[0]`, except that it is null rather than an
+/// error if any part of the path is missing.
+pub fn fn_getpath<'a>(input: Input<'a>, paths: &'a FilterArg, env: &Env<'a>) -> ValueIter<'a> {
+    Box::new(query_iter(input.clone(), paths, env).map(move |path| {
+        let path = path?;
+        let path = as_path(&path, "getpath")?;
+        Ok(match &input {
+            Input::Borrowed(value) => Input::from(getpath(value, path)?),
+            Input::Owned(value) => Input::from(getpath(value, path)?.into_owned()),
+        })
+    }))
+}
+
+/// Emits the input with the value at a path replaced, for each combination
+/// of the results of `paths` and `values`.  The paths change slowest.
+pub fn fn_setpath<'a>(
+    input: Input<'a>,
+    paths: &'a FilterArg,
+    values: &'a FilterArg,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(query_iter(input.clone(), paths, &env.clone()).flat_map(
+        move |path| -> ValueIter<'a> {
+            let path = match path {
+                Ok(path) => path,
+                Err(error) => return once(Err(error)),
+            };
+            let input = input.clone();
+            Box::new(query_iter(input.clone(), values, &env).map(move |value| {
+                let path = as_path(&path, "setpath")?;
+                setpath(Value::clone(&input), path, value?.into_value()).map(Input::from)
+            }))
+        },
+    ))
+}
+
+/// Emits the input without the value at each of the paths, for each array of
+/// paths produced by the sub-query.  The paths are deleted from the last to
+/// the first, so that deleting an array element does not move the others.
+pub fn fn_delpaths<'a>(input: Input<'a>, paths: &'a FilterArg, env: &Env<'a>) -> ValueIter<'a> {
+    Box::new(query_iter(input.clone(), paths, env).map(move |paths| {
+        let paths = paths?;
+        let mut paths = match &*paths {
+            Value::Array(paths) => paths
+                .iter()
+                .map(|path| as_path(path, "delpaths"))
+                .collect::<Result<Vec<_>, _>>()?,
+            paths => {
+                return Err(JQError::InvalidArgument(
+                    value_name(paths).to_string(),
+                    "delpaths".to_string(),
+                ))
+            }
+        };
+        paths.sort_by(|a, b| compare_values(&Value::from(b.to_vec()), &Value::from(a.to_vec())));
+        paths
+            .into_iter()
+            .try_fold(Value::clone(&input), delpath)
+            .map(Input::from)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::json;
+
+    fn query(value: &Value, filter: &str) -> Result<Vec<Value>, JQError> {
+        let expr = parse(filter).expect("failed to parse");
+        query_iter(Input::from(value), &expr, &Env::new())
+            .map(|value| value.map(Input::into_value))
+            .collect()
+    }
+
+    #[test]
+    fn test_getpath() {
+        let value = json!({"a": [1, {"b": 2}], "c": null});
+        assert_eq!(
+            query(
+                &value,
+                r#"getpath(["a", 1, "b"], ["a", -2], ["x", "y"], ["c", 0], [])"#
+            )
+            .expect("failed"),
+            [json!(2), json!(1), Value::Null, Value::Null, value.clone()]
+        );
+        // As in jq, an index that is not an integer is missing
+        assert_eq!(
+            query(&value, r#"getpath(["a", 0.7]), getpath(["a", 1.5, "b"])"#).expect("failed"),
+            [Value::Null, Value::Null]
+        );
+        assert_eq!(
+            query(&value, r#"getpath(["a", {"start": 1, "end": null}])"#).expect("failed"),
+            [json!([{"b": 2}])]
+        );
+        assert_eq!(
+            query(&json!("abcd"), r#"getpath([{"start": 1, "end": -1}])"#).expect("failed"),
+            [json!("bc")]
+        );
+        assert_eq!(
+            query(&value, "getpath(path(.a[-1:][0].b))").expect("failed"),
+            [json!(2)]
+        );
+        assert!(query(&value, r#"getpath(["a", "b"])"#).is_err());
+        assert!(query(&value, r#"getpath("a")"#).is_err());
+    }
+
+    #[test]
+    fn test_setpath() {
+        assert_eq!(
+            query(
+                &json!({"a": 1, "b": 2}),
+                r#"setpath(["a"]; 3), setpath(["c", 1]; true)"#
+            )
+            .expect("failed"),
+            [
+                json!({"a": 3, "b": 2}),
+                json!({"a": 1, "b": 2, "c": [null, true]})
+            ]
+        );
+        assert_eq!(
+            query(&json!([1, 2, 3]), r#"setpath([-1]; 0), setpath([]; 0)"#).expect("failed"),
+            [json!([1, 2, 0]), json!(0)]
+        );
+        assert_eq!(
+            query(
+                &json!([1, 2, 3]),
+                r#"setpath([{"start": 1, "end": 2}]; ["a", "b"])"#
+            )
+            .expect("failed"),
+            [json!([1, "a", "b", 3])]
+        );
+        assert_eq!(
+            query(&json!([1, 2, 3]), r#"setpath(path(.[1:][0]); 0)"#).expect("failed"),
+            [json!([1, 0, 3])]
+        );
+        assert_eq!(
+            query(&Value::Null, r#"[setpath(["a"], ["b"]; 1, 2)]"#).expect("failed"),
+            [json!([{"a": 1}, {"a": 2}, {"b": 1}, {"b": 2}])]
+        );
+        assert!(query(&json!([1]), r#"setpath([-2]; 0)"#).is_err());
+        assert!(matches!(
+            query(&json!([1]), r#"setpath([1e10]; 9)"#),
+            Err(JQError::ArrayQuery(message)) if message == "Array index too large"
+        ));
+        assert!(query(&json!({"a": 1}), r#"setpath(["a", "b"]; 0)"#).is_err());
+    }
+
+    #[test]
+    fn test_delpaths() {
+        let value = json!({"a": [1, 2, 3], "b": {"c": 1, "d": 2}, "e": 3});
+        assert_eq!(
+            query(
+                &value,
+                r#"delpaths([["a", 0], ["a", 2], ["b", "c"], ["x", "y"]])"#
+            )
+            .expect("failed"),
+            [json!({"a": [2], "b": {"d": 2}, "e": 3})]
+        );
+        // The order of the remaining keys is kept
+        assert_eq!(
+            query(&value, r#"delpaths([["a"]]) | keys_unsorted"#).expect("failed"),
+            [json!(["b", "e"])]
+        );
+        assert_eq!(
+            query(&value, r#"delpaths([["a", {"start": 1}]]), delpaths([[]])"#).expect("failed"),
+            [
+                json!({"a": [1], "b": {"c": 1, "d": 2}, "e": 3}),
+                Value::Null
+            ]
+        );
+        assert_eq!(
+            query(&value, r#"delpaths([path(.a[:2]), path(.b)])"#).expect("failed"),
+            [json!({"a": [3], "e": 3})]
+        );
+        assert!(query(&value, r#"delpaths(["a"])"#).is_err());
+        assert!(query(&value, r#"delpaths([["e", 0]])"#).is_err());
+    }
+}
@@ -1,20 +1,14 @@
 /// `has` function
 ///
-use crate::{
-    query_ident, query_identity, query_index, query_range, HasType, JQError, Token, Value,
-};
+use crate::{query_expr, Expr, HasType, JQError, Value};
 
 /// Returns boolean if the input includes the element
-pub fn fn_has<'a>(inputs: &Vec<Value>, has: &HasType<'a>) -> Result<Vec<Value>, JQError> {
+pub fn fn_has(inputs: &[Value], has: &HasType) -> Result<Vec<Value>, JQError> {
     let mut results: Vec<Value> = Vec::new();
+    let path = Expr::from(has);
 
     for input in inputs {
-        let result = match Token::from(has) {
-            Token::Identity => query_identity(input),
-            Token::Ident(ident, silent) => query_ident(input, ident, silent),
-            Token::Range(range) => query_range(input, &range),
-            Token::Index(index) => query_index(input, &index),
-        };
+        let result = query_expr(input, &path);
         if let Ok(r) = result {
             if !r.is_empty() && !r[0].is_null() {
                 results.push(Value::Bool(true));
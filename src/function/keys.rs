@@ -3,14 +3,13 @@
 use crate::{JQError, Value};
 
 /// keys return a sorted set of key values
-pub fn fn_keys(values: &Vec<Value>, sort: bool) -> Result<Vec<Value>, JQError> {
+pub fn fn_keys(values: &[Value], sort: bool) -> Result<Vec<Value>, JQError> {
     let mut results: Vec<Value> = Vec::new();
 
     for value in values {
         if let Some(object) = value.as_object() {
             if sort {
-                let mut keys: Vec<String> =
-                    object.keys().into_iter().map(|s| s.to_owned()).collect();
+                let mut keys: Vec<String> = object.keys().map(|s| s.to_owned()).collect();
                 keys.sort();
                 let keys = Value::from(
                     keys.iter()
@@ -22,7 +21,6 @@ pub fn fn_keys(values: &Vec<Value>, sort: bool) -> Result<Vec<Value>, JQError> {
                 let keys = Value::from(
                     object
                         .keys()
-                        .into_iter()
                         .map(|s| Value::from(s.to_owned()))
                         .collect::<Vec<Value>>(),
                 );
@@ -52,7 +50,7 @@ mod tests {
     fn test_keys() {
         let value = json!({"abc": 1, "abcd": 2, "Foo": 3});
 
-        let result = fn_keys(&vec![value], true).expect("failed");
+        let result = fn_keys(&[value], true).expect("failed");
         //dbg!(&result);
         assert_eq!(result, &[json!(["Foo", "abc", "abcd"])]);
     }
@@ -61,7 +59,7 @@ mod tests {
     fn test_keys_unsorted() {
         let value = json!({"abc": 1, "abcd": 2, "Foo": 3});
 
-        let result = fn_keys(&vec![value], false).expect("failed");
+        let result = fn_keys(&[value], false).expect("failed");
         //dbg!(&result);
         assert_eq!(result, &[json!(["abc", "abcd", "Foo"])]);
     }
@@ -69,7 +67,7 @@ mod tests {
     fn test_keys_with_array() {
         let value = json!([42, 3, 5]);
 
-        let result = fn_keys(&vec![value], true).expect("failed");
+        let result = fn_keys(&[value], true).expect("failed");
         //dbg!(&result);
         assert_eq!(result, &[json!([0, 1, 2])]);
     }
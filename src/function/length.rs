@@ -22,7 +22,7 @@ fn single_length(input: &Value) -> Result<Value, JQError> {
 /// * Number: returns the number
 /// * null: returns 0
 /// * bool: returns error
-pub fn fn_length(inputs: &Vec<Value>) -> Result<Vec<Value>, JQError> {
+pub fn fn_length(inputs: &[Value]) -> Result<Vec<Value>, JQError> {
     let mut results: Vec<Value> = Vec::new();
 
     for input in inputs {
@@ -0,0 +1,86 @@
+/// `limit` function
+///
+use crate::{once, query_iter, value_name, Env, FilterArg, Input, JQError, Value, ValueIter};
+
+/// The number of results that `limit` emits for `count`, or `None` for every
+/// result if it is negative
+pub(crate) fn limit_count(count: &Value) -> Result<Option<usize>, JQError> {
+    match count {
+        Value::Number(count) => {
+            let count = count.as_f64().unwrap_or_default();
+            Ok((count >= 0.0).then(|| count.ceil() as usize))
+        }
+        count => Err(JQError::InvalidArgument(
+            value_name(count).to_string(),
+            "limit".to_string(),
+        )),
+    }
+}
+
+/// Emits at most `count` results of the sub-query, and stops evaluating it
+/// once the limit is reached.  A negative count emits every result.
+pub fn fn_limit<'a>(
+    input: Input<'a>,
+    count: &'a FilterArg,
+    filter: &'a FilterArg,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(query_iter(input.clone(), count, &env.clone()).flat_map(
+        move |count| -> ValueIter<'a> {
+            let count = match count {
+                Ok(count) => count,
+                Err(error) => return once(Err(error)),
+            };
+            let results = query_iter(input.clone(), filter, &env);
+            match limit_count(&count) {
+                Ok(Some(count)) => Box::new(results.take(count)),
+                Ok(None) => results,
+                Err(error) => once(Err(error)),
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::json;
+
+    fn limit(value: &Value, count: &str, filter: &str) -> Result<Vec<Value>, JQError> {
+        let count = Box::new(parse(count).expect("failed to parse"));
+        let filter = Box::new(parse(filter).expect("failed to parse"));
+        fn_limit(Input::from(value), &count, &filter, &Env::new())
+            .map(|value| value.map(Input::into_value))
+            .collect()
+    }
+
+    #[test]
+    fn test_limit() {
+        let value = json!([1, 2, 3]);
+        assert_eq!(
+            limit(&value, "2", ".[]").expect("failed"),
+            [json!(1), json!(2)]
+        );
+        assert!(limit(&value, "0", ".[]").expect("failed").is_empty());
+        assert_eq!(limit(&value, "-1", ".[]").expect("failed").len(), 3);
+        assert_eq!(limit(&value, "1, 2", ".[]").expect("failed").len(), 3);
+    }
+
+    #[test]
+    fn test_limit_stops_early() {
+        let value = json!({"a": 1});
+        assert_eq!(limit(&value, "1", ".a, .a.b").expect("failed"), [json!(1)]);
+        assert!(limit(&value, "2", ".a, .a.b").is_err());
+    }
+
+    #[test]
+    fn test_limit_not_a_number() {
+        let value = json!([1]);
+        assert!(matches!(
+            limit(&value, r#""a""#, ".[]"),
+            Err(JQError::InvalidArgument(..))
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+/// `map` and `map_values` functions
+///
+use crate::{query_iter, value_name, Env, FilterArg, Input, JQError, Value};
+
+/// `map(f)` is equivalent to `[.[] | f]`.  Objects are iterated by value, so
+/// the result is always an array.
+pub fn fn_map(inputs: &[Value], filter: &FilterArg, env: &Env) -> Result<Vec<Value>, JQError> {
+    let mut results: Vec<Value> = Vec::new();
+
+    for input in inputs {
+        let values: Vec<&Value> = match input {
+            Value::Array(array) => array.iter().collect(),
+            Value::Object(object) => object.values().collect(),
+            _ => return Err(JQError::CannotIterate(value_name(input).to_string())),
+        };
+        let mut mapped: Vec<Value> = Vec::new();
+        for value in values {
+            for result in query_iter(Input::from(value), filter, env) {
+                mapped.push(result?.into_value());
+            }
+        }
+        results.push(Value::from(mapped));
+    }
+    Ok(results)
+}
+
+/// `map_values(f)` applies `f` to each value of an array or object and keeps
+/// the first result.  Elements for which `f` produces no result are removed.
+pub fn fn_map_values(
+    inputs: &[Value],
+    filter: &FilterArg,
+    env: &Env,
+) -> Result<Vec<Value>, JQError> {
+    let mut results: Vec<Value> = Vec::new();
+
+    for input in inputs {
+        let first = |value: &Value| -> Result<Option<Value>, JQError> {
+            query_iter(Input::from(value), filter, env)
+                .next()
+                .transpose()
+                .map(|value| value.map(Input::into_value))
+        };
+        let result = match input {
+            Value::Array(array) => {
+                let mut mapped: Vec<Value> = Vec::new();
+                for value in array {
+                    if let Some(v) = first(value)? {
+                        mapped.push(v);
+                    }
+                }
+                Value::from(mapped)
+            }
+            Value::Object(object) => {
+                let mut mapped = object.clone();
+                for (key, value) in object {
+                    match first(value)? {
+                        Some(v) => mapped.insert(key.to_owned(), v),
+                        None => mapped.remove(key),
+                    };
+                }
+                Value::Object(mapped)
+            }
+            _ => return Err(JQError::CannotIterate(value_name(input).to_string())),
+        };
+        results.push(result);
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::json;
+
+    #[test]
+    fn test_map() {
+        let values = vec![json!([1, 2, 3]), json!({"a": 1, "b": 2})];
+        let filter = Box::new(parse(". + 1").expect("failed to parse"));
+        let result = fn_map(&values, &filter, &Env::new()).expect("failed");
+        assert_eq!(result, [json!([2, 3, 4]), json!([2, 3])]);
+    }
+
+    #[test]
+    fn test_map_multiple_outputs() {
+        let values = vec![json!([1, 2])];
+        let filter = Box::new(parse(". , . * 10").expect("failed to parse"));
+        let result = fn_map(&values, &filter, &Env::new()).expect("failed");
+        assert_eq!(result, [json!([1, 10, 2, 20])]);
+    }
+
+    #[test]
+    fn test_map_not_iterable() {
+        let filter = Box::new(parse(".").expect("failed to parse"));
+        assert!(fn_map(&[json!(1)], &filter, &Env::new()).is_err());
+    }
+
+    #[test]
+    fn test_map_values() {
+        let values = vec![json!({"a": 1, "b": 2}), json!([1, 2])];
+        let filter = Box::new(parse(". * 10, 0").expect("failed to parse"));
+        let result = fn_map_values(&values, &filter, &Env::new()).expect("failed");
+        assert_eq!(result, [json!({"a": 10, "b": 20}), json!([10, 20])]);
+    }
+
+    #[test]
+    fn test_map_values_removes_empty() {
+        let values = vec![json!({"a": 1, "b": 2}), json!([1, 2, 3])];
+        let filter = Box::new(parse("select(. != 2)").expect("failed to parse"));
+        let result = fn_map_values(&values, &filter, &Env::new()).expect("failed");
+        assert_eq!(result, [json!({"a": 1}), json!([1, 3])]);
+    }
+}
@@ -1,13 +1,44 @@
-use crate::HasType;
+use crate::{Expr, HasType, JQError, Loader};
+pub use error::*;
+pub use first::*;
+pub use getpath::*;
 pub use has::*;
 pub use keys::*;
 pub use length::*;
+pub use limit::*;
+pub use map::*;
+pub use modulemeta::*;
+pub use not::*;
+pub use path::*;
 pub use recurse::*;
+pub use select::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+/// `error`
+pub mod error;
+/// `first`
+pub mod first;
+/// `getpath`, `setpath` and `delpaths`
+pub mod getpath;
 /// `has`
 pub mod has;
 /// `length`
 pub mod length;
+/// `limit`
+pub mod limit;
+
+/// `map` and `map_values`
+pub mod map;
+
+/// `modulemeta`
+pub mod modulemeta;
+
+/// `not`
+pub mod not;
+
+/// `path`, `paths` and `leaf_paths`
+pub mod path;
 
 /// `recurse`
 pub mod recurse;
@@ -15,15 +46,210 @@ pub mod recurse;
 /// `keys`
 pub mod keys;
 
+/// `select`
+pub mod select;
+
+/// A filter argument to a [Function].  This is a full sub-query that the
+/// function evaluates against its input, such as the `.a > 1` in `select(.a > 1)`.
+pub type FilterArg = Box<Expr>;
+
 /// Represents a Function in the PEG parser
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum Function<'a> {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Function {
     /// [fn_length]
     Length,
     /// [fn_has]
-    Has(HasType<'a>),
+    Has(HasType),
     /// Recursive descent
     Recurse,
+    /// [fn_recurse_with]
+    RecurseWith(FilterArg),
     /// Get (sorted) keys from objects
     Keys(bool),
+    /// [fn_not]
+    Not,
+    /// [fn_select]
+    Select(FilterArg),
+    /// [fn_map]
+    Map(FilterArg),
+    /// [fn_map_values]
+    MapValues(FilterArg),
+    /// [fn_first]
+    First(FilterArg),
+    /// [fn_limit]
+    Limit(FilterArg, FilterArg),
+    /// [fn_modulemeta], with the loader of the query that calls it
+    ModuleMeta(Loader),
+    /// [fn_error], with an optional message
+    Error(Option<FilterArg>),
+    /// [fn_path]
+    Path(FilterArg),
+    /// [fn_paths], with an optional filter
+    Paths(Option<FilterArg>),
+    /// [fn_leaf_paths]
+    LeafPaths,
+    /// [fn_getpath]
+    GetPath(FilterArg),
+    /// [fn_setpath]
+    SetPath(FilterArg, FilterArg),
+    /// [fn_delpaths]
+    DelPaths(FilterArg),
+}
+
+impl Function {
+    /// The name that the function is called by, ie: `map`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Function::Length => "length",
+            Function::Has(_) => "has",
+            Function::Recurse | Function::RecurseWith(_) => "recurse",
+            Function::Keys(true) => "keys",
+            Function::Keys(false) => "keys_unsorted",
+            Function::Not => "not",
+            Function::Select(_) => "select",
+            Function::Map(_) => "map",
+            Function::MapValues(_) => "map_values",
+            Function::First(_) => "first",
+            Function::Limit(..) => "limit",
+            Function::ModuleMeta(_) => "modulemeta",
+            Function::Error(_) => "error",
+            Function::Path(_) => "path",
+            Function::Paths(_) => "paths",
+            Function::LeafPaths => "leaf_paths",
+            Function::GetPath(_) => "getpath",
+            Function::SetPath(..) => "setpath",
+            Function::DelPaths(_) => "delpaths",
+        }
+    }
+
+    /// The filter arguments of the function
+    pub fn args(&self) -> Vec<&Expr> {
+        match self {
+            Function::Error(arg) | Function::Paths(arg) => arg.iter().map(|arg| &**arg).collect(),
+            Function::Select(filter)
+            | Function::Map(filter)
+            | Function::MapValues(filter)
+            | Function::First(filter)
+            | Function::RecurseWith(filter)
+            | Function::Path(filter)
+            | Function::GetPath(filter)
+            | Function::DelPaths(filter) => vec![filter],
+            Function::Limit(count, filter) => vec![count, filter],
+            Function::SetPath(paths, value) => vec![paths, value],
+            Function::Length
+            | Function::Has(_)
+            | Function::Recurse
+            | Function::Keys(_)
+            | Function::Not
+            | Function::ModuleMeta(_)
+            | Function::LeafPaths => Vec::new(),
+        }
+    }
+
+    /// The number of arguments the function is called with
+    pub fn arity(&self) -> usize {
+        match self {
+            Function::Has(_) => 1,
+            function => function.args().len(),
+        }
+    }
+
+    /// The filter arguments of the function, for modification
+    pub fn args_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Function::Error(arg) | Function::Paths(arg) => {
+                arg.iter_mut().map(|arg| &mut **arg).collect()
+            }
+            Function::Select(filter)
+            | Function::Map(filter)
+            | Function::MapValues(filter)
+            | Function::First(filter)
+            | Function::RecurseWith(filter)
+            | Function::Path(filter)
+            | Function::GetPath(filter)
+            | Function::DelPaths(filter) => vec![filter],
+            Function::Limit(count, filter) => vec![count, filter],
+            Function::SetPath(paths, value) => vec![paths, value],
+            Function::Length
+            | Function::Has(_)
+            | Function::Recurse
+            | Function::Keys(_)
+            | Function::Not
+            | Function::ModuleMeta(_)
+            | Function::LeafPaths => Vec::new(),
+        }
+    }
+
+    /// The name and arguments of the function, as they were called.  The
+    /// argument of `has` becomes a literal.
+    pub fn into_call(self) -> (String, Vec<Expr>) {
+        let name = self.name().to_string();
+        let args = match self {
+            Function::Has(has) => {
+                let key = match (has.as_ident(), has.as_index()) {
+                    (Ok(ident), _) => Value::from(ident),
+                    (_, Ok(index)) => Value::from(index),
+                    _ => Value::Null,
+                };
+                vec![Expr::Literal(key)]
+            }
+            Function::Select(filter)
+            | Function::Map(filter)
+            | Function::MapValues(filter)
+            | Function::First(filter)
+            | Function::RecurseWith(filter)
+            | Function::Path(filter)
+            | Function::GetPath(filter)
+            | Function::DelPaths(filter) => vec![*filter],
+            Function::Limit(count, filter) => vec![*count, *filter],
+            Function::SetPath(paths, value) => vec![*paths, *value],
+            Function::Error(arg) | Function::Paths(arg) => {
+                arg.into_iter().map(|arg| *arg).collect()
+            }
+            Function::Length
+            | Function::Recurse
+            | Function::Keys(_)
+            | Function::Not
+            | Function::ModuleMeta(_)
+            | Function::LeafPaths => Vec::new(),
+        };
+        (name, args)
+    }
+
+    /// Resolve a builtin by name and number of filter arguments, ie: `map/1`.
+    pub fn from_call(name: &str, mut args: Vec<Expr>) -> Result<Self, JQError> {
+        let function = match (name, args.len()) {
+            ("not", 0) => Function::Not,
+            ("modulemeta", 0) => Function::ModuleMeta(Loader::new()),
+            ("error", 0) => Function::Error(None),
+            ("error", 1) => Function::Error(Some(Box::new(args.remove(0)))),
+            ("select", 1) => Function::Select(Box::new(args.remove(0))),
+            ("map", 1) => Function::Map(Box::new(args.remove(0))),
+            ("map_values", 1) => Function::MapValues(Box::new(args.remove(0))),
+            ("first", 1) => Function::First(Box::new(args.remove(0))),
+            ("recurse", 1) => Function::RecurseWith(Box::new(args.remove(0))),
+            ("limit", 2) => {
+                let count = args.remove(0);
+                Function::Limit(Box::new(count), Box::new(args.remove(0)))
+            }
+            ("path", 1) => Function::Path(Box::new(args.remove(0))),
+            ("paths", 0) => Function::Paths(None),
+            ("paths", 1) => Function::Paths(Some(Box::new(args.remove(0)))),
+            ("leaf_paths", 0) => Function::LeafPaths,
+            ("getpath", 1) => Function::GetPath(Box::new(args.remove(0))),
+            ("setpath", 2) => {
+                let paths = args.remove(0);
+                Function::SetPath(Box::new(paths), Box::new(args.remove(0)))
+            }
+            ("delpaths", 1) => Function::DelPaths(Box::new(args.remove(0))),
+            _ => {
+                return Err(JQError::UndefinedFunction(format!(
+                    "{}/{}",
+                    name,
+                    args.len()
+                )))
+            }
+        };
+        Ok(function)
+    }
 }
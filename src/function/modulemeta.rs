@@ -0,0 +1,48 @@
+/// `modulemeta` function
+///
+use crate::{value_name, JQError, Loader, Value};
+
+/// Returns the metadata of the module named by each input, which must be a
+/// string such as `"lib"`.  The module is looked up with `loader`, and its
+/// metadata includes `deps`, its `import` and `include` directives, and
+/// `defs`, the functions that it defines.
+pub fn fn_modulemeta(inputs: &[Value], loader: &Loader) -> Result<Vec<Value>, JQError> {
+    inputs
+        .iter()
+        .map(|input| match input {
+            Value::String(name) => loader.modulemeta(name),
+            value => Err(JQError::InvalidArgument(
+                value_name(value).to_string(),
+                "modulemeta".to_string(),
+            )),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_modulemeta() {
+        let loader =
+            Loader::new().with_search_path(concat!(env!("CARGO_MANIFEST_DIR"), "/test/modules"));
+        let result = fn_modulemeta(&[json!("nested")], &loader).expect("failed");
+        assert_eq!(
+            result,
+            [json!({
+                "deps": [{"as": "lib", "is_data": false, "relpath": "lib"}],
+                "defs": ["quadruple/0"]
+            })]
+        );
+        assert!(matches!(
+            fn_modulemeta(&[json!("missing")], &loader),
+            Err(JQError::ModuleNotFound(_))
+        ));
+        assert!(matches!(
+            fn_modulemeta(&[json!(1)], &loader),
+            Err(JQError::InvalidArgument(..))
+        ));
+    }
+}
@@ -0,0 +1,34 @@
+/// `not` function
+///
+use crate::{is_truthy, JQError, Value};
+
+/// Returns the boolean negation of each input.  `false` and `null` are
+/// false, every other value is true.
+pub fn fn_not(inputs: &[Value]) -> Result<Vec<Value>, JQError> {
+    Ok(inputs
+        .iter()
+        .map(|input| Value::Bool(!is_truthy(input)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_not() {
+        let values = vec![json!(true), json!(false), json!(null), json!(0), json!("")];
+        let result = fn_not(&values).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!(false),
+                json!(true),
+                json!(true),
+                json!(false),
+                json!(false)
+            ]
+        );
+    }
+}
@@ -0,0 +1,141 @@
+/// `path`, `paths` and `leaf_paths` functions
+///
+use crate::{
+    is_truthy, once, path_recurse, query_iter, query_paths, Env, Expr, FilterArg, Input, Value,
+    ValueIter,
+};
+
+/// Emits the path to each result of the sub-query, as an array of object keys
+/// and array indexes, ie: `path(.a[0].b)` is `["a", 0, "b"]`.  A slice is a
+/// single key, so `path(.a[1:])` is `["a", {"start": 1, "end": null}]`.  It is
+/// an error if a result is not part of the input, such as `path(1)`.
+pub fn fn_path<'a>(input: Input<'a>, filter: &'a FilterArg, env: &Env<'a>) -> ValueIter<'a> {
+    Box::new(
+        query_paths(input, filter, env)
+            .map(|result| result.map(|(path, _)| Input::from(Value::from(path)))),
+    )
+}
+
+/// Emits the path to every value in the input, other than the input itself,
+/// ie: `path(..)`.  With a filter, only the paths to values for which it is
+/// truthy are emitted, once for each truthy result.
+pub fn fn_paths<'a>(input: Input<'a>, filter: Option<&'a Expr>, env: &Env<'a>) -> ValueIter<'a> {
+    match filter {
+        Some(filter) => {
+            let env = env.clone();
+            paths_where(input, move |value| query_iter(value, filter, &env))
+        }
+        None => Box::new(
+            path_recurse(input)
+                .skip(1)
+                .map(|result| result.map(|(path, _)| Input::from(Value::from(path)))),
+        ),
+    }
+}
+
+/// Emits the path to every truthy value in the input that is not an array or
+/// object, other than the input itself.  The same as jq's `paths(scalars)`.
+pub fn fn_leaf_paths(input: Input<'_>) -> ValueIter<'_> {
+    paths_where(input, |value| -> ValueIter<'_> {
+        if value.is_array() || value.is_object() {
+            Box::new(std::iter::empty())
+        } else {
+            once(Ok(value))
+        }
+    })
+}
+
+/// The paths of `paths(f)`, where `filter` runs `f` on each value
+fn paths_where<'a, F>(input: Input<'a>, filter: F) -> ValueIter<'a>
+where
+    F: Fn(Input<'a>) -> ValueIter<'a> + 'a,
+{
+    Box::new(
+        path_recurse(input)
+            .skip(1)
+            .flat_map(move |result| -> ValueIter<'a> {
+                let (path, value) = match result {
+                    Ok(result) => result,
+                    Err(error) => return once(Err(error)),
+                };
+                let path = Value::from(path);
+                Box::new(filter(value).filter_map(move |value| match value {
+                    Ok(value) if is_truthy(&value) => Some(Ok(Input::from(path.clone()))),
+                    Ok(_) => None,
+                    Err(error) => Some(Err(error)),
+                }))
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse, JQError};
+    use serde_json::json;
+
+    fn paths(value: &Value, filter: &str) -> Result<Vec<Value>, JQError> {
+        let expr = parse(filter).expect("failed to parse");
+        query_iter(Input::from(value), &expr, &Env::new())
+            .map(|value| value.map(Input::into_value))
+            .collect()
+    }
+
+    #[test]
+    fn test_path() {
+        let value = json!({"a": [{"b": 1}, {"b": 2}]});
+        assert_eq!(
+            paths(&value, "path(.a[0].b), path(.a[]), path(.), path(.a[1:])").expect("failed"),
+            [
+                json!(["a", 0, "b"]),
+                json!(["a", 0]),
+                json!(["a", 1]),
+                json!([]),
+                json!(["a", {"start": 1, "end": null}])
+            ]
+        );
+        assert_eq!(
+            paths(&json!({"s": "abc"}), "path(.s[:-1]), path(.x[1:2][0])").expect("failed"),
+            [
+                json!(["s", {"start": null, "end": -1}]),
+                json!(["x", {"start": 1, "end": 2}, 0])
+            ]
+        );
+        assert_eq!(
+            paths(&value, "[path(.a[] | select(.b > 1))], [path(.x?, .a[5])]").expect("failed"),
+            [json!([["a", 1]]), json!([["x"], ["a", 5]])]
+        );
+        assert!(matches!(
+            paths(&value, "path(.a | length)"),
+            Err(JQError::InvalidPathExpression(result)) if result == "2"
+        ));
+    }
+
+    #[test]
+    fn test_paths() {
+        let value = json!({"a": [1, {"b": null}], "c": "x", "d": false});
+        assert_eq!(
+            paths(&value, "[paths]").expect("failed"),
+            [json!([
+                ["a"],
+                ["a", 0],
+                ["a", 1],
+                ["a", 1, "b"],
+                ["c"],
+                ["d"]
+            ])]
+        );
+        assert_eq!(
+            paths(&value, r#"[paths(. == "x" or . == 1)]"#).expect("failed"),
+            [json!([["a", 0], ["c"]])]
+        );
+        assert_eq!(
+            paths(&value, "[leaf_paths]").expect("failed"),
+            [json!([["a", 0], ["c"]])]
+        );
+        assert_eq!(
+            paths(&json!(1), "[paths], [leaf_paths]").expect("failed"),
+            [json!([]), json!([])]
+        );
+    }
+}
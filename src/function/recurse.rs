@@ -1,33 +1,85 @@
-use crate::{JQError, Value};
+use crate::{query_iter, Env, Expr, Input, JQError, Results, Value, ValueIter};
 
-fn recurse_single_value(value: &Value) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = vec![value.to_owned()];
-
-    if let Some(object) = value.as_object() {
-        for (_key, val) in object {
-            let mut vals = recurse_single_value(val)?;
-            results.append(&mut vals);
-        }
-    } else if let Some(array) = value.as_array() {
-        for val in array {
-            let mut vals = recurse_single_value(val)?;
-            results.append(&mut vals);
+/// Recursively descend objects and arrays.  Each value is yielded before its
+/// children, and values are borrowed from the input rather than cloned.
+/// This is only intened to be used on Identity, `.`
+pub fn fn_recurse(value: &Value) -> impl Iterator<Item = &Value> {
+    let mut stack = vec![value];
+    std::iter::from_fn(move || {
+        let value = stack.pop()?;
+        match value {
+            Value::Object(object) => stack.extend(object.values().rev()),
+            Value::Array(array) => stack.extend(array.iter().rev()),
+            _ => {}
         }
-    }
+        Some(value)
+    })
+}
 
-    Ok(results)
+/// Like [fn_recurse], but each value is paired with its path from `value`,
+/// the keys and indexes that lead to it
+pub fn fn_recurse_paths(value: &Value) -> impl Iterator<Item = (Vec<Value>, &Value)> {
+    let mut stack = vec![(Vec::new(), value)];
+    std::iter::from_fn(move || {
+        let (path, value) = stack.pop()?;
+        let child = |key: Value, child| {
+            let mut path = path.clone();
+            path.push(key);
+            (path, child)
+        };
+        match value {
+            Value::Object(object) => stack.extend(
+                object
+                    .iter()
+                    .rev()
+                    .map(|(key, value)| child(Value::from(key.as_str()), value)),
+            ),
+            Value::Array(array) => stack.extend(
+                array
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, value)| child(Value::from(index), value)),
+            ),
+            _ => {}
+        }
+        Some((path, value))
+    })
 }
 
-/// Recursively descend objects. This is only intened to be used on
-/// Identity, `.`
-pub fn fn_recurse(values: &Vec<Value>) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = Vec::new();
+/// `recurse(f)`, the input, then recursively the results of `f` on each
+/// result, depth first.  The same as jq's `def recurse(f): def r: ., (f | r); r;`
+pub fn fn_recurse_with<'a>(input: Input<'a>, filter: &'a Expr, env: &Env<'a>) -> ValueIter<'a> {
+    let env = env.clone();
+    recurse_with(input, move |value: &Input<'a>| {
+        query_iter(value.clone(), filter, &env)
+    })
+}
 
-    for value in values {
-        let mut result = recurse_single_value(value)?;
-        results.append(&mut result);
-    }
-    Ok(results)
+/// The depth first results of recursively applying `children` to `first`.
+/// The pending results are kept on the heap rather than the native stack, so
+/// however deep the recursion, it cannot overflow.  The first error ends it.
+pub(crate) fn recurse_with<'a, T, F>(first: T, children: F) -> Results<'a, T>
+where
+    T: 'a,
+    F: Fn(&T) -> Results<'a, T> + 'a,
+{
+    let mut stack: Vec<Results<'a, T>> = vec![Box::new(std::iter::once(Ok(first)))];
+    Box::new(std::iter::from_fn(move || loop {
+        match stack.last_mut()?.next() {
+            Some(Ok(value)) => {
+                stack.push(children(&value));
+                return Some(Ok(value));
+            }
+            Some(Err(error)) => {
+                stack.clear();
+                return Some(Err::<T, JQError>(error));
+            }
+            None => {
+                stack.pop();
+            }
+        }
+    }))
 }
 
 #[cfg(test)]
@@ -37,29 +89,82 @@ mod tests {
 
     #[test]
     fn test_fn_recurse() {
-        let values = vec![json!([[{"a":1}]])];
-        let result = fn_recurse(&values).expect("Failed");
+        let value = json!([[{"a":1}]]);
+        let result: Vec<&Value> = fn_recurse(&value).collect();
         //dbg!(&result);
         assert_eq!(
             result,
             &[
-                json!([
+                &json!([
                   [
                     {
                       "a": 1
                     }
                   ]
                 ]),
-                json!([
+                &json!([
                   {
                     "a": 1
                   }
                 ]),
-                json!({
+                &json!({
                   "a": 1
                 }),
-                json!(1)
+                &json!(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fn_recurse_order() {
+        let value = json!({"a": [1, 2], "b": 3});
+        let result: Vec<&Value> = fn_recurse(&value).skip(1).collect();
+        assert_eq!(result, [&json!([1, 2]), &json!(1), &json!(2), &json!(3)]);
+    }
+
+    #[test]
+    fn test_fn_recurse_with() {
+        let recurse = |value: &Value, filter: &str| -> Result<Vec<Value>, JQError> {
+            let expr = crate::parse(filter).expect("failed to parse");
+            query_iter(Input::from(value), &expr, &Env::new())
+                .map(|value| value.map(Input::into_value))
+                .collect()
+        };
+        let value = json!({"a": [{"a": []}], "b": 1});
+        assert_eq!(
+            recurse(&value, "[recurse(.a[]?)]").expect("failed"),
+            [json!([value, {"a": []}])]
+        );
+        assert_eq!(
+            recurse(&json!(2), "[limit(3; recurse(. * .))]").expect("failed"),
+            [json!([2, 4, 16])]
+        );
+        assert!(recurse(&json!(2), "[recurse(if . < 100 then . * . else error end)]").is_err());
+        // Deep recursion does not overflow the stack
+        assert_eq!(
+            recurse(&json!(0), "[limit(100000; recurse(. + 1))] | length").expect("failed"),
+            [json!(100000)]
+        );
+    }
+
+    #[test]
+    fn test_fn_recurse_paths() {
+        let value = json!({"a": [1, {"b": 2}], "c": 3});
+        let paths: Vec<Value> = fn_recurse_paths(&value)
+            .map(|(path, _)| Value::from(path))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                json!([]),
+                json!(["a"]),
+                json!(["a", 0]),
+                json!(["a", 1]),
+                json!(["a", 1, "b"]),
+                json!(["c"])
             ]
         );
+        let values: Vec<&Value> = fn_recurse_paths(&value).map(|(_, value)| value).collect();
+        assert_eq!(values, fn_recurse(&value).collect::<Vec<_>>());
     }
 }
@@ -0,0 +1,45 @@
+/// `select` function
+///
+use crate::{is_truthy, query_iter, Env, FilterArg, Input, ValueIter};
+
+/// Emits the input once for each truthy result of the sub-query, so
+/// `.[] | select(.status == "active")` drops everything that is not active.
+pub fn fn_select<'a>(input: Input<'a>, filter: &'a FilterArg, env: &Env<'a>) -> ValueIter<'a> {
+    Box::new(
+        query_iter(input.clone(), filter, env).filter_map(move |value| match value {
+            Ok(value) if is_truthy(&value) => Some(Ok(input.clone())),
+            Ok(_) => None,
+            Err(error) => Some(Err(error)),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::{json, Value};
+
+    fn select(values: &[Value], filter: &FilterArg) -> Vec<Value> {
+        values
+            .iter()
+            .flat_map(|value| fn_select(Input::from(value), filter, &Env::new()))
+            .map(|value| value.map(Input::into_value))
+            .collect::<Result<_, _>>()
+            .expect("failed")
+    }
+
+    #[test]
+    fn test_select() {
+        let values = vec![json!({"a": 1}), json!({"a": 2}), json!({"b": 3})];
+        let filter = Box::new(parse(".a > 1").expect("failed to parse"));
+        assert_eq!(select(&values, &filter), [json!({"a": 2})]);
+    }
+
+    #[test]
+    fn test_select_multiple_outputs() {
+        let values = vec![json!(1)];
+        let filter = Box::new(parse("true, false, 0").expect("failed to parse"));
+        assert_eq!(select(&values, &filter), [json!(1), json!(1)]);
+    }
+}
@@ -1,15 +1,16 @@
 use crate::JQError;
+use serde::{Deserialize, Serialize};
 
 /// Input for  [Has](crate::Function::Has)
 ///
 /// Must have either an index or an ident.  Cannot have both.
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct HasType<'a> {
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HasType {
     index: Option<isize>,
-    ident: Option<&'a str>,
+    ident: Option<String>,
 }
 
-impl<'a> HasType<'a> {
+impl HasType {
     /// True if ident is Some
     pub fn is_ident(&self) -> bool {
         self.ident.is_some()
@@ -29,24 +30,24 @@ impl<'a> HasType<'a> {
     }
 
     /// Returns the ident, or error if None
-    pub fn as_ident(&self) -> Result<&'a str, JQError> {
-        if self.ident.is_none() {
-            return Err(JQError::HasTypeError("No ident".to_string()));
+    pub fn as_ident(&self) -> Result<&str, JQError> {
+        match &self.ident {
+            Some(ident) => Ok(ident),
+            None => Err(JQError::HasTypeError("No ident".to_string())),
         }
-        Ok(self.ident.unwrap())
     }
 }
 
-impl<'a> From<&'a str> for HasType<'a> {
-    fn from(ident: &'a str) -> Self {
+impl From<&str> for HasType {
+    fn from(ident: &str) -> Self {
         Self {
-            ident: Some(ident),
+            ident: Some(ident.to_string()),
             index: None,
         }
     }
 }
 
-impl<'a> From<isize> for HasType<'a> {
+impl From<isize> for HasType {
     fn from(index: isize) -> Self {
         Self {
             ident: None,
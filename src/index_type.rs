@@ -1,15 +1,16 @@
 use crate::JQError;
+use serde::{Deserialize, Serialize};
 
 /// Represents an identifier-index for objects and arrays. IndexType will have
 /// either an identifier or an index.
-#[derive(PartialEq, Eq, Debug, Clone)]
-pub struct IndexType<'a> {
-    identifier: Option<&'a str>,
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct IndexType {
+    identifier: Option<String>,
     index: Option<Vec<isize>>,
     silent: bool,
 }
 
-impl<'a> IndexType<'a> {
+impl IndexType {
     /// True if identifier is Some
     pub fn is_identitfier(&self) -> bool {
         self.identifier.is_some()
@@ -35,7 +36,7 @@ impl<'a> IndexType<'a> {
 
     /// Return the identifier
     pub fn as_identifier(&self) -> Result<(&str, bool), JQError> {
-        match self.identifier {
+        match &self.identifier {
             Some(s) => Ok((s, self.silent)),
             _ => Err(JQError::BadIndexType),
         }
@@ -50,17 +51,17 @@ impl<'a> IndexType<'a> {
     }
 }
 
-impl<'a> From<(&'a str, bool)> for IndexType<'a> {
-    fn from(id: (&'a str, bool)) -> Self {
+impl From<(&str, bool)> for IndexType {
+    fn from(id: (&str, bool)) -> Self {
         Self {
-            identifier: Some(id.0),
+            identifier: Some(id.0.to_string()),
             index: None,
             silent: id.1,
         }
     }
 }
 
-impl<'a> From<(isize, bool)> for IndexType<'a> {
+impl From<(isize, bool)> for IndexType {
     fn from(index: (isize, bool)) -> Self {
         Self {
             identifier: None,
@@ -70,7 +71,7 @@ impl<'a> From<(isize, bool)> for IndexType<'a> {
     }
 }
 
-impl<'a> From<(Vec<isize>, bool)> for IndexType<'a> {
+impl From<(Vec<isize>, bool)> for IndexType {
     fn from(indexes: (Vec<isize>, bool)) -> Self {
         Self {
             identifier: None,
@@ -0,0 +1,129 @@
+/// Inputs and outputs of the lazy evaluator.
+///
+/// Each expression is evaluated against an [Input], and produces a [ValueIter]
+/// that computes its results as they are pulled, rather than collecting them
+/// into a `Vec` up front.  The results of one expression are the inputs of the
+/// next, so a path into a borrowed document is borrowed all the way through.
+use std::{borrow::Cow, ops::Deref, rc::Rc};
+
+use serde_json::Value;
+
+use crate::JQError;
+
+/// A lazy stream of results.  Evaluation stops as soon as the consumer stops pulling.
+pub type ValueIter<'a> = Box<dyn Iterator<Item = Result<Input<'a>, JQError>> + 'a>;
+
+/// The input to, or result of, an expression.  Cloning an input never clones
+/// the value it refers to.
+#[derive(Clone, Debug)]
+pub enum Input<'a> {
+    /// A value owned by the caller, such as the document passed to [query](crate::query::query),
+    /// or any part of it
+    Borrowed(&'a Value),
+    /// A value produced by the query, such as each result of the left hand side of a pipe
+    Owned(Rc<Value>),
+}
+
+impl<'a> Input<'a> {
+    /// Returns the value, only cloning it if it is borrowed or shared
+    pub fn into_value(self) -> Value {
+        match self {
+            Input::Borrowed(value) => value.clone(),
+            Input::Owned(value) => Rc::try_unwrap(value).unwrap_or_else(|value| (*value).clone()),
+        }
+    }
+
+    /// Returns the borrowed value, or the owned value
+    pub fn into_cow(self) -> Cow<'a, Value> {
+        match self {
+            Input::Borrowed(value) => Cow::Borrowed(value),
+            owned => Cow::Owned(owned.into_value()),
+        }
+    }
+}
+
+impl<'a> Deref for Input<'a> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        match self {
+            Input::Borrowed(value) => value,
+            Input::Owned(value) => value,
+        }
+    }
+}
+
+impl<'a> From<&'a Value> for Input<'a> {
+    fn from(value: &'a Value) -> Self {
+        Self::Borrowed(value)
+    }
+}
+
+impl<'a> From<Value> for Input<'a> {
+    fn from(value: Value) -> Self {
+        Self::Owned(Rc::new(value))
+    }
+}
+
+impl<'a> From<Cow<'a, Value>> for Input<'a> {
+    fn from(value: Cow<'a, Value>) -> Self {
+        match value {
+            Cow::Borrowed(value) => Self::Borrowed(value),
+            Cow::Owned(value) => Self::from(value),
+        }
+    }
+}
+
+/// A stream of a single result
+pub fn once<'a>(result: Result<Input<'a>, JQError>) -> ValueIter<'a> {
+    Box::new(std::iter::once(result))
+}
+
+/// A stream of eagerly computed results.  An error is the only item.
+pub fn from_results<'a>(results: Result<Vec<Value>, JQError>) -> ValueIter<'a> {
+    match results {
+        Ok(values) => Box::new(values.into_iter().map(|value| Ok(Input::from(value)))),
+        Err(error) => once(Err(error)),
+    }
+}
+
+/// Defer building a stream until its first result is pulled
+pub fn deferred<'a>(f: impl FnOnce() -> ValueIter<'a> + 'a) -> ValueIter<'a> {
+    Box::new(std::iter::once_with(f).flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_into_value() {
+        let value = json!({"a": 1});
+        assert_eq!(Input::from(&value).into_value(), value);
+
+        let input = Input::from(value.clone());
+        let shared = input.clone();
+        assert_eq!(input.into_value(), value);
+        assert_eq!(shared.into_value(), value);
+    }
+
+    #[test]
+    fn test_into_cow() {
+        let value = json!({"a": 1});
+        assert!(matches!(Input::from(&value).into_cow(), Cow::Borrowed(_)));
+        assert!(matches!(Input::from(value).into_cow(), Cow::Owned(_)));
+    }
+
+    #[test]
+    fn test_deferred() {
+        let called = std::cell::Cell::new(false);
+        let mut iter = deferred(|| {
+            called.set(true);
+            once(Ok(json!(1).into()))
+        });
+        assert!(!called.get());
+        assert_eq!(*iter.next().expect("no result").expect("failed"), json!(1));
+        assert!(called.get());
+    }
+}
@@ -2,10 +2,85 @@
 ///
 /// This module contains the PEG parser for parsing JQ query strings.
 ///
-use crate::{Action, Block, Function, HasType, IndexType, JQError, RangeType, Token};
+use crate::{
+    query_expr, CompileOptions, Definition, Expr, Function, HasType, Import, IndexType, JQError,
+    Module, ObjectEntry, Operator, Param, Pattern, RangeType,
+};
+use serde_json::{Map, Value};
+use std::cell::Cell;
+
+/// How many bytes of the native stack the parser may use.  The grammar is
+/// recursive, so a query nested more deeply than this allows, ie: by thousands
+/// of parentheses, is a [JQError::ParseError] rather than a stack overflow.
+pub const MAX_PARSE_STACK: usize = 512 * 1024;
+
+/// The address of a local variable, which is how far down the stack the
+/// caller is
+fn stack_position() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+thread_local! {
+    /// The [stack_position] where the current parse started, or 0 if the
+    /// parser was not started by [guarded]
+    static PARSE_BASE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Run the parser, so that the grammar can tell how much of the stack it uses
+fn guarded<T>(parse: impl FnOnce() -> T) -> T {
+    let outer = PARSE_BASE.with(|base| base.replace(stack_position()));
+    let result = parse();
+    PARSE_BASE.with(|base| base.set(outer));
+    result
+}
+
+/// False once the parser has used more than [MAX_PARSE_STACK]
+fn within_parse_stack() -> bool {
+    let base = PARSE_BASE.with(Cell::get);
+    base == 0 || stack_position().abs_diff(base) <= MAX_PARSE_STACK
+}
+
+/// `term`, followed by the path `keys`
+fn with_path(term: Expr, keys: Vec<Expr>) -> Expr {
+    if keys.is_empty() {
+        term
+    } else {
+        Expr::Pipe(Box::new(term), Box::new(Expr::path(keys)))
+    }
+}
+
+/// `lhs op rhs`.  Operators are applied from left to right, so when `lhs` is
+/// already an operation, `op` is appended to it rather than nesting it.
+fn chain_operation(lhs: Expr, op: Operator, rhs: Expr) -> Expr {
+    match lhs {
+        Expr::Operation(first, mut rest) => {
+            rest.push((op, rhs));
+            Expr::Operation(first, rest)
+        }
+        lhs => Expr::Operation(Box::new(lhs), vec![(op, rhs)]),
+    }
+}
+
+/// `-expr`, which is `0 - expr`.  As in jq, negating anything but a number is
+/// an error, because it cannot be subtracted from a number.
+fn negate(expr: Expr) -> Expr {
+    Expr::Operation(
+        Box::new(Expr::Literal(Value::from(0))),
+        vec![(Operator::Subtract, expr)],
+    )
+}
+
+/// The value of directive metadata, which must be a constant object
+fn constant_object(expr: &Expr) -> Result<Map<String, Value>, &'static str> {
+    match query_expr(&Value::Null, expr).as_deref() {
+        Ok([Value::Object(object)]) => Ok(object.clone()),
+        _ => Err("constant object"),
+    }
+}
 
 peg::parser!( grammar query_parser() for str {
-    rule _ = [' ' | '\t']*
+    rule _ = [' ' | '\t' | '\n' | '\r']*
 
     /// Decimal number
     pub rule number() -> isize = n:$(['+' | '-']? ("0" / [ '0'..='9']+)) {n.parse().unwrap()}
@@ -13,206 +88,557 @@ peg::parser!( grammar query_parser() for str {
     pub rule number_list() -> Vec<isize>
     =  number() ++  ","
 
+    /// A character that may continue an identifier or keyword
+    rule ident_char() = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
+
     /// An identifier
     /// Must strt with an alpha character.  Can contain alphanumeric and '_'
-    pub rule ident() -> Token<'input>
-        = s:$(['a'..='z' | 'A'..='Z'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_' ]*) b:"?"? {Token::Ident(s, b.is_some())}
+    pub rule ident() -> Expr
+        = s:$(['a'..='z' | 'A'..='Z'] ['a'..='z' | 'A'..='Z' | '0'..='9' | '_' ]*) b:"?"? {Expr::Ident(s.to_string(), b.is_some())}
 
 
     pub rule string() -> &'input str
         = "\"" !"\"" s:$(['a'..='z' | 'A'..='Z' | '0'..='9' | ' ' | '_' | '-' | '/' | '#']*) "\"" {s}
 
-    pub rule identity() -> Token<'input>
-        = _ "." !"." _ { Token::Identity }
+    /// A JSON string, including escape sequences
+    pub rule string_literal() -> Value
+        = s:$("\"" ("\\" [_] / [^ '"' | '\\'])* "\"") {? serde_json::from_str(s).or(Err("string")) }
+
+    /// A JSON number, either integer or floating point
+    pub rule number_literal() -> Value
+        = n:$("-"? ['0'..='9']+ ("." ['0'..='9']+)? (['e' | 'E'] ['+' | '-']? ['0'..='9']+)?) {? serde_json::from_str(n).or(Err("number")) }
+
+    /// `true`, `false` or `null`
+    pub rule keyword_literal() -> Value
+        = k:$("true" / "false" / "null") !ident_char() {? serde_json::from_str(k).or(Err("keyword")) }
+
+    /// Words that cannot be used as function names
+    rule keyword() = (
+        "def" / "as" / "and" / "or" / "true" / "false" / "null" / "import" / "include" / "module"
+        / "if" / "then" / "elif" / "else" / "end" / "try" / "catch" / "reduce" / "foreach"
+        / "label" / "break"
+    ) !ident_char()
+
+    /// A function or filter parameter name
+    rule function_name() -> &'input str
+        = !keyword() n:$(['a'..='z' | 'A'..='Z' | '_'] ident_char()*) { n }
+
+    /// A function name, optionally qualified by the module it was imported as, ie: `lib::f`
+    rule qualified_name() -> &'input str
+        = $(function_name() ("::" function_name())*)
+
+    /// A variable name, without the leading `$`.  The data of a module
+    /// imported as `$d` is called `$d`, or `$d::d`.
+    rule variable_name() -> String
+        = "$" n:$(['a'..='z' | 'A'..='Z' | '_'] ident_char()* ("::" ident_char()+)*) { n.to_string() }
+
+    /// `$name`
+    pub rule variable() -> Expr
+        = _ n:variable_name() _ { Expr::Variable(n) }
+
+    /// A literal emits the same value for every input
+    pub rule literal() -> Expr
+        = _ v:(string_literal() / number_literal() / keyword_literal()) _ {Expr::Literal(v)}
+
+    pub rule identity() -> Expr
+        = _ "." !"." _ { Expr::Identity }
 
     /// A Range iterates on an object or array
     /// An empty range, `.[]`, iterates all values of an object or array
     /// If not enpty, a range has  `[start:end]` syntax.
     /// Either `start` or `end` may be omitted, but not both.
-    pub rule range() -> Token<'input>
+    pub rule range() -> Expr
         = precedence!{
-            _ "[" _ "]" _ {Token::Range(RangeType::new())}
+            _ "[" _ "]" _ {Expr::Range(RangeType::new())}
             --
-           _ "[" _ start:number() _ ":" _ "]" _ { Token::Range(RangeType::from_start(start)) }
+           _ "[" _ start:number() _ ":" _ "]" _ { Expr::Range(RangeType::from_start(start)) }
            --
-           _ "[" _ ":" _ end:number() _ "]" _ { Token::Range(RangeType::from_end(end)) }
+           _ "[" _ ":" _ end:number() _ "]" _ { Expr::Range(RangeType::from_end(end)) }
            --
-           _ "[" _ start:number() _  ":" _ end:number() _ "]" _ { Token::Range(RangeType::from_both(start,end)) }
+           _ "[" _ start:number() _  ":" _ end:number() _ "]" _ { Expr::Range(RangeType::from_both(start,end)) }
 
         }
 
     /// An  index is either a object index: `[string]` or an array index: `[number]`
     /// Note: an empty set of brackets: `[]` is a range, not an index.
-    pub rule index() -> Token<'input>
+    pub rule index() -> Expr
         = precedence! {
-            _ "[" _ i:string() _ "]" b:"?"?_ {Token::Index(IndexType::from((i, b.is_some())))}
+            _ "[" _ i:string() _ "]" b:"?"?_ {Expr::Index(IndexType::from((i, b.is_some())))}
             --
-            _ "[" _ n:number_list() _ "]" b:"?"? _ {Token::Index(IndexType::from((n, b.is_some())))}
+            _ "[" _ n:number_list() _ "]" b:"?"? _ {Expr::Index(IndexType::from((n, b.is_some())))}
         }
 
-    pub rule identifier() -> Token<'input>
+    pub rule identifier() -> Expr
         = precedence! {
-            _ "." _ i:ident() _ {i}
+            _ "." i:ident() _ {i}
         --
-            _ "." _ s:string() b:"?"? _  {Token::Ident(s, b.is_some())}
+            _ "." _ s:string() b:"?"? _  {Expr::Ident(s.to_string(), b.is_some())}
         }
 
 
-    pub rule key() -> Token<'input>
+    pub rule key() -> Expr
         =  identifier() / index() / range() / identity()
 
 
-    /// A filter is a path of Keys
-    pub rule filter() -> Action<'input>
-        = _ k:key()+ _ {Action::Filter(k)}
+    /// A filter is a path of Keys.  The path must start with `.`, so that
+    /// `[1]` is an array rather than an index.
+    pub rule filter() -> Expr
+        = _ f:(identifier() / identity()) k:key()* _ {
+            let mut keys = vec![f];
+            keys.extend(k);
+            Expr::path(keys)
+        }
 
-    pub rule length() -> Action<'input>
-        = _ "length" _ {Action::Function(Function::Length)}
+    pub rule length() -> Expr
+        = _ "length" !ident_char() _ {Expr::Function(Function::Length)}
 
-    pub rule has() -> Action<'input>
+    pub rule has() -> Expr
         = precedence!{
-           _ "has(" _ ident:string() _ ")" _ { Action::Function(Function::Has(HasType::from(ident)))}
+           _ "has(" _ ident:string() _ ")" _ { Expr::Function(Function::Has(HasType::from(ident)))}
             --
-           _ "has(" _ index:number() _ ")" _ { Action::Function(Function::Has(HasType::from(index)))}
+           _ "has(" _ index:number() _ ")" _ { Expr::Function(Function::Has(HasType::from(index)))}
         }
 
-    pub rule recurse() -> Action<'input>
+    pub rule recurse() -> Expr
         = precedence!{
-             _ ".." _ {Action::Function(Function::Recurse)}
+             _ ".." _ {Expr::Function(Function::Recurse)}
             --
-            _ "recurse" _ {Action::Function(Function::Recurse)}
+            _ "recurse" !ident_char() !(_ "(") _ {Expr::Function(Function::Recurse)}
         }
 
-    pub rule keys() -> Action<'input>
-    = _ "keys" f:"_unsorted"?_ { Action::Function(Function::Keys(f.is_none()))}
+    pub rule keys() -> Expr
+    = _ "keys" f:"_unsorted"? !ident_char() _ { Expr::Function(Function::Keys(f.is_none()))}
 
 
-    pub rule function() -> Action<'input>
-    = length() / has() / recurse() / keys()
+    /// `;` separated filter arguments
+    pub rule args() -> Vec<Expr>
+    = "(" _ a:(pipe() ++ (_ ";" _)) _ ")" {a}
 
-    pub rule action() -> Action<'input>
-        = filter() / function()
+    /// A function called by name, with optional filter arguments, ie: `map(.a)`.
+    /// Names that are not builtins are user defined functions, which are
+    /// resolved after parsing.
+    pub rule call() -> Expr
+    = _ name:qualified_name() _ a:args()? _ {
+        let args = a.unwrap_or_default();
+        match Function::from_call(name, args.clone()) {
+            Ok(function) => Expr::Function(function),
+            Err(_) => Expr::Call(name.to_string(), args),
+        }
+    }
 
-    pub rule actions() -> Vec<Action<'input>>
-    =  action() ++  ","
+    /// A parameter of a function definition: `f` or `$x`
+    pub rule param() -> Param
+        = n:variable_name() { Param::Variable(n) }
+        / n:function_name() { Param::Filter(n.to_string()) }
 
-    pub rule collect() -> Block<'input>
-        = _ "[" _ a:actions() _ "]" _ {
-            Block{actions: Some(a), collect: true}
+    /// `def name(params): body;`
+    pub rule definition() -> Definition
+        = "def" !ident_char() _ name:function_name() _
+          p:("(" _ p:(param() ++ (_ ";" _)) _ ")" {p})? _ ":" _ body:pipe() _ ";" {
+            Definition::new(name, p.unwrap_or_default(), body)
         }
 
-    /// A block is either a set of filters or a command
-    pub rule block() -> Block<'input>
-        = precedence! {
-            _ actions:actions() _ {Block{actions: Some(actions),collect: false}}
-        --
-            collect: collect() {collect}
+    pub rule function() -> Expr
+    = length() / has() / recurse() / keys() / call()
+
+    /// An object key is an identifier, a string, or a computed `(filter)`
+    pub rule object_key() -> Expr
+        = k:$(['a'..='z' | 'A'..='Z' | '_'] ident_char()*) { Expr::Literal(Value::from(k)) }
+        / k:string_literal() { Expr::Literal(k) }
+        / "(" _ p:pipe() _ ")" { p }
+
+    /// An object value is a pipe of operations, but may not contain `,`
+    pub rule object_value() -> Expr
+        = a:operation() ++ (_ "|" _) {
+            a.into_iter()
+                .rev()
+                .reduce(|rhs, lhs| Expr::Pipe(Box::new(lhs), Box::new(rhs)))
+                .unwrap()
+        }
+
+    /// `key: value`, or the shorthand `key`, which is the same as `key: .key`
+    pub rule object_entry() -> ObjectEntry
+        = k:object_key() _ ":" _ v:object_value() { (k, v) }
+        / k:$(['a'..='z' | 'A'..='Z' | '_'] ident_char()*) {
+            (Expr::Literal(Value::from(k)), Expr::Ident(k.to_string(), false))
+        }
+        / k:string() {
+            (Expr::Literal(Value::from(k)), Expr::Ident(k.to_string(), false))
+        }
+        / n:variable_name() {
+            (Expr::Literal(Value::from(n.as_str())), Expr::Variable(n))
+        }
+
+    /// Array construction: `[filter]` collects every result of the filter
+    pub rule array() -> Expr
+        = _ "[" _ "]" _ { Expr::Literal(Value::from(Vec::<Value>::new())) }
+        / _ "[" _ p:pipe() _ "]" _ { Expr::Array(Box::new(p)) }
+
+    /// Object construction: `{a: .x, "b": .y, (.k): .v, c}`.  As in jq, the
+    /// last entry may be followed by a `,`.
+    pub rule object() -> Expr
+        = _ "{" _ e:(object_entry() ++ (_ "," _)) _ ","? _ "}" _ { Expr::Object(e) }
+        / _ "{" _ "}" _ { Expr::Object(Vec::new()) }
+
+    /// `if cond then a elif cond2 then b else c end`.  `elif` and `else` are optional.
+    pub rule conditional() -> Expr
+        = _ "if" !ident_char() _ c:pipe() _ "then" !ident_char() _ t:pipe() _ e:conditional_else() _ {
+            Expr::If(Box::new(c), Box::new(t), Box::new(e))
+        }
+
+    /// The rest of a conditional after `then`.  Without `else`, the input is passed through.
+    rule conditional_else() -> Expr
+        = "elif" !ident_char() _ c:pipe() _ "then" !ident_char() _ t:pipe() _ e:conditional_else() {
+            Expr::If(Box::new(c), Box::new(t), Box::new(e))
+        }
+        / "else" !ident_char() _ e:pipe() _ "end" !ident_char() { e }
+        / "end" !ident_char() { Expr::Identity }
+
+    /// `try body catch handler`, or `try body`.  Both are single terms, so
+    /// `try .a | .b` is `(try .a) | .b`.
+    pub rule try_catch() -> Expr
+        = _ "try" !ident_char() _ b:term() _ h:("catch" !ident_char() _ h:term() {h})? _ {
+            Expr::Try(Box::new(b), h.map(Box::new))
         }
 
-    pub rule blocks() -> Vec<Block<'input>>
-        = block() ** "|"
+    /// `reduce source as $name (init; update)`.  The source is a single term.
+    pub rule reduce() -> Expr
+        = _ "reduce" !ident_char() _ s:term() _ "as" !ident_char() _ p:(pattern() ++ (_ "?//" _)) _
+          "(" _ i:pipe() _ ";" _ u:pipe() _ ")" _ {
+            Expr::Reduce(Box::new(s), p, Box::new(i), Box::new(u))
+        }
+
+    /// `foreach source as $name (init; update; extract)`, where `; extract` is optional
+    pub rule foreach() -> Expr
+        = _ "foreach" !ident_char() _ s:term() _ "as" !ident_char() _ p:(pattern() ++ (_ "?//" _)) _
+          "(" _ i:pipe() _ ";" _ u:pipe() _ e:(";" _ e:pipe() _ {e})? ")" _ {
+            Expr::Foreach(Box::new(s), p, Box::new(i), Box::new(u), e.map(Box::new))
+        }
+
+    /// `label $name | body`.  The body extends as far right as possible.
+    pub rule label() -> Expr
+        = _ "label" !ident_char() _ n:variable_name() _ "|" _ body:pipe() {
+            Expr::Label(n, Box::new(body))
+        }
+
+    /// `break $name`
+    pub rule break_label() -> Expr
+        = _ "break" !ident_char() _ n:variable_name() _ { Expr::Break(n) }
+
+    /// A parenthesized sub-expression
+    pub rule group() -> Expr
+        = _ "(" _ p:pipe() _ ")" _ { p }
+
+    /// Fails once the query is nested too deeply to parse
+    rule nesting() = {? if within_parse_stack() { Ok(()) } else { Err("shallower nesting") } }
+
+    /// A single operand.  Every nested expression is parsed as an operand.
+    pub rule primary() -> Expr
+        = nesting() p:(filter() / conditional() / try_catch() / reduce() / foreach() / label()
+            / break_label() / function() / literal() / variable() / array() / object() / group()) { p }
+
+    /// An operand, optionally followed by a path, such as `(.a, .b)[0]`.  A
+    /// postfix `?` drops the errors of everything before it, ie: `.a[0]?.b`.
+    pub rule term() -> Expr
+        = t:primary() p:(k:(identifier() / index() / range()) { Some(k) } / "?" !"//" _ { None })* _ {
+            let mut term = t;
+            let mut keys = Vec::new();
+            for postfix in p {
+                match postfix {
+                    Some(key) => keys.push(key),
+                    None => {
+                        term = with_path(term, std::mem::take(&mut keys));
+                        term = Expr::Try(Box::new(term), None);
+                    }
+                }
+            }
+            with_path(term, keys)
+        }
+
+    /// A destructuring pattern: `$name`, `[$a, $b]` or `{a: $a, $b, (.k): [$c]}`
+    pub rule pattern() -> Pattern
+        = nesting() p:(
+            n:variable_name() { Pattern::Variable(n) }
+            / "[" _ p:(pattern() ++ (_ "," _)) _ "]" { Pattern::Array(p) }
+            / "{" _ e:(pattern_entry() ++ (_ "," _)) _ "}" { Pattern::Object(e.concat()) }
+        ) { p }
+
+    /// `$name: pattern` binds `$name` to `.name` and also destructures it
+    rule pattern_entry() -> Vec<(Expr, Pattern)>
+        = n:variable_name() _ ":" _ p:pattern() {
+            let key = Expr::Literal(Value::from(n.as_str()));
+            vec![(key.clone(), Pattern::Variable(n)), (key, p)]
+        }
+        / n:variable_name() {
+            vec![(Expr::Literal(Value::from(n.as_str())), Pattern::Variable(n))]
+        }
+        / k:object_key() _ ":" _ p:pattern() { vec![(k, p)] }
+
+    /// The `as patterns | body` following a term.  Alternative patterns are
+    /// separated by `?//`.  The body extends as far right as possible.
+    pub rule binding() -> (Vec<Pattern>, Expr)
+        = "as" !ident_char() _ p:(pattern() ++ (_ "?//" _)) _ "|" _ body:pipe() { (p, body) }
+
+    /// Terms combined with operators.  From lowest to highest precedence:
+    /// `//`, `or`, `and`, comparisons, `+ -`, `* / %`, and a prefix `-`.  `//`
+    /// is right associative.  A `-` that starts a number is part of the literal.
+    pub rule operation() -> Expr
+        = precedence!{
+            x:@ _ "//" _ y:(@) { Expr::Alternative(Box::new(x), Box::new(y)) }
+            --
+            x:(@) _ "or" !ident_char() _ y:@ { Expr::Or(Box::new(x), Box::new(y)) }
+            --
+            x:(@) _ "and" !ident_char() _ y:@ { Expr::And(Box::new(x), Box::new(y)) }
+            --
+            x:(@) _ "==" _ y:@ { chain_operation(x, Operator::Equal, y) }
+            x:(@) _ "!=" _ y:@ { chain_operation(x, Operator::NotEqual, y) }
+            x:(@) _ "<=" _ y:@ { chain_operation(x, Operator::LessEqual, y) }
+            x:(@) _ "<" _ y:@ { chain_operation(x, Operator::Less, y) }
+            x:(@) _ ">=" _ y:@ { chain_operation(x, Operator::GreaterEqual, y) }
+            x:(@) _ ">" _ y:@ { chain_operation(x, Operator::Greater, y) }
+            --
+            x:(@) _ "+" _ y:@ { chain_operation(x, Operator::Add, y) }
+            x:(@) _ "-" _ y:@ { chain_operation(x, Operator::Subtract, y) }
+            --
+            x:(@) _ "*" _ y:@ { chain_operation(x, Operator::Multiply, y) }
+            x:(@) _ "/" !"/" _ y:@ { chain_operation(x, Operator::Divide, y) }
+            x:(@) _ "%" _ y:@ { chain_operation(x, Operator::Modulo, y) }
+            --
+            _ "-" !['0'..='9'] _ x:@ { negate(x) }
+            --
+            t:term() b:binding()? {
+                match b {
+                    Some((patterns, body)) => Expr::Bind(Box::new(t), patterns, Box::new(body)),
+                    None => t,
+                }
+            }
+        }
+
+    /// Operations separated by `,`
+    pub rule comma() -> Expr
+        = a:operation() ++ (_ "," _) {
+            if a.len() == 1 { a.into_iter().next().unwrap() } else { Expr::Comma(a) }
+        }
+
+    /// `|` has the lowest precedence, and is right associative.  Function
+    /// definitions come first, and are visible in the rest of the pipe.
+    pub rule pipe() -> Expr
+        = _ d:definition() _ rest:pipe() { Expr::Define(Box::new(d), Box::new(rest)) }
+        / lhs:comma() rhs:(_ "|" _ p:pipe() {p})? {
+            match rhs {
+                Some(rhs) => Expr::Pipe(Box::new(lhs), Box::new(rhs)),
+                None => lhs,
+            }
+        }
+
+    /// A complete query.  An empty query is the same as `.`
+    pub rule query() -> Expr
+        = _ p:pipe()? _ { p.unwrap_or(Expr::Identity) }
+
+    /// Constant metadata following a directive, ie: `{search: "./lib"}`
+    rule metadata() -> Map<String, Value>
+        = o:object() {? constant_object(&o) }
+
+    /// The path of a module, as a string
+    rule module_path() -> String
+        = p:string_literal() {? p.as_str().map(str::to_string).ok_or("module path") }
+
+    /// `import "path" as name;`, `import "path" as $name;` or `include "path";`
+    pub rule import() -> Import
+        = "import" !ident_char() _ p:module_path() _ "as" !ident_char() _ "$" n:function_name() _
+          m:metadata()? _ ";" {
+            Import::data(&p, n, m.unwrap_or_default())
+        }
+        / "import" !ident_char() _ p:module_path() _ "as" !ident_char() _ n:function_name() _
+          m:metadata()? _ ";" {
+            Import::functions(&p, n, m.unwrap_or_default())
+        }
+        / "include" !ident_char() _ p:module_path() _ m:metadata()? _ ";" {
+            Import::include(&p, m.unwrap_or_default())
+        }
+
+    /// A module file: an optional `module {...};` directive, then `import`
+    /// and `include` directives, then function definitions
+    pub rule module() -> Module
+        = _ m:("module" !ident_char() _ m:metadata() _ ";" {m})? _ i:(import() ** _) _
+          d:(definition() ** _) _ {
+            Module::new(m.unwrap_or_default(), i, d)
+        }
+
+    /// A query, which may start with `import` and `include` directives
+    pub rule program() -> (Vec<Import>, Expr)
+        = _ i:(import() ** _) q:query() { (i, q) }
 });
 
 /// Parse query string
 /// This ues the PEG grammer based `query_parser` above.  Since `query_parser` is not
 /// callable outside the module, this method is provided for public access.
-pub fn parse(input: &str) -> Result<Vec<Block>, JQError> {
-    query_parser::blocks(input).map_err(|_| JQError::ParseError)
+pub fn parse(input: &str) -> Result<Expr, JQError> {
+    parse_with_vars(input, &[])
+}
+
+/// Parse a query string that may use the variables in `vars`, as well as the
+/// [PREDEFINED](crate::variables::PREDEFINED) variables.  Any other variable is [JQError::UndefinedVariable],
+/// and a call to a function that is not defined is [JQError::UndefinedFunction].
+pub fn parse_with_vars(input: &str, vars: &[&str]) -> Result<Expr, JQError> {
+    let options = vars.iter().fold(CompileOptions::new(), |options, name| {
+        options.with_var(name)
+    });
+    parse_with_options(input, &options)
+}
+
+/// Parse a query string that may use the variables and native functions of
+/// `options`.  The modules named by `import` and `include` directives are
+/// loaded from its search path.
+pub fn parse_with_options(input: &str, options: &CompileOptions) -> Result<Expr, JQError> {
+    let (imports, query) =
+        guarded(|| query_parser::program(input)).map_err(|_| JQError::ParseError)?;
+    let mut expr = options.loader().link(&imports, query)?;
+    expr.resolve(options)?;
+    Ok(expr)
+}
+
+/// Parse a module file, which may only contain directives and definitions
+pub fn parse_module(input: &str) -> Result<Module, JQError> {
+    guarded(|| query_parser::module(input)).map_err(|_| JQError::ParseError)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn lit_box(n: i64) -> Box<Expr> {
+        Box::new(Expr::Literal(Value::from(n)))
+    }
+
     #[test]
     fn test_recurse() {
-        let query = parse(r#".[] | .."#).expect("Failed");
-        dbg!(&query);
+        assert_eq!(
+            query_parser::query(r#".[] | .."#),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Range(RangeType::new())),
+                Box::new(Expr::Function(Function::Recurse))
+            ))
+        );
+        assert_eq!(
+            query_parser::query(r#"recurse(.a), recurse_all"#),
+            Ok(Expr::Comma(vec![
+                Expr::Function(Function::RecurseWith(Box::new(Expr::Ident(
+                    "a".to_string(),
+                    false
+                )))),
+                Expr::Call("recurse_all".to_string(), Vec::new())
+            ]))
+        );
     }
 
     #[test]
     fn test_identity_to_array() {
-        let query = query_parser::block(".[]");
-        dbg!(&query);
+        assert_eq!(
+            query_parser::comma(".[]"),
+            Ok(Expr::Range(RangeType::new()))
+        );
     }
 
     #[test]
-    fn test_blocks() {
+    fn test_pipe() {
+        let comma = || {
+            Box::new(Expr::Comma(vec![
+                Expr::Identity,
+                Expr::Ident("b".to_string(), false),
+            ]))
+        };
         assert_eq!(
-            query_parser::blocks(r#" . , .b | ., .b"#),
-            Ok(vec![
-                Block {
-                    actions: Some(vec![
-                        Action::Filter(vec![Token::Identity,],),
-                        Action::Filter(vec![Token::Ident("b", false),],),
-                    ],),
-                    collect: false,
-                },
-                Block {
-                    actions: Some(vec![
-                        Action::Filter(vec![Token::Identity,],),
-                        Action::Filter(vec![Token::Ident("b", false),],),
-                    ],),
-                    collect: false
-                },
-            ])
+            query_parser::pipe(r#" . , .b | ., .b"#),
+            Ok(Expr::Pipe(comma(), comma()))
+        );
+    }
+
+    #[test]
+    fn test_pipe_right_associative() {
+        let a = || Box::new(Expr::Ident("a".to_string(), false));
+        assert_eq!(
+            query_parser::pipe(".a | .a | .a"),
+            Ok(Expr::Pipe(a(), Box::new(Expr::Pipe(a(), a()))))
+        );
+    }
+
+    #[test]
+    fn test_empty_query() {
+        assert_eq!(query_parser::query(""), Ok(Expr::Identity));
+        assert_eq!(query_parser::query("  "), Ok(Expr::Identity));
+    }
+
+    #[test]
+    fn test_comma_filter_and_function() {
+        assert_eq!(
+            query_parser::comma("., length "),
+            Ok(Expr::Comma(vec![
+                Expr::Identity,
+                Expr::Function(Function::Length)
+            ]))
         );
     }
 
     #[test]
-    fn test_block_filter_and_function() {
+    fn test_comma_1_function() {
         assert_eq!(
-            query_parser::block("., length "),
-            Ok(Block {
-                actions: Some(vec![
-                    Action::Filter(vec![Token::Identity]),
-                    Action::Function(Function::Length)
-                ]),
-                collect: false
-            })
+            query_parser::comma(" length "),
+            Ok(Expr::Function(Function::Length))
         );
     }
 
     #[test]
-    fn test_block_1_function() {
+    fn test_comma_2_filters() {
         assert_eq!(
-            query_parser::block(" length "),
-            Ok(Block {
-                actions: Some(vec![Action::Function(Function::Length)]),
-                collect: false,
-            })
+            query_parser::comma(". , .b"),
+            Ok(Expr::Comma(vec![
+                Expr::Identity,
+                Expr::Ident("b".to_string(), false)
+            ]))
         );
     }
 
     #[test]
-    fn test_block_2_filters() {
+    fn test_collect() {
+        let collect = parse("[.[] | .a]").expect("fail");
         assert_eq!(
-            query_parser::block(". , .b"),
-            Ok(Block {
-                actions: Some(vec![
-                    Action::Filter(vec![Token::Identity]),
-                    Action::Filter(vec![Token::Ident("b", false)])
-                ]),
-                collect: false
-            },)
+            collect,
+            Expr::Array(Box::new(Expr::Pipe(
+                Box::new(Expr::Range(RangeType::new())),
+                Box::new(Expr::Ident("a".to_string(), false))
+            )))
         );
     }
 
     #[test]
-    fn test_block_collect() {
-        //let collect = query_parser::collect("[ .[] ]");
-        let collect = parse("[.[]]").expect("fail");
-        dbg!(&collect);
+    fn test_array() {
+        assert_eq!(
+            query_parser::array("[]"),
+            Ok(Expr::Literal(Value::from(Vec::<Value>::new())))
+        );
+        assert_eq!(
+            query_parser::array("[1, 2]"),
+            Ok(Expr::Array(Box::new(Expr::Comma(vec![
+                Expr::Literal(Value::from(1)),
+                Expr::Literal(Value::from(2))
+            ]))))
+        );
+        assert_eq!(
+            query_parser::object("{ids: [.[]]}"),
+            Ok(Expr::Object(vec![(
+                Expr::Literal(Value::from("ids")),
+                Expr::Array(Box::new(Expr::Range(RangeType::new())))
+            )]))
+        );
+        assert!(query_parser::filter("[1]").is_err());
     }
 
     #[test]
-    fn test_block_1_filter() {
+    fn test_comma_1_filter() {
         assert_eq!(
-            query_parser::block(".b"),
-            Ok(Block {
-                actions: Some(vec![Action::Filter(vec![Token::Ident("b", false)])]),
-                collect: false
-            })
+            query_parser::comma(".b"),
+            Ok(Expr::Ident("b".to_string(), false))
         );
     }
 
@@ -220,11 +646,11 @@ mod tests {
     fn test_function_has() {
         assert_eq!(
             query_parser::has(r#" has("some_path")"#),
-            Ok(Action::Function(Function::Has(HasType::from("some_path"))))
+            Ok(Expr::Function(Function::Has(HasType::from("some_path"))))
         );
         assert_eq!(
             query_parser::has(r#" has(2)"#),
-            Ok(Action::Function(Function::Has(HasType::from(2))))
+            Ok(Expr::Function(Function::Has(HasType::from(2))))
         );
     }
 
@@ -232,79 +658,78 @@ mod tests {
     fn test_function_length() {
         assert_eq!(
             query_parser::length(" length "),
-            Ok(Action::Function(Function::Length))
+            Ok(Expr::Function(Function::Length))
         );
     }
 
     #[test]
     fn test_filter() {
-        assert_eq!(
-            query_parser::filter("."),
-            Ok(Action::Filter(vec![Token::Identity]))
-        );
+        assert_eq!(query_parser::filter("."), Ok(Expr::Identity));
 
         assert_eq!(
             query_parser::filter(".a"),
-            Ok(Action::Filter(vec![Token::Ident("a", false)]))
+            Ok(Expr::Ident("a".to_string(), false))
         );
 
         assert_eq!(
             query_parser::filter(".[]"),
-            Ok(Action::Filter(vec![
-                Token::Identity,
-                Token::Range(RangeType::new())
-            ]))
+            Ok(Expr::Range(RangeType::new()))
         );
 
         assert_eq!(
             query_parser::filter(".a.b"),
-            Ok(Action::Filter(vec![
-                Token::Ident("a", false),
-                Token::Ident("b", false)
+            Ok(Expr::path(vec![
+                Expr::Ident("a".to_string(), false),
+                Expr::Ident("b".to_string(), false)
             ]))
         );
 
         assert_eq!(
             query_parser::filter(r#"."a".b"#),
-            Ok(Action::Filter(vec![
-                Token::Ident("a", false),
-                Token::Ident("b", false)
+            Ok(Expr::path(vec![
+                Expr::Ident("a".to_string(), false),
+                Expr::Ident("b".to_string(), false)
             ]))
         );
 
         assert_eq!(
             query_parser::filter(r#".["a"].b"#),
-            Ok(Action::Filter(vec![
-                Token::Identity,
-                Token::Index(IndexType::from(("a", false))),
-                Token::Ident("b", false)
+            Ok(Expr::path(vec![
+                Expr::Index(IndexType::from(("a", false))),
+                Expr::Ident("b".to_string(), false)
             ]))
         );
     }
 
     #[test]
     fn test_key() {
-        assert_eq!(query_parser::key("."), Ok(Token::Identity));
+        assert_eq!(query_parser::key("."), Ok(Expr::Identity));
 
-        assert_eq!(query_parser::key(".a"), Ok(Token::Ident("a", false)));
+        assert_eq!(
+            query_parser::key(".a"),
+            Ok(Expr::Ident("a".to_string(), false))
+        );
 
-        assert_eq!(query_parser::key(r#"."a""#), Ok(Token::Ident("a", false)));
+        assert_eq!(
+            query_parser::key(r#"."a""#),
+            Ok(Expr::Ident("a".to_string(), false))
+        );
 
         assert_eq!(
             query_parser::key(r#"["a"]"#),
-            Ok(Token::Index(IndexType::from(("a", false))))
+            Ok(Expr::Index(IndexType::from(("a", false))))
         );
 
         assert_eq!(
             query_parser::key(r#"[2]"#),
-            Ok(Token::Index(IndexType::from((2, false))))
+            Ok(Expr::Index(IndexType::from((2, false))))
         );
 
-        assert_eq!(query_parser::key("[]"), Ok(Token::Range(RangeType::new())));
+        assert_eq!(query_parser::key("[]"), Ok(Expr::Range(RangeType::new())));
 
         assert_eq!(
             query_parser::key(r#"[1:2]"#),
-            Ok(Token::Range(RangeType::from_both(1, 2)))
+            Ok(Expr::Range(RangeType::from_both(1, 2)))
         );
     }
 
@@ -318,12 +743,12 @@ mod tests {
     fn test_identifier_index() {
         assert_eq!(
             query_parser::index(r#"["a"]"#),
-            Ok(Token::Index(IndexType::from(("a", false))))
+            Ok(Expr::Index(IndexType::from(("a", false))))
         );
 
         assert_eq!(
             query_parser::index(r#"[ "a" ]"#),
-            Ok(Token::Index(IndexType::from(("a", false))))
+            Ok(Expr::Index(IndexType::from(("a", false))))
         );
     }
 
@@ -331,17 +756,17 @@ mod tests {
     fn test_index_index() {
         assert_eq!(
             query_parser::index("[2]"),
-            Ok(Token::Index(IndexType::from((2, false))))
+            Ok(Expr::Index(IndexType::from((2, false))))
         );
 
         assert_eq!(
             query_parser::index("[ 2]"),
-            Ok(Token::Index(IndexType::from((2, false))))
+            Ok(Expr::Index(IndexType::from((2, false))))
         );
 
         assert_eq!(
             query_parser::index("[2 ]"),
-            Ok(Token::Index(IndexType::from((2, false))))
+            Ok(Expr::Index(IndexType::from((2, false))))
         );
     }
 
@@ -349,7 +774,7 @@ mod tests {
     fn test_negative_index() {
         assert_eq!(
             query_parser::index("[-2]"),
-            Ok(Token::Index(IndexType::from((-2, false))))
+            Ok(Expr::Index(IndexType::from((-2, false))))
         );
     }
 
@@ -362,17 +787,17 @@ mod tests {
     fn test_range_start_only() {
         assert_eq!(
             query_parser::range("[1:]"),
-            Ok(Token::Range(RangeType::from_start(1)))
+            Ok(Expr::Range(RangeType::from_start(1)))
         );
 
         assert_eq!(
             query_parser::range("[1 :]"),
-            Ok(Token::Range(RangeType::from_start(1)))
+            Ok(Expr::Range(RangeType::from_start(1)))
         );
 
         assert_eq!(
             query_parser::range("[-1:]"),
-            Ok(Token::Range(RangeType::from_start(-1)))
+            Ok(Expr::Range(RangeType::from_start(-1)))
         );
     }
 
@@ -380,12 +805,12 @@ mod tests {
     fn test_range_end_only() {
         assert_eq!(
             query_parser::range("[:1]"),
-            Ok(Token::Range(RangeType::from_end(1)))
+            Ok(Expr::Range(RangeType::from_end(1)))
         );
 
         assert_eq!(
             query_parser::range("[: 1]"),
-            Ok(Token::Range(RangeType::from_end(1)))
+            Ok(Expr::Range(RangeType::from_end(1)))
         );
     }
 
@@ -393,22 +818,22 @@ mod tests {
     fn test_range_start_end() {
         assert_eq!(
             query_parser::range("[1:2]"),
-            Ok(Token::Range(RangeType::from_both(1, 2)))
+            Ok(Expr::Range(RangeType::from_both(1, 2)))
         );
 
         assert_eq!(
             query_parser::range("[1 : 2]"),
-            Ok(Token::Range(RangeType::from_both(1, 2)))
+            Ok(Expr::Range(RangeType::from_both(1, 2)))
         );
 
         assert_eq!(
             query_parser::range("[ 1 : 2 ]"),
-            Ok(Token::Range(RangeType::from_both(1, 2)))
+            Ok(Expr::Range(RangeType::from_both(1, 2)))
         );
 
         assert_eq!(
             query_parser::range("[ 1 : 2 ]"),
-            Ok(Token::Range(RangeType::from_both(1, 2)))
+            Ok(Expr::Range(RangeType::from_both(1, 2)))
         );
     }
 
@@ -416,12 +841,12 @@ mod tests {
     fn test_identifier() {
         assert_eq!(
             query_parser::identifier(".Ab_1c"),
-            Ok(Token::Ident("Ab_1c", false))
+            Ok(Expr::Ident("Ab_1c".to_string(), false))
         );
 
         assert_eq!(
             query_parser::identifier(r#"."Ab 1c""#),
-            Ok(Token::Ident("Ab 1c", false))
+            Ok(Expr::Ident("Ab 1c".to_string(), false))
         );
     }
 
@@ -429,7 +854,7 @@ mod tests {
     fn test_ident() {
         assert_eq!(
             query_parser::ident("Ab_1c"),
-            Ok(Token::Ident("Ab_1c", false))
+            Ok(Expr::Ident("Ab_1c".to_string(), false))
         );
         assert!(query_parser::ident("1Ab_1c").is_err());
     }
@@ -441,6 +866,661 @@ mod tests {
         assert_eq!(query_parser::string(r#"" a 1_bc ""#), Ok(" a 1_bc "));
     }
 
+    #[test]
+    fn test_literal() {
+        assert_eq!(
+            query_parser::literal(r#" "fallback" "#),
+            Ok(Expr::Literal(Value::from("fallback")))
+        );
+        assert_eq!(
+            query_parser::literal(r#""a \"quoted\" \u00e9""#),
+            Ok(Expr::Literal(Value::from("a \"quoted\" \u{e9}")))
+        );
+        assert_eq!(
+            query_parser::literal("42"),
+            Ok(Expr::Literal(Value::from(42)))
+        );
+        assert_eq!(
+            query_parser::literal("-1.5e2"),
+            Ok(Expr::Literal(Value::from(-150.0)))
+        );
+        assert_eq!(
+            query_parser::literal("true"),
+            Ok(Expr::Literal(Value::Bool(true)))
+        );
+        assert_eq!(
+            query_parser::literal("null"),
+            Ok(Expr::Literal(Value::Null))
+        );
+        assert!(query_parser::literal("nullable").is_err());
+    }
+
+    #[test]
+    fn test_comma_literals() {
+        assert_eq!(
+            query_parser::comma(r#".a, "sep", .b"#),
+            Ok(Expr::Comma(vec![
+                Expr::Ident("a".to_string(), false),
+                Expr::Literal(Value::from("sep")),
+                Expr::Ident("b".to_string(), false)
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_precedence() {
+        let a = || Box::new(Expr::Ident("a".to_string(), false));
+        let lit = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            query_parser::operation(".a + 2 * 3"),
+            Ok(Expr::Operation(
+                a(),
+                vec![(
+                    Operator::Add,
+                    Expr::Operation(lit(2), vec![(Operator::Multiply, *lit(3))])
+                )]
+            ))
+        );
+        assert_eq!(
+            query_parser::operation(".a - 1 - 2"),
+            Ok(Expr::Operation(
+                a(),
+                vec![(Operator::Subtract, *lit(1)), (Operator::Subtract, *lit(2))]
+            ))
+        );
+        assert_eq!(
+            query_parser::operation("6 % 4 / .a"),
+            Ok(Expr::Operation(
+                lit(6),
+                vec![(Operator::Modulo, *lit(4)), (Operator::Divide, *a())]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_alternative_precedence() {
+        let a = || Box::new(Expr::Ident("a".to_string(), false));
+        let lit = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            query_parser::operation(".a // 1 // .a or 2"),
+            Ok(Expr::Alternative(
+                a(),
+                Box::new(Expr::Alternative(lit(1), Box::new(Expr::Or(a(), lit(2)))))
+            ))
+        );
+        assert_eq!(
+            query_parser::operation(".a / 2 // 1"),
+            Ok(Expr::Alternative(
+                Box::new(Expr::Operation(a(), vec![(Operator::Divide, *lit(2))])),
+                lit(1)
+            ))
+        );
+        assert_eq!(
+            query_parser::comma(".a // 1, 2"),
+            Ok(Expr::Comma(vec![Expr::Alternative(a(), lit(1)), *lit(2)]))
+        );
+    }
+
+    #[test]
+    fn test_comparison_precedence() {
+        let a = || Box::new(Expr::Ident("a".to_string(), false));
+        let lit = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            query_parser::operation(".a + 1 <= 2 and .a or .a"),
+            Ok(Expr::Or(
+                Box::new(Expr::And(
+                    Box::new(Expr::Operation(
+                        a(),
+                        vec![(Operator::Add, *lit(1)), (Operator::LessEqual, *lit(2))]
+                    )),
+                    a()
+                )),
+                a()
+            ))
+        );
+        assert_eq!(
+            query_parser::pipe(".a | not"),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Ident("a".to_string(), false)),
+                Box::new(Expr::Function(Function::Not))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_function_select() {
+        assert_eq!(
+            query_parser::call(r#" select(.a | not) "#),
+            Ok(Expr::Function(Function::Select(Box::new(Expr::Pipe(
+                Box::new(Expr::Ident("a".to_string(), false)),
+                Box::new(Expr::Function(Function::Not))
+            )))))
+        );
+    }
+
+    #[test]
+    fn test_function_args() {
+        assert!(matches!(
+            parse("map(.a; .b)"),
+            Err(JQError::UndefinedFunction(name)) if name == "map/2"
+        ));
+        assert_eq!(
+            query_parser::args("(.a; 1 | .b)"),
+            Ok(vec![
+                Expr::Ident("a".to_string(), false),
+                Expr::Pipe(
+                    Box::new(Expr::Literal(Value::from(1))),
+                    Box::new(Expr::Ident("b".to_string(), false))
+                )
+            ])
+        );
+        assert_eq!(
+            query_parser::call("map_values(.a)"),
+            Ok(Expr::Function(Function::MapValues(Box::new(Expr::Ident(
+                "a".to_string(),
+                false
+            )))))
+        );
+        assert_eq!(
+            query_parser::call("unknown"),
+            Ok(Expr::Call("unknown".to_string(), Vec::new()))
+        );
+        assert!(matches!(
+            parse("unknown"),
+            Err(JQError::UndefinedFunction(_))
+        ));
+    }
+
+    #[test]
+    fn test_definition() {
+        assert_eq!(
+            query_parser::definition("def f: .a;"),
+            Ok(Definition::new(
+                "f",
+                Vec::new(),
+                Expr::Ident("a".to_string(), false)
+            ))
+        );
+        assert_eq!(
+            query_parser::definition("def f(g; $x): g | $x;"),
+            Ok(Definition::new(
+                "f",
+                vec![
+                    Param::Filter("g".to_string()),
+                    Param::Variable("x".to_string())
+                ],
+                Expr::Pipe(
+                    Box::new(Expr::Call("g".to_string(), Vec::new())),
+                    Box::new(Expr::Variable("x".to_string()))
+                )
+            ))
+        );
+        assert!(query_parser::definition("def and: 1;").is_err());
+        assert!(query_parser::definition("def f: 1").is_err());
+    }
+
+    #[test]
+    fn test_definition_scope() {
+        assert_eq!(
+            query_parser::query("def f: 1; f, 2"),
+            Ok(Expr::Define(
+                Box::new(Definition::new(
+                    "f",
+                    Vec::new(),
+                    Expr::Literal(Value::from(1))
+                )),
+                Box::new(Expr::Comma(vec![
+                    Expr::Call("f".to_string(), Vec::new()),
+                    *lit_box(2)
+                ]))
+            ))
+        );
+        // Functions are visible in their own body, and after the definition
+        assert!(parse("def f: f; f").is_ok());
+        assert!(parse("(def f: 1; f), f").is_err());
+        assert!(parse("def f(g): g; g").is_err());
+        assert!(parse("def f($x): x, $x; f(1)").is_ok());
+        assert!(parse("def f($x): 1; $x").is_err());
+        // Functions are identified by name and arity
+        assert!(parse("def f(g): g; f").is_err());
+    }
+
+    #[test]
+    fn test_shadow_builtin() {
+        let mut expr = query_parser::query("def map(f): 1; map(.)").expect("failed to parse");
+        assert!(matches!(&expr, Expr::Define(_, rest) if rest.is_function()));
+        expr.resolve(&CompileOptions::new())
+            .expect("failed to resolve");
+        assert!(matches!(&expr, Expr::Define(_, rest) if rest.is_call()));
+
+        // A different arity does not shadow the builtin
+        let expr = parse("def map: 1; map(.)").expect("failed to parse");
+        assert!(matches!(&expr, Expr::Define(_, rest) if rest.is_function()));
+    }
+
+    #[test]
+    fn test_import() {
+        assert_eq!(
+            query_parser::import(r#"import "a/b" as b;"#),
+            Ok(Import::functions("a/b", "b", Map::new()))
+        );
+        assert_eq!(
+            query_parser::import(r#"import "data" as $d;"#),
+            Ok(Import::data("data", "d", Map::new()))
+        );
+        let meta = serde_json::json!({"search": "./lib"});
+        assert_eq!(
+            query_parser::import(r#"include "c" {search: "./lib"};"#),
+            Ok(Import::include("c", meta.as_object().cloned().unwrap()))
+        );
+        // Metadata must be constant
+        assert!(query_parser::import(r#"include "c" {search: .x};"#).is_err());
+        assert!(query_parser::import(r#"import "a" as a"#).is_err());
+        assert!(query_parser::import(r#"import "a";"#).is_err());
+    }
+
+    #[test]
+    fn test_program() {
+        let (imports, query) =
+            query_parser::program(r#"import "a" as a; include "b"; a::f($x::x)"#)
+                .expect("failed to parse");
+        assert_eq!(imports.len(), 2);
+        assert_eq!(
+            query,
+            Expr::Call("a::f".to_string(), vec![Expr::Variable("x::x".to_string())])
+        );
+        assert_eq!(query_parser::program(""), Ok((Vec::new(), Expr::Identity)));
+        // Directives must come first
+        assert!(query_parser::program(r#". | import "a" as a; ."#).is_err());
+    }
+
+    #[test]
+    fn test_module() {
+        let module =
+            parse_module("module {name: \"m\"};\nimport \"a\" as a;\ndef f: a::g;\ndef h: f;\n")
+                .expect("failed to parse");
+        assert_eq!(module.meta().get("name"), Some(&Value::from("m")));
+        assert_eq!(module.imports().len(), 1);
+        assert_eq!(module.definitions().len(), 2);
+        assert!(parse_module("").is_ok());
+        // A module has no main query
+        assert!(parse_module("def f: 1; f").is_err());
+    }
+
+    #[test]
+    fn test_conditional() {
+        let lit = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            query_parser::conditional("if . then 1 elif .a then 2 else 3 end"),
+            Ok(Expr::If(
+                Box::new(Expr::Identity),
+                lit(1),
+                Box::new(Expr::If(
+                    Box::new(Expr::Ident("a".to_string(), false)),
+                    lit(2),
+                    lit(3)
+                ))
+            ))
+        );
+        assert_eq!(
+            query_parser::conditional("if . then 1 end"),
+            Ok(Expr::If(
+                Box::new(Expr::Identity),
+                lit(1),
+                Box::new(Expr::Identity)
+            ))
+        );
+        assert!(query_parser::conditional("if . then 1 else 2").is_err());
+        assert!(query_parser::conditional("if . 1 end").is_err());
+        assert!(parse("if .a | length > 1 then .a, .b else def f: 1; f end | [.]").is_ok());
+        assert!(parse("if . then 1 endx").is_err());
+    }
+
+    #[test]
+    fn test_try_catch() {
+        let ident = |s: &str| Box::new(Expr::Ident(s.to_string(), false));
+        assert_eq!(
+            query_parser::query("try .a catch .b | .c"),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Try(ident("a"), Some(ident("b")))),
+                ident("c")
+            ))
+        );
+        assert_eq!(
+            query_parser::query("try .a + 1"),
+            Ok(Expr::Operation(
+                Box::new(Expr::Try(ident("a"), None)),
+                vec![(Operator::Add, *lit_box(1))]
+            ))
+        );
+        assert_eq!(
+            query_parser::query(r#"error("x")"#),
+            Ok(Expr::Function(Function::Error(Some(Box::new(
+                Expr::Literal(Value::from("x"))
+            )))))
+        );
+        assert!(query_parser::query("try").is_err());
+        assert!(query_parser::query(". catch .").is_err());
+    }
+
+    #[test]
+    fn test_postfix_optional() {
+        let ident = |s: &str| Expr::Ident(s.to_string(), false);
+        assert_eq!(
+            query_parser::query("(.a)?"),
+            Ok(Expr::Try(Box::new(ident("a")), None))
+        );
+        // `?` applies to the whole path before it
+        assert_eq!(
+            query_parser::query(".[]?.b"),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Try(Box::new(Expr::Range(RangeType::new())), None)),
+                Box::new(ident("b"))
+            ))
+        );
+        assert_eq!(
+            query_parser::query("$x[]??"),
+            Ok(Expr::Try(
+                Box::new(Expr::Try(
+                    Box::new(Expr::Pipe(
+                        Box::new(Expr::Variable("x".to_string())),
+                        Box::new(Expr::Range(RangeType::new()))
+                    )),
+                    None
+                )),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn test_reduce_foreach() {
+        let x = || vec![Pattern::Variable("x".to_string())];
+        let add_x = || {
+            Box::new(Expr::Operation(
+                Box::new(Expr::Identity),
+                vec![(Operator::Add, Expr::Variable("x".to_string()))],
+            ))
+        };
+        let zero = || Box::new(Expr::Literal(Value::from(0)));
+        let each = || Box::new(Expr::Range(RangeType::new()));
+        assert_eq!(
+            query_parser::query("reduce .[] as $x (0; . + $x)"),
+            Ok(Expr::Reduce(each(), x(), zero(), add_x()))
+        );
+        assert_eq!(
+            query_parser::query("foreach .[] as $x (0; . + $x)"),
+            Ok(Expr::Foreach(each(), x(), zero(), add_x(), None))
+        );
+        assert_eq!(
+            query_parser::query("foreach .[] as $x (0; . + $x; $x)"),
+            Ok(Expr::Foreach(
+                each(),
+                x(),
+                zero(),
+                add_x(),
+                Some(Box::new(Expr::Variable("x".to_string())))
+            ))
+        );
+        assert!(parse("reduce .[] as [$a, $b] ?// $a (0, 1; ., . + $a | . * 2) | . + 1").is_ok());
+        assert!(parse("reduce .[] as $x (0)").is_err());
+        assert!(parse("foreach .[] as $x (0; 1; 2; 3)").is_err());
+        assert!(parse("reduce .[] as $x (0; .) | $x").is_err());
+        assert!(parse("def reduce: 1; reduce").is_err());
+    }
+
+    #[test]
+    fn test_object() {
+        let lit = |s: &str| Expr::Literal(Value::from(s));
+        let ident = |s: &str| Expr::Ident(s.to_string(), false);
+        assert_eq!(
+            query_parser::object(r#"{a: .x, "b c": .y, (.k): .v, d, "e"}"#),
+            Ok(Expr::Object(vec![
+                (lit("a"), ident("x")),
+                (lit("b c"), ident("y")),
+                (ident("k"), ident("v")),
+                (lit("d"), ident("d")),
+                (lit("e"), ident("e")),
+            ]))
+        );
+        assert_eq!(
+            query_parser::object("{\n  a: .x | .y\n}"),
+            Ok(Expr::Object(vec![(
+                lit("a"),
+                Expr::Pipe(Box::new(ident("x")), Box::new(ident("y")))
+            )]))
+        );
+        assert_eq!(query_parser::object("{}"), Ok(Expr::Object(vec![])));
+        assert_eq!(
+            query_parser::object("{a: 1, b,\n}"),
+            Ok(Expr::Object(vec![
+                (lit("a"), Expr::Literal(Value::from(1))),
+                (lit("b"), ident("b")),
+            ]))
+        );
+        assert!(query_parser::object("{,}").is_err());
+        assert!(query_parser::object("{a: 1,,}").is_err());
+    }
+
+    #[test]
+    fn test_group_precedence() {
+        let a = || Box::new(Expr::Ident("a".to_string(), false));
+        let lit = |n: i64| Box::new(Expr::Literal(Value::from(n)));
+        assert_eq!(
+            query_parser::pipe("(.a + 1) * 2"),
+            Ok(Expr::Operation(
+                a(),
+                vec![(Operator::Add, *lit(1)), (Operator::Multiply, *lit(2))]
+            ))
+        );
+        assert_eq!(
+            query_parser::pipe("(.a, .a) | length"),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Comma(vec![*a(), *a()])),
+                Box::new(Expr::Function(Function::Length))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        let a = || Expr::Ident("a".to_string(), false);
+        let lit = |n: i64| Expr::Literal(Value::from(n));
+        let negate_a = || Expr::Operation(Box::new(lit(0)), vec![(Operator::Subtract, a())]);
+        assert_eq!(query_parser::pipe("-.a"), Ok(negate_a()));
+        assert_eq!(
+            query_parser::pipe("- (1)"),
+            Ok(Expr::Operation(
+                Box::new(lit(0)),
+                vec![(Operator::Subtract, lit(1))]
+            ))
+        );
+        assert_eq!(
+            query_parser::pipe("-.a * 2"),
+            Ok(Expr::Operation(
+                Box::new(lit(0)),
+                vec![(Operator::Subtract, a()), (Operator::Multiply, lit(2))]
+            ))
+        );
+        assert_eq!(
+            query_parser::pipe("1 - -.a"),
+            Ok(Expr::Operation(
+                Box::new(lit(1)),
+                vec![(Operator::Subtract, negate_a())]
+            ))
+        );
+        assert_eq!(query_parser::pipe("-1"), Ok(lit(-1)));
+        assert_eq!(
+            query_parser::pipe("2 -1"),
+            Ok(Expr::Operation(
+                Box::new(lit(2)),
+                vec![(Operator::Subtract, lit(1))]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_postfix() {
+        assert_eq!(
+            query_parser::term(r#"(.a)[0].b"#),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Ident("a".to_string(), false)),
+                Box::new(Expr::path(vec![
+                    Expr::Index(IndexType::from((0, false))),
+                    Expr::Ident("b".to_string(), false)
+                ]))
+            ))
+        );
+        assert_eq!(
+            query_parser::term(r#"[1][]"#),
+            Ok(Expr::Pipe(
+                Box::new(Expr::Array(lit_box(1))),
+                Box::new(Expr::Range(RangeType::new()))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        assert_eq!(
+            query_parser::query(r#"$x"#),
+            Ok(Expr::Variable("x".to_string()))
+        );
+        assert_eq!(
+            query_parser::query(r#". as $x | $x"#),
+            Ok(Expr::Bind(
+                Box::new(Expr::Identity),
+                vec![Pattern::Variable("x".to_string())],
+                Box::new(Expr::Variable("x".to_string()))
+            ))
+        );
+        assert_eq!(
+            query_parser::query(r#"{$x}"#),
+            Ok(Expr::Object(vec![(
+                Expr::Literal(Value::from("x")),
+                Expr::Variable("x".to_string())
+            )]))
+        );
+        assert!(query_parser::query(r#"$1"#).is_err());
+    }
+
+    #[test]
+    fn test_bind_body_extends_right() {
+        assert_eq!(
+            query_parser::query(r#"1 as $x | 2, $x | 3"#),
+            Ok(Expr::Bind(
+                lit_box(1),
+                vec![Pattern::Variable("x".to_string())],
+                Box::new(Expr::Pipe(
+                    Box::new(Expr::Comma(vec![
+                        *lit_box(2),
+                        Expr::Variable("x".to_string())
+                    ])),
+                    lit_box(3)
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_patterns() {
+        let var = |name: &str| Pattern::Variable(name.to_string());
+        let key = |name: &str| Expr::Literal(Value::from(name));
+        assert_eq!(query_parser::pattern(r#"$a"#), Ok(var("a")));
+        assert_eq!(
+            query_parser::pattern(r#"[$a, [ $b ]]"#),
+            Ok(Pattern::Array(vec![
+                var("a"),
+                Pattern::Array(vec![var("b")])
+            ]))
+        );
+        assert_eq!(
+            query_parser::pattern(r#"{$a, b: $b, "c d": $c, (.k): $k, $e: [$f]}"#),
+            Ok(Pattern::Object(vec![
+                (key("a"), var("a")),
+                (key("b"), var("b")),
+                (key("c d"), var("c")),
+                (Expr::Ident("k".to_string(), false), var("k")),
+                (key("e"), var("e")),
+                (key("e"), Pattern::Array(vec![var("f")])),
+            ]))
+        );
+        assert!(query_parser::pattern(r#"[]"#).is_err());
+        assert!(query_parser::pattern(r#"{a}"#).is_err());
+        assert!(query_parser::pattern(r#".a"#).is_err());
+    }
+
+    #[test]
+    fn test_pattern_alternatives() {
+        assert_eq!(
+            query_parser::query(r#". as [$a] ?// $a | $a"#),
+            Ok(Expr::Bind(
+                Box::new(Expr::Identity),
+                vec![
+                    Pattern::Array(vec![Pattern::Variable("a".to_string())]),
+                    Pattern::Variable("a".to_string())
+                ],
+                Box::new(Expr::Variable("a".to_string()))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        assert!(matches!(
+            parse(r#"$x"#),
+            Err(JQError::UndefinedVariable(name)) if name == "x"
+        ));
+        assert!(matches!(
+            parse(r#"(. as $x | $x), $x"#),
+            Err(JQError::UndefinedVariable(_))
+        ));
+        assert!(matches!(
+            parse(r#"map(. as $x | $x) | $x"#),
+            Err(JQError::UndefinedVariable(_))
+        ));
+        assert!(parse(r#". as $x | map($x)"#).is_ok());
+        assert!(parse(r#"$ENV, $__prog_args"#).is_ok());
+        assert!(parse_with_vars(r#"$name"#, &["name"]).is_ok());
+        assert!(parse(r#". as {$a, ($a): $b} | $b"#).is_ok());
+        assert!(parse(r#". as [$a] ?// {$b} | $a, $b"#).is_ok());
+        assert!(matches!(
+            parse(r#". as {($b): $a, $b} | $a"#),
+            Err(JQError::UndefinedVariable(_))
+        ));
+    }
+
+    #[test]
+    fn test_label() {
+        assert_eq!(
+            query_parser::query("label $out | 1, break $out"),
+            Ok(Expr::Label(
+                "out".to_string(),
+                Box::new(Expr::Comma(vec![
+                    Expr::Literal(Value::from(1)),
+                    Expr::Break("out".to_string())
+                ]))
+            ))
+        );
+        assert!(
+            parse("[label $a | .[] | label $b | if . then break $a else break $b end]").is_ok()
+        );
+        assert!(parse("label $out | def f: break $out; f").is_ok());
+        assert!(matches!(
+            parse("break $out"),
+            Err(JQError::UndefinedLabel(name)) if name == "out"
+        ));
+        assert!(matches!(
+            parse("(label $out | 1), break $out"),
+            Err(JQError::UndefinedLabel(_))
+        ));
+        assert!(matches!(
+            parse(". as $out | break $out"),
+            Err(JQError::UndefinedLabel(_))
+        ));
+        assert!(parse("label $out | $out").is_err());
+    }
+
     #[test]
     fn test_numbers() {
         assert_eq!(query_parser::number("0"), Ok(0));
@@ -453,4 +1533,49 @@ mod tests {
         assert!(query_parser::number("123+").is_err());
         assert!(query_parser::number("123-").is_err());
     }
+
+    #[test]
+    fn test_nesting_limit() {
+        let nested = |open: &str, inner: &str, close: &str, depth: usize| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+        assert!(parse(&nested("(", ".", ")", 20)).is_ok());
+        assert!(parse(&nested("[", ".", "]", 20)).is_ok());
+        assert!(parse(&format!(". as {} | $a", nested("[", "$a", "]", 20))).is_ok());
+
+        // Too deeply nested to parse, rather than a stack overflow
+        for query_str in [
+            nested("(", ".", ")", 3000),
+            nested("[", ".", "]", 3000),
+            nested("{a: ", ".", "}", 3000),
+            nested("try ", ".", "", 3000),
+            nested("if . then ", ".", " end", 3000),
+            nested(". | ", ".", "", 3000),
+            format!(". as {} | $a", nested("[", "$a", "]", 3000)),
+        ] {
+            assert!(matches!(parse(&query_str), Err(JQError::ParseError)));
+        }
+    }
+
+    #[test]
+    fn test_long_chains() {
+        // Long lists and operator chains are flat, so they can be resolved,
+        // evaluated and dropped without a deep recursion
+        let ones = vec!["1"; 10000];
+        let array = parse(&format!("[{}]", ones.join(","))).expect("failed to parse");
+        assert_eq!(
+            query_expr(&Value::Null, &array).expect("failed to query"),
+            [Value::from(vec![1; 10000])]
+        );
+        let sum = parse(&ones.join(" + ")).expect("failed to parse");
+        assert_eq!(
+            query_expr(&Value::Null, &sum).expect("failed to query"),
+            [Value::from(10000)]
+        );
+        let compare = parse(&format!("{} == 1", ones.join(" - "))).expect("failed to parse");
+        assert_eq!(
+            query_expr(&Value::Null, &compare).expect("failed to query"),
+            [Value::from(false)]
+        );
+    }
 }
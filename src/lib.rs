@@ -1,50 +1,97 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
-use action::*;
-use block::*;
+use compiled::*;
+use definition::*;
+use env::*;
 use errors::*;
+use expr::*;
 use function::*;
 use has_type::*;
 use index_type::*;
+use input::*;
 use jq_peg::*;
+use loader::*;
+use module::*;
+use native::*;
+use operator::*;
+use paths::*;
+use pattern::*;
 use query::*;
 use range_type::*;
 pub use serde_json;
-use token::*;
+use variables::*;
 
 use serde_json::Value;
-/// Contains Action
-pub mod action;
-/// Contains Block
-pub mod block;
+use std::borrow::Cow;
+/// Contains Query
+pub mod compiled;
+/// Contains Definition and Param
+pub mod definition;
+/// Contains Env
+pub mod env;
 /// Contains JQError
 pub mod errors;
+/// Contains Expr
+pub mod expr;
 /// Contains Function
 pub mod function;
 /// Contains HasType
 pub mod has_type;
 /// Contains IndexType
 pub mod index_type;
+/// Contains Input and ValueIter
+pub mod input;
 #[doc(hidden)]
 pub mod jq_peg;
+/// Contains Loader
+pub mod loader;
+/// Contains Module and Import
+pub mod module;
+/// Contains NativeFunction and FunctionRegistry
+pub mod native;
+/// Contains Operator
+pub mod operator;
+/// Contains the path expression functions
+pub mod paths;
+/// Contains Pattern
+pub mod pattern;
 /// Contains the query functions
 pub mod query;
 /// Contains RangeType
 pub mod range_type;
-/// Contains Token
-pub mod token;
+/// Contains Variables
+pub mod variables;
 
 /// This is the function that users of the r_jq library will call.
 pub fn jq(json: &[u8], query_str: &str) -> Result<Vec<Value>, JQError> {
     let value: Value = serde_json::from_slice(json)?;
-    let blocks = parse(query_str)?;
-    query(&[value], blocks)
+    Query::compile(query_str)?.run(&value)
 }
 
-/// In case you are already dealiing with a serde_json::Value, use this
+/// In case you are already dealiing with a serde_json::Value, use this.
+/// To run the same query many times, compile it once with [Query::compile].
 pub fn jq_from_value(value: &Value, query_str: &str) -> Result<Vec<Value>, JQError> {
-    let blocks = parse(query_str)?;
-    query(&[value.to_owned()], blocks)
+    Query::compile(query_str)?.run(value)
+}
+
+/// Run a query with values bound to `$name` variables, so that untrusted values
+/// never need to be formatted into the query string.
+pub fn jq_with_vars(
+    value: &Value,
+    query_str: &str,
+    vars: &[(&str, Value)],
+) -> Result<Vec<Value>, JQError> {
+    let vars = Variables::from(vars);
+    Query::compile_with_vars(query_str, &vars.names())?.run_with(value, &vars)
+}
+
+/// Lazily run a compiled query, borrowing results from the input rather than
+/// cloning them.  Only values that the query constructs are owned.
+pub fn jq_ref<'a>(
+    value: &'a Value,
+    query: &'a Query,
+) -> impl Iterator<Item = Result<Cow<'a, Value>, JQError>> + 'a {
+    query.run_ref(value)
 }
 #[cfg(test)]
 mod tests {
@@ -61,6 +108,31 @@ mod tests {
         assert_eq!(&result, &[input]);
     }
 
+    #[test]
+    fn test_jq_ref() {
+        let input = serde_json::json!({"items": [{"id": 1}, {"id": 2}]});
+        let query = Query::compile(".items[] | .id").expect("Failed to compile");
+        let result: Vec<Cow<Value>> = jq_ref(&input, &query)
+            .collect::<Result<_, _>>()
+            .expect("Failed JQ");
+        assert!(std::ptr::eq(result[0].as_ref(), &input["items"][0]["id"]));
+        assert_eq!(result[1].as_ref(), &serde_json::json!(2));
+    }
+
+    #[test]
+    fn test_jq_with_vars() {
+        let input = serde_json::json!([{"name": "a\" or true"}, {"name": "b"}]);
+        let vars = [("name", serde_json::json!("a\" or true"))];
+        let result =
+            jq_with_vars(&input, ".[] | select(.name == $name)", &vars).expect("Failed JQ");
+        assert_eq!(&result, &[input[0].clone()]);
+
+        assert!(matches!(
+            jq_with_vars(&input, "$other", &vars),
+            Err(JQError::UndefinedVariable(_))
+        ));
+    }
+
     #[test]
     fn test_empty_query() {
         let json = include_bytes!("../test/basic.json");
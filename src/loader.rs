@@ -0,0 +1,298 @@
+/// Finds and loads the modules named by `import` and `include` directives.
+///
+/// A module path such as `"lib/util"` is looked up in each directory of the
+/// search path in turn, as `lib/util.jq` or `lib/util/util.jq`, and a data
+/// module as `lib/util.json`.  Directories in the `search` metadata of a
+/// directive are tried first, relative to the module that contains it.  A
+/// search path directory may start with `~/` for the home directory, or
+/// `$ORIGIN/` for the directory of the running executable.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{parse_module, Definition, Expr, Import, JQError, Module, Pattern};
+
+/// The search path used when none is given, the same as jq's
+const DEFAULT_SEARCH_PATH: [&str; 3] = ["~/.jq", "$ORIGIN/../lib/jq", "$ORIGIN/../lib"];
+
+/// Loads modules from a search path
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Loader {
+    search_path: Vec<PathBuf>,
+}
+
+/// Expand a leading `~/` or `$ORIGIN/`.  Returns `None` if the directory it
+/// refers to is not known.
+fn expand(dir: &Path) -> Option<PathBuf> {
+    if let Ok(rest) = dir.strip_prefix("~") {
+        let home = std::env::var_os("HOME")?;
+        return Some(PathBuf::from(home).join(rest));
+    }
+    if let Ok(rest) = dir.strip_prefix("$ORIGIN") {
+        let exe = std::env::current_exe().ok()?;
+        return Some(exe.parent()?.join(rest));
+    }
+    Some(dir.to_path_buf())
+}
+
+/// Every JSON text in a data module
+fn read_json_texts(path: &Path) -> Result<Vec<Value>, JQError> {
+    let contents = fs::read(path).map_err(|error| module_error(path, error))?;
+    serde_json::Deserializer::from_slice(&contents)
+        .into_iter::<Value>()
+        .collect::<Result<_, _>>()
+        .map_err(|error| module_error(path, error))
+}
+
+fn module_error(path: &Path, error: impl ToString) -> JQError {
+    JQError::ModuleError(path.display().to_string(), error.to_string())
+}
+
+/// Nest the definitions, so that each one is visible to those after it and to `rest`
+fn define_all(definitions: Vec<Definition>, rest: Expr) -> Expr {
+    definitions
+        .into_iter()
+        .rev()
+        .fold(rest, |rest, definition| {
+            Expr::Define(Box::new(definition), Box::new(rest))
+        })
+}
+
+impl Loader {
+    /// A loader that uses the default search path
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory to the search path.  Once a directory is added, the
+    /// default search path is no longer used.
+    pub fn with_search_path(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.search_path.push(dir.into());
+        self
+    }
+
+    /// The directories that modules are looked up in, in order
+    pub fn search_path(&self) -> Vec<PathBuf> {
+        let dirs = if self.search_path.is_empty() {
+            DEFAULT_SEARCH_PATH.iter().map(PathBuf::from).collect()
+        } else {
+            self.search_path.clone()
+        };
+        dirs.iter().filter_map(|dir| expand(dir)).collect()
+    }
+
+    /// The file for `import`, which is in a module in `origin`
+    fn find(&self, import: &Import, origin: &Path) -> Result<PathBuf, JQError> {
+        let extension = if import.is_data() { "json" } else { "jq" };
+        let relpath = Path::new(import.path());
+        let name = match relpath.file_name() {
+            Some(name) if relpath.is_relative() => name.to_string_lossy(),
+            _ => return Err(JQError::ModuleNotFound(import.path().to_string())),
+        };
+        let dirs = import
+            .search()
+            .into_iter()
+            .filter_map(|dir| expand(Path::new(dir)))
+            .map(|dir| origin.join(dir))
+            .chain(self.search_path());
+        for dir in dirs {
+            let candidates = [
+                dir.join(format!("{}.{}", import.path(), extension)),
+                dir.join(relpath).join(format!("{}.{}", name, extension)),
+            ];
+            if let Some(path) = candidates.into_iter().find(|path| path.is_file()) {
+                return Ok(path);
+            }
+        }
+        Err(JQError::ModuleNotFound(import.path().to_string()))
+    }
+
+    /// Parse the module file at `path`
+    fn parse_file(&self, path: &Path) -> Result<Module, JQError> {
+        let source = fs::read_to_string(path).map_err(|error| module_error(path, error))?;
+        parse_module(&source).map_err(|error| module_error(path, error))
+    }
+
+    /// The metadata of the module `name`, as reported by `modulemeta`
+    pub fn modulemeta(&self, name: &str) -> Result<Value, JQError> {
+        let path = self.find(&Import::include(name, Default::default()), Path::new("."))?;
+        Ok(self.parse_file(&path)?.metadata())
+    }
+
+    /// Load the modules named by `imports`, and make them visible to `query`.
+    /// Relative `search` directories are relative to the current directory.
+    pub(crate) fn link(&self, imports: &[Import], query: Expr) -> Result<Expr, JQError> {
+        self.link_all(imports, query, Path::new("."), &mut Vec::new())
+    }
+
+    /// Link each of `imports`, which are in a module in `origin`.  `loading`
+    /// holds the modules that are being linked, to detect circular imports.
+    fn link_all(
+        &self,
+        imports: &[Import],
+        rest: Expr,
+        origin: &Path,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<Expr, JQError> {
+        imports.iter().rev().try_fold(rest, |rest, import| {
+            self.link_import(import, rest, origin, loading)
+        })
+    }
+
+    /// A data module is bound to `$name` and `$name::name` around `rest`, as
+    /// in jq, the definitions
+    /// of an included module are placed before `rest`, and an imported module
+    /// becomes an [Expr::Import]
+    fn link_import(
+        &self,
+        import: &Import,
+        rest: Expr,
+        origin: &Path,
+        loading: &mut Vec<PathBuf>,
+    ) -> Result<Expr, JQError> {
+        let path = self.find(import, origin)?;
+        if import.is_data() {
+            let alias = import.alias().unwrap_or_default();
+            let data = Expr::Literal(Value::Array(read_json_texts(&path)?));
+            let qualified = format!("{0}::{0}", alias);
+            let rest = Expr::Bind(
+                Box::new(Expr::Variable(qualified.clone())),
+                vec![Pattern::Variable(alias.to_string())],
+                Box::new(rest),
+            );
+            let pattern = Pattern::Variable(qualified);
+            return Ok(Expr::Bind(Box::new(data), vec![pattern], Box::new(rest)));
+        }
+
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if loading.contains(&canonical) {
+            return Err(module_error(&path, "circular import"));
+        }
+        let (imports, definitions) = self.parse_file(&path)?.into_parts();
+        let dir = path.parent().unwrap_or(origin).to_path_buf();
+        loading.push(canonical);
+        let linked = match import.alias() {
+            Some(alias) => {
+                let library = define_all(definitions, Expr::Identity);
+                let library = self.link_all(&imports, library, &dir, loading)?;
+                Expr::Import(Box::new(library), alias.to_string(), Box::new(rest))
+            }
+            None => self.link_all(&imports, define_all(definitions, rest), &dir, loading)?,
+        };
+        loading.pop();
+        Ok(linked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Map};
+
+    const MODULES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test/modules");
+
+    fn loader() -> Loader {
+        Loader::new().with_search_path(MODULES)
+    }
+
+    #[test]
+    fn test_search_path() {
+        assert_eq!(loader().search_path(), [PathBuf::from(MODULES)]);
+        let search_path = Loader::new().search_path();
+        assert!(search_path.iter().all(|dir| !dir.starts_with("~")));
+        assert!(search_path.iter().all(|dir| !dir.starts_with("$ORIGIN")));
+    }
+
+    #[test]
+    fn test_find() {
+        let found = loader()
+            .find(&Import::include("util", Map::new()), Path::new("."))
+            .expect("not found");
+        assert_eq!(found, Path::new(MODULES).join("util.jq"));
+
+        // `nested` is `nested/nested.jq`
+        let found = loader()
+            .find(
+                &Import::functions("nested", "n", Map::new()),
+                Path::new("."),
+            )
+            .expect("not found");
+        assert_eq!(found, Path::new(MODULES).join("nested/nested.jq"));
+
+        let found = loader()
+            .find(&Import::data("data", "d", Map::new()), Path::new("."))
+            .expect("not found");
+        assert_eq!(found, Path::new(MODULES).join("data.json"));
+
+        assert!(matches!(
+            loader().find(&Import::include("missing", Map::new()), Path::new(".")),
+            Err(JQError::ModuleNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_find_search_metadata() {
+        let mut meta = Map::new();
+        meta.insert("search".to_string(), json!("./nested"));
+        let import = Import::include("nested", meta);
+        let found = Loader::new()
+            .with_search_path("/nonexistent")
+            .find(&import, Path::new(MODULES))
+            .expect("not found");
+        assert_eq!(found, Path::new(MODULES).join("./nested/nested.jq"));
+    }
+
+    #[test]
+    fn test_link() {
+        let imports = [
+            Import::data("data", "d", Map::new()),
+            Import::functions("util", "u", Map::new()),
+            Import::include("util", Map::new()),
+        ];
+        let expr = loader().link(&imports, Expr::Identity).expect("failed");
+        match expr {
+            Expr::Bind(data, patterns, rest) => {
+                assert_eq!(*data, Expr::Literal(json!([{"a": 1}, {"a": 2}])));
+                assert_eq!(patterns, [Pattern::Variable("d::d".to_string())]);
+                let rest = match *rest {
+                    Expr::Bind(data, patterns, rest) => {
+                        assert_eq!(*data, Expr::Variable("d::d".to_string()));
+                        assert_eq!(patterns, [Pattern::Variable("d".to_string())]);
+                        rest
+                    }
+                    other => panic!("unexpected {:?}", other),
+                };
+                assert!(matches!(*rest, Expr::Import(_, alias, rest)
+                    if alias == "u" && rest.is_define()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_circular_import() {
+        let imports = [Import::functions("cycle_a", "a", Map::new())];
+        let error = loader()
+            .link(&imports, Expr::Identity)
+            .expect_err("should fail");
+        assert!(error.to_string().ends_with("circular import"));
+    }
+
+    #[test]
+    fn test_modulemeta() {
+        assert_eq!(
+            loader().modulemeta("lib").expect("failed"),
+            json!({
+                "name": "lib",
+                "version": 1,
+                "deps": [
+                    {"as": "data", "is_data": true, "relpath": "data"},
+                    {"is_data": false, "relpath": "util"}
+                ],
+                "defs": ["double/0", "increment/0", "first_datum/0"]
+            })
+        );
+    }
+}
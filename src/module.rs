@@ -0,0 +1,211 @@
+/// Modules of jq definitions, and the directives that load them.
+///
+/// A module is a file of function definitions, which may start with a
+/// `module {...};` directive and any number of `import` and `include`
+/// directives.  A query may start with the same directives:
+/// * `import "path" as name;` : the functions of `path.jq` are called as `name::f`
+/// * `import "path" as $name;` : every JSON text in `path.json`, as an array,
+///   is bound to `$name::name`
+/// * `include "path";` : the definitions of `path.jq` are used as if they
+///   were written in place of the directive
+///
+/// Every directive may be followed by a constant metadata object, such as
+/// `import "lib" as lib {search: "./vendor"};`.
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::Definition;
+
+/// An `import` or `include` directive
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Import {
+    path: String,
+    alias: Option<String>,
+    is_data: bool,
+    meta: Map<String, Value>,
+}
+
+impl Import {
+    /// `import "path" as alias;`
+    pub fn functions(path: &str, alias: &str, meta: Map<String, Value>) -> Self {
+        Self {
+            path: path.to_string(),
+            alias: Some(alias.to_string()),
+            is_data: false,
+            meta,
+        }
+    }
+
+    /// `import "path" as $alias;`
+    pub fn data(path: &str, alias: &str, meta: Map<String, Value>) -> Self {
+        Self {
+            path: path.to_string(),
+            alias: Some(alias.to_string()),
+            is_data: true,
+            meta,
+        }
+    }
+
+    /// `include "path";`
+    pub fn include(path: &str, meta: Map<String, Value>) -> Self {
+        Self {
+            path: path.to_string(),
+            alias: None,
+            is_data: false,
+            meta,
+        }
+    }
+
+    /// The path of the module, relative to the search path and without an extension
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The name the module is imported as, or `None` for `include`
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    /// True for a JSON data module, `import "path" as $name;`
+    pub fn is_data(&self) -> bool {
+        self.is_data
+    }
+
+    /// The metadata object that follows the path, or an empty object
+    pub fn meta(&self) -> &Map<String, Value> {
+        &self.meta
+    }
+
+    /// The directories in the `search` metadata key, which may be a string
+    /// or an array of strings
+    pub fn search(&self) -> Vec<&str> {
+        match self.meta.get("search") {
+            Some(Value::String(dir)) => vec![dir.as_str()],
+            Some(Value::Array(dirs)) => dirs.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The dependency as reported by `modulemeta`: the metadata, followed by
+    /// `as`, `is_data` and `relpath`
+    pub fn metadata(&self) -> Value {
+        let mut meta = self.meta.clone();
+        if let Some(alias) = &self.alias {
+            meta.insert("as".to_string(), Value::from(alias.as_str()));
+        }
+        meta.insert("is_data".to_string(), Value::Bool(self.is_data));
+        meta.insert("relpath".to_string(), Value::from(self.path.as_str()));
+        Value::Object(meta)
+    }
+}
+
+/// A parsed module file
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub struct Module {
+    meta: Map<String, Value>,
+    imports: Vec<Import>,
+    definitions: Vec<Definition>,
+}
+
+impl Module {
+    /// Create a module from its `module` metadata, directives and definitions
+    pub fn new(
+        meta: Map<String, Value>,
+        imports: Vec<Import>,
+        definitions: Vec<Definition>,
+    ) -> Self {
+        Self {
+            meta,
+            imports,
+            definitions,
+        }
+    }
+
+    /// The `module {...};` metadata, or an empty object
+    pub fn meta(&self) -> &Map<String, Value> {
+        &self.meta
+    }
+
+    /// The `import` and `include` directives, in order
+    pub fn imports(&self) -> &[Import] {
+        &self.imports
+    }
+
+    /// The functions defined by the module, in order
+    pub fn definitions(&self) -> &[Definition] {
+        &self.definitions
+    }
+
+    /// The module as reported by `modulemeta`: the metadata, followed by
+    /// `deps`, the directives, and `defs`, each function as `name/arity`
+    pub fn metadata(&self) -> Value {
+        let mut meta = self.meta.clone();
+        let deps = self.imports.iter().map(Import::metadata).collect();
+        meta.insert("deps".to_string(), Value::Array(deps));
+        let defs = self
+            .definitions
+            .iter()
+            .map(|definition| Value::from(format!("{}/{}", definition.name(), definition.arity())))
+            .collect();
+        meta.insert("defs".to_string(), Value::Array(defs));
+        Value::Object(meta)
+    }
+
+    pub(crate) fn into_parts(self) -> (Vec<Import>, Vec<Definition>) {
+        (self.imports, self.definitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Expr, Param};
+    use serde_json::json;
+
+    fn object(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn test_import_metadata() {
+        let import = Import::functions("lib/a", "a", object(json!({"search": "./"})));
+        assert_eq!(
+            import.metadata(),
+            json!({"search": "./", "as": "a", "is_data": false, "relpath": "lib/a"})
+        );
+        assert_eq!(import.search(), ["./"]);
+        assert_eq!(
+            Import::data("d", "d", Map::new()).metadata(),
+            json!({"as": "d", "is_data": true, "relpath": "d"})
+        );
+        assert_eq!(
+            Import::include("c", Map::new()).metadata(),
+            json!({"is_data": false, "relpath": "c"})
+        );
+        let import = Import::include("c", object(json!({"search": ["a", 1, "b"]})));
+        assert_eq!(import.search(), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_module_metadata() {
+        let module = Module::new(
+            object(json!({"name": "m"})),
+            vec![Import::functions("a", "a", Map::new())],
+            vec![
+                Definition::new("f", Vec::new(), Expr::Identity),
+                Definition::new("g", vec![Param::Filter("h".to_string())], Expr::Identity),
+            ],
+        );
+        assert_eq!(
+            module.metadata(),
+            json!({
+                "name": "m",
+                "deps": [{"as": "a", "is_data": false, "relpath": "a"}],
+                "defs": ["f/0", "g/1"]
+            })
+        );
+    }
+}
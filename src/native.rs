@@ -0,0 +1,163 @@
+/// Functions implemented in Rust, which embedders make available to queries.
+///
+/// A [FunctionRegistry] is given to [CompileOptions::with_functions](crate::compiled::CompileOptions::with_functions),
+/// and its functions are called by name like any builtin, ie: `lookup_user(.id)`.
+/// Each argument is evaluated against the input, and the function is called
+/// once for each combination of their results.  A native function shadows a
+/// builtin with the same name and arity, and is shadowed by a user defined one.
+use std::fmt;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::JQError;
+
+/// A function implemented in Rust.  Any
+/// `Fn(&Value, &[Value]) -> Result<Value, JQError>` closure is a native function.
+pub trait NativeFunction: Send + Sync {
+    /// Compute the result for `input`, with one value for each argument
+    fn call(&self, input: &Value, args: &[Value]) -> Result<Value, JQError>;
+}
+
+impl<F> NativeFunction for F
+where
+    F: Fn(&Value, &[Value]) -> Result<Value, JQError> + Send + Sync,
+{
+    fn call(&self, input: &Value, args: &[Value]) -> Result<Value, JQError> {
+        self(input, args)
+    }
+}
+
+impl fmt::Debug for dyn NativeFunction + '_ {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("NativeFunction")
+    }
+}
+
+/// Native functions, identified by name and arity
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: Vec<(String, usize, Arc<dyn NativeFunction>)>,
+}
+
+impl FunctionRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function that is called as `name` with `arity` arguments.  It
+    /// replaces a function with the same name and arity.
+    pub fn with_function(
+        mut self,
+        name: &str,
+        arity: usize,
+        function: impl NativeFunction + 'static,
+    ) -> Self {
+        self.functions
+            .retain(|(existing, n, _)| existing != name || *n != arity);
+        self.functions
+            .push((name.to_string(), arity, Arc::new(function)));
+        self
+    }
+
+    /// The function called as `name` with `arity` arguments
+    pub fn get(&self, name: &str, arity: usize) -> Option<&dyn NativeFunction> {
+        self.functions
+            .iter()
+            .find(|(existing, n, _)| existing == name && *n == arity)
+            .map(|(_, _, function)| function.as_ref())
+    }
+
+    /// The name and arity of each function
+    pub fn signatures(&self) -> Vec<(&str, usize)> {
+        self.functions
+            .iter()
+            .map(|(name, arity, _)| (name.as_str(), *arity))
+            .collect()
+    }
+
+    /// True if there are no functions
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signatures: Vec<String> = self
+            .signatures()
+            .iter()
+            .map(|(name, arity)| format!("{}/{}", name, arity))
+            .collect();
+        f.debug_struct("FunctionRegistry")
+            .field("functions", &signatures)
+            .finish()
+    }
+}
+
+/// Registries are equal if they have the same functions, in the same order
+impl PartialEq for FunctionRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        self.functions.len() == other.functions.len()
+            && self
+                .functions
+                .iter()
+                .zip(&other.functions)
+                .all(|((a, m, f), (b, n, g))| a == b && m == n && Arc::ptr_eq(f, g))
+    }
+}
+
+impl Eq for FunctionRegistry {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn add(input: &Value, args: &[Value]) -> Result<Value, JQError> {
+        let sum = args
+            .iter()
+            .chain([input])
+            .filter_map(Value::as_i64)
+            .sum::<i64>();
+        Ok(Value::from(sum))
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = FunctionRegistry::new()
+            .with_function("add", 1, add)
+            .with_function("add", 2, add)
+            .with_function("zero", 0, |_: &Value, _: &[Value]| Ok(json!(0)));
+        assert_eq!(registry.signatures(), [("add", 1), ("add", 2), ("zero", 0)]);
+        let function = registry.get("add", 2).expect("missing");
+        assert_eq!(
+            function
+                .call(&json!(1), &[json!(2), json!(3)])
+                .expect("failed"),
+            json!(6)
+        );
+        assert!(registry.get("add", 0).is_none());
+        assert_eq!(
+            format!("{:?}", registry),
+            r#"FunctionRegistry { functions: ["add/1", "add/2", "zero/0"] }"#
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let registry = FunctionRegistry::new()
+            .with_function("f", 0, |_: &Value, _: &[Value]| Ok(json!(1)))
+            .with_function("f", 0, |_: &Value, _: &[Value]| Ok(json!(2)));
+        assert_eq!(registry.signatures(), [("f", 0)]);
+        let result = registry
+            .get("f", 0)
+            .expect("missing")
+            .call(&Value::Null, &[]);
+        assert_eq!(result.expect("failed"), json!(2));
+
+        assert_eq!(registry, registry.clone());
+        assert_ne!(registry, FunctionRegistry::new());
+    }
+}
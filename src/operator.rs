@@ -0,0 +1,411 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{value_name, JQError};
+
+/// Binary operators that combine the results of two [expressions](crate::Expr)
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum Operator {
+    /// `+`
+    Add,
+    /// `-`
+    Subtract,
+    /// `*`
+    Multiply,
+    /// `/`
+    Divide,
+    /// `%`
+    Modulo,
+    /// `==`
+    Equal,
+    /// `!=`
+    NotEqual,
+    /// `<`
+    Less,
+    /// `<=`
+    LessEqual,
+    /// `>`
+    Greater,
+    /// `>=`
+    GreaterEqual,
+}
+
+/// Describe a value the way jq does in error messages, ie: `number (1)`
+fn describe(value: &Value) -> String {
+    format!("{} ({})", value_name(value), value)
+}
+
+/// Convert an f64 result back to a [Value], preferring integers when the
+/// result has no fractional part, so that `4 / 2` yields `2` rather than `2.0`.
+fn number_value(n: f64) -> Value {
+    if n.is_finite() && n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
+
+/// The position of a value's type in jq's ordering
+fn type_order(value: &Value) -> u8 {
+    match value {
+        Value::Null => 0,
+        Value::Bool(false) => 1,
+        Value::Bool(true) => 2,
+        Value::Number(_) => 3,
+        Value::String(_) => 4,
+        Value::Array(_) => 5,
+        Value::Object(_) => 6,
+    }
+}
+
+/// Compare two values using jq's total ordering across types:
+/// `null < false < true < numbers < strings < arrays < objects`.
+///
+/// Arrays are compared element by element.  Objects first compare their sorted
+/// sets of keys, and then their values key by key.
+pub fn compare_values(lhs: &Value, rhs: &Value) -> Ordering {
+    match (lhs, rhs) {
+        (Value::Number(l), Value::Number(r)) => l
+            .as_f64()
+            .unwrap()
+            .partial_cmp(&r.as_f64().unwrap())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(l), Value::String(r)) => l.cmp(r),
+        (Value::Array(l), Value::Array(r)) => {
+            for (l_val, r_val) in l.iter().zip(r.iter()) {
+                let ord = compare_values(l_val, r_val);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            l.len().cmp(&r.len())
+        }
+        (Value::Object(l), Value::Object(r)) => {
+            let mut l_keys: Vec<&String> = l.keys().collect();
+            let mut r_keys: Vec<&String> = r.keys().collect();
+            l_keys.sort();
+            r_keys.sort();
+            let ord = l_keys.cmp(&r_keys);
+            if ord != Ordering::Equal {
+                return ord;
+            }
+            for key in l_keys {
+                let ord = compare_values(&l[key], &r[key]);
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            Ordering::Equal
+        }
+        _ => type_order(lhs).cmp(&type_order(rhs)),
+    }
+}
+
+/// Recursively merge `rhs` into `lhs`.  Keys in `rhs` win, unless both values
+/// are objects, in which case they are merged.
+fn deep_merge(lhs: &Map<String, Value>, rhs: &Map<String, Value>) -> Map<String, Value> {
+    let mut result = lhs.clone();
+    for (key, r_val) in rhs {
+        let merged = match (result.get(key), r_val) {
+            (Some(Value::Object(l_obj)), Value::Object(r_obj)) => {
+                Value::Object(deep_merge(l_obj, r_obj))
+            }
+            _ => r_val.clone(),
+        };
+        result.insert(key.to_owned(), merged);
+    }
+    result
+}
+
+/// Repeat a string.  Mirrors jq, which returns `null` when the count is not positive.
+fn repeat_string(s: &str, n: f64) -> Value {
+    if n <= 0.0 {
+        return Value::Null;
+    }
+    Value::from(s.repeat((n - 1.0) as usize + 1))
+}
+
+impl Operator {
+    /// The verb jq uses when reporting a type mismatch.
+    fn verb(&self) -> &'static str {
+        match self {
+            Operator::Add => "added",
+            Operator::Subtract => "subtracted",
+            Operator::Multiply => "multiplied",
+            Operator::Divide => "divided",
+            Operator::Modulo => "divided",
+            _ => "compared",
+        }
+    }
+
+    fn mismatch(&self, lhs: &Value, rhs: &Value) -> JQError {
+        JQError::OperandMismatch(describe(lhs), describe(rhs), self.verb().to_string())
+    }
+
+    /// Apply the operator, using jq's polymorphic rules:
+    /// * `+`: numbers are added, strings and arrays are concatenated, objects
+    ///   are merged, and `null` is the identity.
+    /// * `-`: numbers are subtracted, and arrays have all occurrences of the
+    ///   right hand elements removed.
+    /// * `*`: numbers are multiplied, strings are repeated, and objects are
+    ///   merged recursively.
+    /// * `/`: numbers are divided, and strings are split.
+    /// * `%`: numbers are truncated to integers and the remainder returned.
+    ///
+    /// Comparison operators always return a boolean, using [compare_values].
+    pub fn apply(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        let ord = || compare_values(lhs, rhs);
+        match self {
+            Operator::Add => self.add(lhs, rhs),
+            Operator::Subtract => self.subtract(lhs, rhs),
+            Operator::Multiply => self.multiply(lhs, rhs),
+            Operator::Divide => self.divide(lhs, rhs),
+            Operator::Modulo => self.modulo(lhs, rhs),
+            Operator::Equal => Ok(Value::Bool(ord() == Ordering::Equal)),
+            Operator::NotEqual => Ok(Value::Bool(ord() != Ordering::Equal)),
+            Operator::Less => Ok(Value::Bool(ord() == Ordering::Less)),
+            Operator::LessEqual => Ok(Value::Bool(ord() != Ordering::Greater)),
+            Operator::Greater => Ok(Value::Bool(ord() == Ordering::Greater)),
+            Operator::GreaterEqual => Ok(Value::Bool(ord() != Ordering::Less)),
+        }
+    }
+
+    fn add(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        match (lhs, rhs) {
+            (Value::Null, _) => Ok(rhs.clone()),
+            (_, Value::Null) => Ok(lhs.clone()),
+            (Value::Number(l), Value::Number(r)) => match (l.as_i64(), r.as_i64()) {
+                (Some(l), Some(r)) if l.checked_add(r).is_some() => Ok(Value::from(l + r)),
+                _ => Ok(number_value(l.as_f64().unwrap() + r.as_f64().unwrap())),
+            },
+            (Value::String(l), Value::String(r)) => Ok(Value::from(format!("{}{}", l, r))),
+            (Value::Array(l), Value::Array(r)) => {
+                let mut result = l.clone();
+                result.extend(r.iter().cloned());
+                Ok(Value::from(result))
+            }
+            (Value::Object(l), Value::Object(r)) => {
+                let mut result = l.clone();
+                for (key, val) in r {
+                    result.insert(key.to_owned(), val.clone());
+                }
+                Ok(Value::Object(result))
+            }
+            _ => Err(self.mismatch(lhs, rhs)),
+        }
+    }
+
+    fn subtract(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        match (lhs, rhs) {
+            (Value::Number(l), Value::Number(r)) => match (l.as_i64(), r.as_i64()) {
+                (Some(l), Some(r)) if l.checked_sub(r).is_some() => Ok(Value::from(l - r)),
+                _ => Ok(number_value(l.as_f64().unwrap() - r.as_f64().unwrap())),
+            },
+            (Value::Array(l), Value::Array(r)) => Ok(Value::from(
+                l.iter()
+                    .filter(|v| !r.contains(v))
+                    .cloned()
+                    .collect::<Vec<Value>>(),
+            )),
+            _ => Err(self.mismatch(lhs, rhs)),
+        }
+    }
+
+    fn multiply(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        match (lhs, rhs) {
+            (Value::Number(l), Value::Number(r)) => match (l.as_i64(), r.as_i64()) {
+                (Some(l), Some(r)) if l.checked_mul(r).is_some() => Ok(Value::from(l * r)),
+                _ => Ok(number_value(l.as_f64().unwrap() * r.as_f64().unwrap())),
+            },
+            (Value::String(s), Value::Number(n)) | (Value::Number(n), Value::String(s)) => {
+                Ok(repeat_string(s, n.as_f64().unwrap()))
+            }
+            (Value::Object(l), Value::Object(r)) => Ok(Value::Object(deep_merge(l, r))),
+            _ => Err(self.mismatch(lhs, rhs)),
+        }
+    }
+
+    fn divide(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        match (lhs, rhs) {
+            (Value::Number(l), Value::Number(r)) => {
+                let divisor = r.as_f64().unwrap();
+                if divisor == 0.0 {
+                    return Err(JQError::DivisionByZero(describe(lhs), describe(rhs)));
+                }
+                Ok(number_value(l.as_f64().unwrap() / divisor))
+            }
+            (Value::String(l), Value::String(r)) => {
+                if l.is_empty() {
+                    return Ok(Value::from(Vec::<Value>::new()));
+                }
+                Ok(Value::from(
+                    l.split(r.as_str()).map(Value::from).collect::<Vec<Value>>(),
+                ))
+            }
+            _ => Err(self.mismatch(lhs, rhs)),
+        }
+    }
+
+    fn modulo(&self, lhs: &Value, rhs: &Value) -> Result<Value, JQError> {
+        match (lhs, rhs) {
+            (Value::Number(l), Value::Number(r)) => {
+                let divisor = r.as_f64().unwrap() as i64;
+                if divisor == 0 {
+                    return Err(JQError::DivisionByZero(describe(lhs), describe(rhs)));
+                }
+                Ok(Value::from(
+                    (l.as_f64().unwrap() as i64).wrapping_rem(divisor),
+                ))
+            }
+            _ => Err(self.mismatch(lhs, rhs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_add() {
+        let add = Operator::Add;
+        assert_eq!(add.apply(&json!(1), &json!(2)).unwrap(), json!(3));
+        assert_eq!(add.apply(&json!(1), &json!(0.5)).unwrap(), json!(1.5));
+        assert_eq!(add.apply(&json!(null), &json!(2)).unwrap(), json!(2));
+        assert_eq!(add.apply(&json!("a"), &json!(null)).unwrap(), json!("a"));
+        assert_eq!(add.apply(&json!("a"), &json!("b")).unwrap(), json!("ab"));
+        assert_eq!(
+            add.apply(&json!([1, 2]), &json!([2, 3])).unwrap(),
+            json!([1, 2, 2, 3])
+        );
+        assert_eq!(
+            add.apply(&json!({"a": 1, "b": {"c": 1}}), &json!({"b": {"d": 2}}))
+                .unwrap(),
+            json!({"a": 1, "b": {"d": 2}})
+        );
+        assert!(add.apply(&json!(1), &json!("a")).is_err());
+    }
+
+    #[test]
+    fn test_subtract() {
+        let sub = Operator::Subtract;
+        assert_eq!(sub.apply(&json!(4), &json!(1)).unwrap(), json!(3));
+        assert_eq!(
+            sub.apply(&json!(["a", "b", "a", "c"]), &json!(["a"]))
+                .unwrap(),
+            json!(["b", "c"])
+        );
+        assert!(sub.apply(&json!("ab"), &json!("b")).is_err());
+    }
+
+    #[test]
+    fn test_multiply() {
+        let mul = Operator::Multiply;
+        assert_eq!(mul.apply(&json!(4), &json!(2.5)).unwrap(), json!(10));
+        assert_eq!(mul.apply(&json!("ab"), &json!(3)).unwrap(), json!("ababab"));
+        assert_eq!(mul.apply(&json!(2), &json!("x")).unwrap(), json!("xx"));
+        assert_eq!(mul.apply(&json!("ab"), &json!(0)).unwrap(), json!(null));
+        assert_eq!(
+            mul.apply(&json!({"a": {"b": 1}}), &json!({"a": {"c": 2}}))
+                .unwrap(),
+            json!({"a": {"b": 1, "c": 2}})
+        );
+        assert!(mul.apply(&json!([1]), &json!(2)).is_err());
+    }
+
+    #[test]
+    fn test_divide() {
+        let div = Operator::Divide;
+        assert_eq!(div.apply(&json!(4), &json!(2)).unwrap(), json!(2));
+        assert_eq!(div.apply(&json!(1), &json!(4)).unwrap(), json!(0.25));
+        assert_eq!(
+            div.apply(&json!("a, b,c"), &json!(", ")).unwrap(),
+            json!(["a", "b,c"])
+        );
+        let err = div.apply(&json!(1), &json!(0)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "number (1) and number (0) cannot be divided because the divisor is zero"
+        );
+    }
+
+    #[test]
+    fn test_modulo() {
+        let rem = Operator::Modulo;
+        assert_eq!(rem.apply(&json!(5), &json!(2)).unwrap(), json!(1));
+        assert_eq!(rem.apply(&json!(5.5), &json!(2)).unwrap(), json!(1));
+        assert_eq!(rem.apply(&json!(-5), &json!(2)).unwrap(), json!(-1));
+        assert!(rem.apply(&json!(5), &json!(0)).is_err());
+        let err = rem.apply(&json!("a"), &json!(2)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "string (\"a\") and number (2) cannot be divided"
+        );
+    }
+
+    #[test]
+    fn test_compare_across_types() {
+        let ordered = [
+            json!(null),
+            json!(false),
+            json!(true),
+            json!(-1),
+            json!(2.5),
+            json!(""),
+            json!("a"),
+            json!([]),
+            json!([1, 2]),
+            json!([2]),
+            json!({}),
+            json!({"a": 2}),
+            json!({"a": 1, "b": 1}),
+            json!({"b": 0}),
+        ];
+        for (idx, lhs) in ordered.iter().enumerate() {
+            for rhs in &ordered[idx + 1..] {
+                assert_eq!(
+                    compare_values(lhs, rhs),
+                    Ordering::Less,
+                    "{} < {}",
+                    lhs,
+                    rhs
+                );
+                assert_eq!(compare_values(rhs, lhs), Ordering::Greater);
+            }
+            assert_eq!(compare_values(lhs, lhs), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        assert_eq!(
+            Operator::Equal.apply(&json!(1), &json!(1.0)).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            Operator::NotEqual.apply(&json!("a"), &json!("a")).unwrap(),
+            json!(false)
+        );
+        assert_eq!(
+            Operator::Less.apply(&json!(null), &json!(false)).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            Operator::LessEqual.apply(&json!(2), &json!(2)).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            Operator::Greater.apply(&json!("b"), &json!("a")).unwrap(),
+            json!(true)
+        );
+        assert_eq!(
+            Operator::GreaterEqual
+                .apply(&json!([1]), &json!({}))
+                .unwrap(),
+            json!(false)
+        );
+    }
+}
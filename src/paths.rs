@@ -0,0 +1,519 @@
+/// Path expressions, which report where each of their results came from.
+///
+/// `path(f)`, `paths` and the other path builtins evaluate `f` in path mode,
+/// which tracks the path from the input to each result as an array of
+/// object keys, array indexes and slices, ie: `path(.a[0])` is `["a", 0]`.
+/// Only the parts of a query that select from their input, such as `.a`,
+/// `.[]`, `..`, `select(f)` and `getpath(p)`, have paths.
+/// Anything that builds a new value, such as `1` or `.a + 1`, is an error.
+use serde_json::Value;
+
+use crate::{
+    bind_alternatives, fn_recurse_paths, getpath, is_truthy, library_env, limit_count, module_root,
+    query_function, query_iter, query_label, query_params, query_patterns, recurse_with, Callable,
+    Env, Expr, Function, IndexType, Input, JQError, Pattern, RangeType, Results, ValueIter,
+};
+
+/// A path, and the value that it leads to
+pub type PathValue<'a> = (Vec<Value>, Input<'a>);
+
+/// A lazy stream of paths, and the values they lead to
+pub type PathIter<'a> = Results<'a, PathValue<'a>>;
+
+fn once_path<'a>(result: Result<PathValue<'a>, JQError>) -> PathIter<'a> {
+    Box::new(std::iter::once(result))
+}
+
+/// The results of an expression that has no paths.  Each value is an error.
+fn invalid<'a>(results: ValueIter<'a>) -> PathIter<'a> {
+    Box::new(results.map(|value| Err(JQError::InvalidPathExpression(value?.to_string()))))
+}
+
+/// The value at `path` in the input.  A borrowed input stays borrowed.
+fn child<'a>(input: &Input<'a>, path: &[Value]) -> Result<Input<'a>, JQError> {
+    Ok(match input {
+        Input::Borrowed(value) => Input::from(getpath(value, path)?),
+        Input::Owned(value) => Input::from(getpath(value, path)?.into_owned()),
+    })
+}
+
+/// One path for each key, if the input is an object or array that they can
+/// index.  Null has a null at every key, and like [query_index], indexing a
+/// boolean or number is the value itself.
+fn path_keys<'a>(input: Input<'a>, keys: Vec<Value>, silent: bool) -> PathIter<'a> {
+    if input.is_boolean() || input.is_number() {
+        return once_path(Ok((Vec::new(), input)));
+    }
+    let mismatch = keys.iter().find(|key| {
+        !matches!(
+            (&*input, key),
+            (Value::Null, _)
+                | (Value::Object(_), Value::String(_))
+                | (Value::Array(_), Value::Number(_))
+        )
+    });
+    match mismatch {
+        Some(_) if silent => Box::new(std::iter::empty()),
+        Some(key) => once_path(Err(JQError::IndexMismatch(
+            crate::value_name(&input).to_string(),
+            key.to_string(),
+        ))),
+        None => Box::new(keys.into_iter().map(move |key| {
+            let value = child(&input, std::slice::from_ref(&key))?;
+            Ok((vec![key], value))
+        })),
+    }
+}
+
+/// `.name` or `.["name"]`.  Like [query_ident](crate::query_ident), indexing
+/// anything but an object is an error.
+fn path_ident<'a>(input: Input<'a>, name: &str, silent: bool) -> PathIter<'a> {
+    match &*input {
+        Value::Object(_) => path_keys(input, vec![Value::from(name)], silent),
+        _ if silent => Box::new(std::iter::empty()),
+        value => once_path(Err(JQError::IdentMismatch(format!(
+            "cannot index {}",
+            crate::value_name(value)
+        )))),
+    }
+}
+
+/// `.["name"]`, or `.[0]` and `.[0, 1]`
+fn path_index<'a>(input: Input<'a>, index: &IndexType) -> PathIter<'a> {
+    if let Ok((name, silent)) = index.as_identifier() {
+        if input.is_object() {
+            if let Err(error) = crate::query_index(&input, index) {
+                return once_path(Err(error));
+            }
+        }
+        return path_keys(input, vec![Value::from(name)], silent);
+    }
+    match index.as_index() {
+        Ok((indexes, silent)) => {
+            let keys = indexes.into_iter().map(Value::from).collect();
+            path_keys(input, keys, silent)
+        }
+        Err(error) => once_path(Err(error)),
+    }
+}
+
+/// `.[start:end]`, the slice of an array or string.  As in jq, the path key
+/// is the slice itself, `{"start": start, "end": end}`, with a null for an
+/// omitted bound.  Like [query_range], a boolean or number is the value
+/// itself.
+fn path_slice<'a>(input: Input<'a>, range: &'a RangeType) -> PathIter<'a> {
+    match &*input {
+        Value::Null | Value::Array(_) | Value::String(_) => {
+            let mut slice = serde_json::Map::new();
+            slice.insert("start".to_string(), Value::from(range.start()));
+            slice.insert("end".to_string(), Value::from(range.end()));
+            let key = Value::Object(slice);
+            once_path(child(&input, std::slice::from_ref(&key)).map(|value| (vec![key], value)))
+        }
+        Value::Object(_) => invalid(Box::new(std::iter::once_with(move || {
+            let value = crate::query_range(&input, range)?.remove(0).into_owned();
+            Ok(Input::from(value))
+        }))),
+        _ => once_path(Ok((Vec::new(), input))),
+    }
+}
+
+/// `.[]`, each value of an array or object, or `.[start:end]`, a slice.  Like
+/// [query_range], iterating anything else is an error.
+fn path_range<'a>(input: Input<'a>, range: &'a RangeType) -> PathIter<'a> {
+    if !range.is_empty() {
+        return path_slice(input, range);
+    }
+    let keys: Vec<Value> = match &*input {
+        Value::Array(array) => (0..array.len()).map(Value::from).collect(),
+        Value::Object(object) => object.keys().map(|key| Value::from(key.as_str())).collect(),
+        value => {
+            return once_path(Err(JQError::CannotIterate(
+                crate::value_name(value).to_string(),
+            )))
+        }
+    };
+    path_keys(input, keys, false)
+}
+
+/// `..`, the input and everything in it
+pub(crate) fn path_recurse<'a>(input: Input<'a>) -> PathIter<'a> {
+    match input {
+        Input::Borrowed(value) => Box::new(
+            fn_recurse_paths(value).map(|(path, value)| Ok((path, Input::Borrowed(value)))),
+        ),
+        Input::Owned(value) => {
+            let paths: Vec<_> = fn_recurse_paths(&value)
+                .map(|(path, value)| Ok((path, Input::from(value.clone()))))
+                .collect();
+            Box::new(paths.into_iter())
+        }
+    }
+}
+
+/// `lhs | rhs`, each path of `rhs` follows the path of `lhs` that it started from
+fn path_pipe<'a>(input: Input<'a>, lhs: &'a Expr, rhs: &'a Expr, env: &Env<'a>) -> PathIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_paths(input, lhs, &env.clone()).flat_map(move |result| -> PathIter<'a> {
+            let (path, value) = match result {
+                Ok(result) => result,
+                Err(error) => return once_path(Err(error)),
+            };
+            Box::new(query_paths(value, rhs, &env).map(move |result| {
+                result.map(|(rest, value)| {
+                    let mut full = path.clone();
+                    full.extend(rest);
+                    (full, value)
+                })
+            }))
+        }),
+    )
+}
+
+/// `try body catch handler`, the paths of `body` up to its first error.  The
+/// results of `handler` are not part of the input, so they are errors.
+fn path_try<'a>(
+    input: Input<'a>,
+    body: &'a Expr,
+    handler: Option<&'a Expr>,
+    env: &Env<'a>,
+) -> PathIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_paths(input, body, &env.clone())
+            .scan(false, move |failed, result| {
+                if *failed {
+                    return None;
+                }
+                Some(match (result, handler) {
+                    (Ok(result), _) => once_path(Ok(result)),
+                    (Err(error), _) if error.is_break() => {
+                        *failed = true;
+                        once_path(Err(error))
+                    }
+                    (Err(error), Some(handler)) => {
+                        *failed = true;
+                        invalid(query_iter(Input::from(error.into_value()), handler, &env))
+                    }
+                    (Err(_), None) => {
+                        *failed = true;
+                        Box::new(std::iter::empty())
+                    }
+                })
+            })
+            .flatten(),
+    )
+}
+
+/// `lhs // rhs`, the paths of the truthy results of `lhs`, or the paths of `rhs`
+fn path_alternative<'a>(
+    input: Input<'a>,
+    lhs: &'a Expr,
+    rhs: &'a Expr,
+    env: &Env<'a>,
+) -> PathIter<'a> {
+    let found = std::rc::Rc::new(std::cell::Cell::new(false));
+    let lhs_found = found.clone();
+    let rhs_input = input.clone();
+    let env = env.clone();
+    Box::new(
+        path_try(input, lhs, None, &env)
+            .filter(move |result| match result {
+                Ok((_, value)) if is_truthy(value) => {
+                    lhs_found.set(true);
+                    true
+                }
+                Ok(_) => false,
+                Err(_) => true,
+            })
+            .chain(
+                std::iter::once_with(move || -> PathIter<'a> {
+                    if found.get() {
+                        Box::new(std::iter::empty())
+                    } else {
+                        query_paths(rhs_input, rhs, &env)
+                    }
+                })
+                .flatten(),
+            ),
+    )
+}
+
+/// `source as patterns | body`, the paths of `body` for each binding
+fn path_bind<'a>(
+    input: Input<'a>,
+    source: &'a Expr,
+    patterns: &'a [Pattern],
+    body: &'a Expr,
+    env: &Env<'a>,
+) -> PathIter<'a> {
+    let env = bind_alternatives(env, patterns);
+    Box::new(
+        query_iter(input.clone(), source, &env.clone()).flat_map(move |value| match value {
+            Ok(value) => {
+                let body = move |input, env: &Env<'a>| query_paths(input, body, env);
+                query_patterns(input.clone(), value, patterns, body, &env)
+            }
+            Err(error) => once_path(Err(error)),
+        }),
+    )
+}
+
+/// The paths of a builtin.  Those that select from their input have paths,
+/// and the rest are errors.
+fn path_function<'a>(input: Input<'a>, function: &'a Function, env: &Env<'a>) -> PathIter<'a> {
+    match function {
+        Function::Recurse => path_recurse(input),
+        Function::RecurseWith(filter) => {
+            let env = env.clone();
+            recurse_with((Vec::new(), input), move |(path, value): &PathValue<'a>| {
+                let path = path.clone();
+                Box::new(query_paths(value.clone(), filter, &env).map(move |result| {
+                    let (mut child, value) = result?;
+                    child.splice(0..0, path.iter().cloned());
+                    Ok((child, value))
+                }))
+            })
+        }
+        Function::Select(filter) => Box::new(query_iter(input.clone(), filter, env).filter_map(
+            move |value| match value {
+                Ok(value) if is_truthy(&value) => Some(Ok((Vec::new(), input.clone()))),
+                Ok(_) => None,
+                Err(error) => Some(Err(error)),
+            },
+        )),
+        Function::First(filter) => Box::new(query_paths(input, filter, env).take(1)),
+        Function::Limit(count, filter) => {
+            let env = env.clone();
+            Box::new(query_iter(input.clone(), count, &env.clone()).flat_map(
+                move |count| -> PathIter<'a> {
+                    let count = match count.and_then(|count| limit_count(&count)) {
+                        Ok(count) => count,
+                        Err(error) => return once_path(Err(error)),
+                    };
+                    let results = query_paths(input.clone(), filter, &env);
+                    match count {
+                        Some(count) => Box::new(results.take(count)),
+                        None => results,
+                    }
+                },
+            ))
+        }
+        Function::GetPath(paths) => {
+            Box::new(query_iter(input.clone(), paths, env).map(move |path| {
+                let path = path?;
+                match &*path {
+                    Value::Array(keys) => Ok((keys.clone(), child(&input, keys)?)),
+                    path => Err(JQError::InvalidArgument(
+                        crate::value_name(path).to_string(),
+                        "getpath".to_string(),
+                    )),
+                }
+            }))
+        }
+        _ => invalid(query_function(input, function, env)),
+    }
+}
+
+/// Call a user defined function or a filter parameter in path mode.  Native
+/// functions build new values, so they have no paths.
+fn path_call<'a>(
+    input: Input<'a>,
+    call: &'a Expr,
+    name: &'a str,
+    args: &'a [Expr],
+    env: &Env<'a>,
+) -> PathIter<'a> {
+    if let Err(error) = env.check_depth() {
+        return once_path(Err(error));
+    }
+    match env.function(name, args.len()) {
+        Some(Callable::Definition(definition, scope)) => query_params(
+            input,
+            definition,
+            definition.params(),
+            args,
+            scope,
+            env,
+            query_paths,
+        ),
+        Some(Callable::Closure(filter, scope)) => query_paths(input, filter, &scope),
+        Some(Callable::Native(_)) => invalid(query_iter(input, call, env)),
+        None => once_path(Err(JQError::UndefinedFunction(format!(
+            "{}/{}",
+            name,
+            args.len()
+        )))),
+    }
+}
+
+/// Lazily evaluate an expression in path mode.  Each result is paired with
+/// its path from the input.
+pub fn query_paths<'a>(input: Input<'a>, expr: &'a Expr, env: &Env<'a>) -> PathIter<'a> {
+    match expr {
+        Expr::Identity => once_path(Ok((Vec::new(), input))),
+        Expr::Ident(name, silent) => path_ident(input, name, *silent),
+        Expr::Index(index) => path_index(input, index),
+        Expr::Range(range) => path_range(input, range),
+        Expr::Function(function) => path_function(input, function, env),
+        Expr::Alternative(lhs, rhs) => path_alternative(input, lhs, rhs, env),
+        Expr::Pipe(lhs, rhs) => path_pipe(input, lhs, rhs, env),
+        Expr::Comma(exprs) => {
+            let env = env.clone();
+            Box::new(
+                exprs
+                    .iter()
+                    .flat_map(move |expr| query_paths(input.clone(), expr, &env)),
+            )
+        }
+        Expr::If(cond, then, otherwise) => {
+            let env = env.clone();
+            Box::new(query_iter(input.clone(), cond, &env.clone()).flat_map(
+                move |value| match value {
+                    Ok(value) if is_truthy(&value) => query_paths(input.clone(), then, &env),
+                    Ok(_) => query_paths(input.clone(), otherwise, &env),
+                    Err(error) => once_path(Err(error)),
+                },
+            ))
+        }
+        Expr::Try(body, handler) => path_try(input, body, handler.as_deref(), env),
+        Expr::Label(name, body) => query_label(input, name, body, env, query_paths),
+        Expr::Bind(source, patterns, body) => path_bind(input, source, patterns, body, env),
+        Expr::Define(definition, rest) => query_paths(input, rest, &env.define(definition)),
+        Expr::Call(name, args) => path_call(input, expr, name, args, env),
+        Expr::Import(library, alias, rest) => {
+            let module = library_env(library, module_root(env));
+            query_paths(input, rest, &env.import(alias, module))
+        }
+        Expr::Break(_)
+        | Expr::Literal(_)
+        | Expr::Variable(_)
+        | Expr::Operation(..)
+        | Expr::And(..)
+        | Expr::Or(..)
+        | Expr::Array(_)
+        | Expr::Object(_)
+        | Expr::Reduce(..)
+        | Expr::Foreach(..) => invalid(query_iter(input, expr, env)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use serde_json::json;
+
+    fn paths(value: &Value, query: &str) -> Result<Vec<(Value, Value)>, JQError> {
+        let expr = parse(query).expect("failed to parse query");
+        query_paths(Input::from(value), &expr, &Env::new())
+            .map(|result| result.map(|(path, value)| (Value::from(path), value.into_value())))
+            .collect()
+    }
+
+    #[test]
+    fn test_query_paths() {
+        let value = json!({"a": [{"b": 1}, {"b": 2}], "c": null});
+        assert_eq!(
+            paths(&value, ".a[0].b").expect("failed"),
+            [(json!(["a", 0, "b"]), json!(1))]
+        );
+        assert_eq!(
+            paths(&value, r#".["c"], .a[-1], .a[0,5]"#).expect("failed"),
+            [
+                (json!(["c"]), Value::Null),
+                (json!(["a", -1]), json!({"b": 2})),
+                (json!(["a", 0]), json!({"b": 1})),
+                (json!(["a", 5]), Value::Null)
+            ]
+        );
+        assert_eq!(
+            paths(&value, ".a[1:], .c[]?, (.a[0].b | .[]?), .a[0][]").expect("failed"),
+            [
+                (json!(["a", {"start": 1, "end": null}]), json!([{"b": 2}])),
+                (json!(["a", 0, "b"]), json!(1))
+            ]
+        );
+        assert!(matches!(
+            paths(&value, ".c[]"),
+            Err(JQError::CannotIterate(name)) if name == "null"
+        ));
+        assert_eq!(
+            paths(&json!([1, [2]]), "..").expect("failed"),
+            [
+                (json!([]), json!([1, [2]])),
+                (json!([0]), json!(1)),
+                (json!([1]), json!([2])),
+                (json!([1, 0]), json!(2))
+            ]
+        );
+        assert_eq!(
+            paths(&json!([{"b": 1}, [2]]), ".[] | .b?").expect("failed"),
+            [(json!([0, "b"]), json!(1))]
+        );
+        assert_eq!(
+            paths(&json!({"a": {"a": {"b": 1}}}), "recurse(.a | select(.))").expect("failed"),
+            [
+                (json!([]), json!({"a": {"a": {"b": 1}}})),
+                (json!(["a"]), json!({"a": {"b": 1}})),
+                (json!(["a", "a"]), json!({"b": 1}))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_paths_control() {
+        let value = json!({"a": [1, null, 3], "b": false});
+        assert_eq!(
+            paths(&value, ".a[] | select(. != null)").expect("failed"),
+            [(json!(["a", 0]), json!(1)), (json!(["a", 2]), json!(3))]
+        );
+        assert_eq!(
+            paths(
+                &value,
+                r#"if .b then .a else .b end, .b // .a[0], first(.a[]), getpath(["a", 2])"#
+            )
+            .expect("failed"),
+            [
+                (json!(["b"]), json!(false)),
+                (json!(["a", 0]), json!(1)),
+                (json!(["a", 0]), json!(1)),
+                (json!(["a", 2]), json!(3))
+            ]
+        );
+        assert_eq!(
+            paths(
+                &value,
+                r#"def second: .[1]; (.a | second), (.b as $b | .a[0])"#
+            )
+            .expect("failed"),
+            [(json!(["a", 1]), Value::Null), (json!(["a", 0]), json!(1))]
+        );
+        assert_eq!(
+            paths(
+                &value,
+                r#"label $out | .a[] | if . == null then break $out else . end"#
+            )
+            .expect("failed"),
+            [(json!(["a", 0]), json!(1))]
+        );
+        assert_eq!(
+            paths(&value, r#"try (.a[0], error("x"), .b) catch ., .b"#)
+                .map_err(|error| error.to_string()),
+            Err("Invalid path expression with result \"x\"".to_string())
+        );
+        assert!(matches!(
+            paths(&value, r#".a | .[0], 1, .[1]"#),
+            Err(JQError::InvalidPathExpression(result)) if result == "1"
+        ));
+        assert!(matches!(
+            paths(&json!("abc"), r#".[]"#),
+            Err(JQError::CannotIterate(name)) if name == "string"
+        ));
+        assert_eq!(
+            paths(&json!("abc"), r#".[1:]"#).expect("failed"),
+            [(json!([{"start": 1, "end": null}]), json!("bc"))]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{query_iter, value_name, Env, Expr, Input, JQError, Scope};
+
+/// The left hand side of `source as pattern | body`.
+///
+/// A pattern either binds the whole value to a variable, or destructures an
+/// array or object and binds its parts:
+/// * `$x` : binds the value to `$x`
+/// * `[$a, $b]` : binds `.[0]` to `$a` and `.[1]` to `$b`
+/// * `{a: $a, "b": [$first], (.k): $v, $c}` : binds `.a` to `$a`, destructures
+///   `.b`, binds `.[.k]` to `$v` and `.c` to `$c`
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum Pattern {
+    /// `$name`
+    Variable(String),
+    /// `[p0, p1, ...]`, each pattern is matched against the element at the same index
+    Array(Vec<Pattern>),
+    /// `{key: pattern, ...}`, each key is evaluated against the value being
+    /// destructured, and the pattern is matched against the value at that key.
+    /// `$name` is stored as `("name", $name)`, and `$name: pattern` as both
+    /// `("name", $name)` and `("name", pattern)`.
+    Object(Vec<(Expr, Pattern)>),
+}
+
+/// The element of `value` at `key`, or `None` if the element does not exist.
+/// Indexing `null` always succeeds.
+fn element<'v>(value: &'v Value, key: &Value) -> Result<Option<&'v Value>, JQError> {
+    match (value, key) {
+        (Value::Null, _) => Ok(None),
+        (Value::Array(array), Value::Number(index)) => Ok(index
+            .as_u64()
+            .and_then(|index| array.get(usize::try_from(index).ok()?))),
+        (Value::Object(object), Value::String(key)) => Ok(object.get(key)),
+        (value, Value::String(key)) => Err(JQError::IndexMismatch(
+            value_name(value).to_string(),
+            format!("{:?}", key),
+        )),
+        (value, key) => Err(JQError::IndexMismatch(
+            value_name(value).to_string(),
+            value_name(key).to_string(),
+        )),
+    }
+}
+
+/// `value[key]`, borrowing from `value` when possible.  A missing element is `null`.
+fn lookup<'a>(value: &Input<'a>, key: &Value) -> Result<Input<'a>, JQError> {
+    Ok(match value {
+        Input::Borrowed(value) => match element(value, key)? {
+            Some(element) => Input::Borrowed(element),
+            None => Input::from(Value::Null),
+        },
+        Input::Owned(value) => Input::from(element(value, key)?.cloned().unwrap_or_default()),
+    })
+}
+
+impl Pattern {
+    /// The names of every variable bound by the pattern, in order
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            Pattern::Variable(name) => vec![name.as_str()],
+            Pattern::Array(patterns) => patterns.iter().flat_map(Pattern::variables).collect(),
+            Pattern::Object(entries) => entries
+                .iter()
+                .flat_map(|(_, pattern)| pattern.variables())
+                .collect(),
+        }
+    }
+
+    /// Bind the parts of `value` to the variables of the pattern.  A computed
+    /// key that produces several results binds once per result, so a new
+    /// environment is returned for each combination.
+    pub fn destructure<'a>(
+        &'a self,
+        value: Input<'a>,
+        env: &Env<'a>,
+    ) -> Result<Vec<Env<'a>>, JQError> {
+        match self {
+            Pattern::Variable(name) => Ok(vec![env.bind(name, value)]),
+            Pattern::Array(patterns) => {
+                let mut envs = vec![env.clone()];
+                for (index, pattern) in patterns.iter().enumerate() {
+                    let element = lookup(&value, &Value::from(index))?;
+                    let mut next = Vec::new();
+                    for env in &envs {
+                        next.extend(pattern.destructure(element.clone(), env)?);
+                    }
+                    envs = next;
+                }
+                Ok(envs)
+            }
+            Pattern::Object(entries) => {
+                let mut envs = vec![env.clone()];
+                for (key, pattern) in entries {
+                    let mut next = Vec::new();
+                    for env in &envs {
+                        for key in query_iter(value.clone(), key, env) {
+                            let element = lookup(&value, &*key?)?;
+                            next.extend(pattern.destructure(element, env)?);
+                        }
+                    }
+                    envs = next;
+                }
+                Ok(envs)
+            }
+        }
+    }
+
+    /// Resolve the key expressions of the pattern, and add its variables to
+    /// `scope`.  Keys may refer to variables bound earlier in the pattern.
+    pub(crate) fn resolve_scope(&mut self, scope: &mut Scope) -> Result<(), JQError> {
+        match self {
+            Pattern::Variable(name) => scope.push_variable(name),
+            Pattern::Array(patterns) => {
+                for pattern in patterns {
+                    pattern.resolve_scope(scope)?;
+                }
+            }
+            Pattern::Object(entries) => {
+                for (key, pattern) in entries {
+                    key.resolve_scope(scope)?;
+                    pattern.resolve_scope(scope)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn variable(name: &str) -> Pattern {
+        Pattern::Variable(name.to_string())
+    }
+
+    fn key(name: &str) -> Expr {
+        Expr::Literal(Value::from(name))
+    }
+
+    fn get(env: &Env, name: &str) -> Value {
+        env.get(name)
+            .map(|value| Value::clone(value))
+            .expect("not bound")
+    }
+
+    #[test]
+    fn test_variables() {
+        let pattern = Pattern::Object(vec![
+            (key("a"), variable("a")),
+            (key("b"), Pattern::Array(vec![variable("c"), variable("d")])),
+        ]);
+        assert_eq!(pattern.variables(), ["a", "c", "d"]);
+    }
+
+    #[test]
+    fn test_destructure_array() {
+        let value = json!([1, [2, 3]]);
+        let pattern = Pattern::Array(vec![
+            variable("a"),
+            Pattern::Array(vec![variable("b"), variable("c"), variable("d")]),
+        ]);
+        let envs = pattern
+            .destructure(Input::from(&value), &Env::new())
+            .expect("failed");
+        assert_eq!(envs.len(), 1);
+        assert_eq!(get(&envs[0], "a"), json!(1));
+        assert_eq!(get(&envs[0], "b"), json!(2));
+        assert_eq!(get(&envs[0], "c"), json!(3));
+        assert_eq!(get(&envs[0], "d"), Value::Null);
+    }
+
+    #[test]
+    fn test_destructure_object() {
+        let value = json!({"a": 1, "b": {"c": 2}});
+        let pattern = Pattern::Object(vec![
+            (key("a"), variable("a")),
+            (key("b"), Pattern::Object(vec![(key("c"), variable("c"))])),
+            (key("x"), variable("x")),
+        ]);
+        let envs = pattern
+            .destructure(Input::from(value), &Env::new())
+            .expect("failed");
+        assert_eq!(get(&envs[0], "a"), json!(1));
+        assert_eq!(get(&envs[0], "c"), json!(2));
+        assert_eq!(get(&envs[0], "x"), Value::Null);
+    }
+
+    #[test]
+    fn test_destructure_borrows() {
+        let value = json!({"a": [1, 2]});
+        let pattern = Pattern::Object(vec![(key("a"), variable("a"))]);
+        let envs = pattern
+            .destructure(Input::from(&value), &Env::new())
+            .expect("failed");
+        assert!(matches!(envs[0].get("a"), Some(Input::Borrowed(_))));
+    }
+
+    #[test]
+    fn test_destructure_mismatch() {
+        let pattern = Pattern::Array(vec![variable("a")]);
+        let error = pattern
+            .destructure(Input::from(json!({"a": 1})), &Env::new())
+            .expect_err("should fail");
+        assert_eq!(error.to_string(), "Cannot index object with number");
+
+        let pattern = Pattern::Object(vec![(key("a"), variable("a"))]);
+        let error = pattern
+            .destructure(Input::from(json!([1])), &Env::new())
+            .expect_err("should fail");
+        assert_eq!(error.to_string(), r#"Cannot index array with "a""#);
+    }
+}
@@ -1,14 +1,36 @@
 use super::Value;
 use crate::{
-    fn_has, fn_keys, fn_length, fn_recurse, from_range, Action, Block, Filter, Function, IndexType,
-    JQError, RangeType, Token,
+    deferred, fn_delpaths, fn_error, fn_first, fn_getpath, fn_has, fn_keys, fn_leaf_paths,
+    fn_length, fn_limit, fn_map, fn_map_values, fn_modulemeta, fn_not, fn_path, fn_paths,
+    fn_recurse, fn_recurse_with, fn_select, fn_setpath, from_range, from_results, once, predefined,
+    Callable, Definition, Env, Expr, Function, IndexType, Input, JQError, NativeFunction,
+    ObjectEntry, Operator, OperatorOperand, Param, Pattern, RangeType, ValueIter,
 };
 use serde_json::Map;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-fn value_name(value: &Value) -> &str {
+/// Returned for missing keys and indexes, so that they can be borrowed like any other value
+static NULL: Value = Value::Null;
+
+/// The results of evaluating an expression: values, or in path mode, paths
+/// and the values they lead to
+pub(crate) type Results<'a, T> = Box<dyn Iterator<Item = Result<T, JQError>> + 'a>;
+
+/// Evaluates an expression for its values, ie: [query_iter], or for its paths
+pub(crate) type Eval<'a, T> = fn(Input<'a>, &'a Expr, &Env<'a>) -> Results<'a, T>;
+
+/// Identifies each evaluation of a `label`, so that a `break` in a recursive
+/// function stops the label it was bound to, and not a newer one with the same name
+static NEXT_LABEL: AtomicUsize = AtomicUsize::new(0);
+
+/// The jq name for the type of a value
+pub(crate) fn value_name(value: &Value) -> &str {
     match value {
         Value::Null => "null",
-        Value::Bool(_) => "bool",
+        Value::Bool(_) => "boolean",
         Value::Number(_) => "number",
         Value::String(_) => "string",
         Value::Array(_) => "array",
@@ -16,9 +38,21 @@ fn value_name(value: &Value) -> &str {
     }
 }
 
+/// jq treats `false` and `null` as false, and every other value as true
+pub fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Null | Value::Bool(false))
+}
+
+/// Values selected from a borrowed input.  Values that are part of the input
+/// are borrowed, and only values that are constructed, such as substrings, are owned.
+pub type Selection<'v> = Vec<Cow<'v, Value>>;
+
 /// Called by [fn_has]
-pub fn query_object_ident(object: &Map<String, Value>, id: &str) -> Result<Vec<Value>, JQError> {
-    Ok(vec![object.get(id).unwrap_or(&Value::Null).clone()])
+pub fn query_object_ident<'v>(
+    object: &'v Map<String, Value>,
+    id: &str,
+) -> Result<Selection<'v>, JQError> {
+    Ok(vec![Cow::Borrowed(object.get(id).unwrap_or(&NULL))])
 }
 
 /// Traverse an object
@@ -28,17 +62,17 @@ pub fn query_object_ident(object: &Map<String, Value>, id: &str) -> Result<Vec<V
 ///
 /// An error is returned if the value is not an object or the key is not an
 /// object identifier-index.
-pub fn query_object_index(
-    object: &Map<String, Value>,
+pub fn query_object_index<'v>(
+    object: &'v Map<String, Value>,
     idx: &IndexType,
-) -> Result<Vec<Value>, JQError> {
+) -> Result<Selection<'v>, JQError> {
     let (index, silent) = idx.as_identifier()?;
     let value = object.get(index);
     match value {
-        Some(result) => Ok(vec![result.to_owned()]),
+        Some(result) => Ok(vec![Cow::Borrowed(result)]),
         None => {
             if silent {
-                Ok(Vec::<Value>::new())
+                Ok(Selection::new())
             } else {
                 Err(JQError::ObjectQuery(format!(
                     "bad object index {:?}",
@@ -54,19 +88,15 @@ pub fn query_object_index(
 /// as an array.  So `{"a":"a_val", "b":"b_val"}` is converted to `["a_val", "b_val"]
 ///
 /// Returns Err if the element is not an object
-fn query_object_range(
-    object: &Map<String, Value>,
+fn query_object_range<'v>(
+    object: &'v Map<String, Value>,
     range: &RangeType,
-) -> Result<Vec<Value>, JQError> {
+) -> Result<Selection<'v>, JQError> {
     if !range.is_empty() {
         return Err(JQError::ObjectQuery("Only empty range allowed".to_string()));
     }
-    let mut values = Vec::new();
     // return each element in the object
-    for (_, value) in object {
-        values.push(value.clone())
-    }
-    Ok(values)
+    Ok(object.values().map(Cow::Borrowed).collect())
 }
 
 /// Travers an array.
@@ -76,33 +106,36 @@ fn query_object_range(
 /// This function can be called in path traversal.
 ///
 /// An error is returned if the value is not an array or the key is not an index.
-pub fn query_array_index(array: &Vec<Value>, idx: &IndexType) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = Vec::new();
+pub fn query_array_index<'v>(
+    array: &'v [Value],
+    idx: &IndexType,
+) -> Result<Selection<'v>, JQError> {
+    let mut results = Selection::new();
     let (indexes, _silent) = idx.as_index()?;
     for mut idx in indexes {
         // If idx is negative, pull from end of array
         if idx < 0 {
             idx += array.len() as isize;
         }
-        if idx >= array.len() as isize {
+        if idx < 0 || idx >= array.len() as isize {
             // disregard silent.  Always push null.
             // This mirrors jq behavior
-            results.push(Value::Null);
+            results.push(Cow::Borrowed(&NULL));
             continue;
         }
-        results.push(array[idx as usize].to_owned())
+        results.push(Cow::Borrowed(&array[idx as usize]))
     }
     Ok(results)
 }
 
-fn query_string_index(input: &str, index: &IndexType) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = Vec::new();
+fn query_string_index<'v>(input: &str, index: &IndexType) -> Result<Selection<'v>, JQError> {
+    let mut results = Selection::new();
     let (indexes, silent) = index.as_index()?;
     for mut idx in indexes {
         if idx < 0 {
             idx += input.len() as isize;
         }
-        if idx >= input.len() as isize {
+        if idx < 0 || idx >= input.len() as isize {
             // idx is out of bounds
             if silent {
                 continue;
@@ -110,57 +143,46 @@ fn query_string_index(input: &str, index: &IndexType) -> Result<Vec<Value>, JQEr
                 return Err(JQError::ArrayQuery(format!("string index oob {}", idx)));
             }
         }
-        results.push(Value::from(
+        results.push(Cow::Owned(Value::from(
             input.get(idx as usize..idx as usize + 1).unwrap_or(""),
-        ));
+        )));
     }
     Ok(results)
 }
 /// Query an array.  This is a terminal query operation.
 ///
 /// Returns an error if the value is not an array, or the key is not a range.
-fn query_array_range(array: &Vec<Value>, range: &RangeType) -> Result<Vec<Value>, JQError> {
-    let mut values = Vec::new();
-    if range.is_empty() {
-        for val in array {
-            values.push(val.clone());
-        }
-    } else {
-        // Convert the jq style range to a Rust range
-        for val in from_range(array, range)? {
-            values.push(val.clone());
-        }
-    }
-    Ok(values)
+fn query_array_range<'v>(array: &'v [Value], range: &RangeType) -> Result<Selection<'v>, JQError> {
+    // Convert the jq style range to a Rust range
+    Ok(from_range(array, range)?
+        .iter()
+        .map(Cow::Borrowed)
+        .collect())
 }
 
 /// Query an array.  This is a terminal query operation.
 ///
 /// Returns an error if the value is not an array, or the key is not a range.
-fn query_string_range(value: &str, range: &RangeType) -> Result<Vec<Value>, JQError> {
-    if range.is_empty() {
-        return Ok(vec![Value::from(value)]);
-    }
+fn query_string_range<'v>(value: &str, range: &RangeType) -> Result<Selection<'v>, JQError> {
     let len = value.len();
     let (start, end) = range.as_slice(len);
     let val = value.get(start..end).unwrap_or("");
 
-    Ok(vec![Value::from(val)])
+    Ok(vec![Cow::Owned(Value::from(val))])
 }
 
 /// Used by [fn_has]
-pub fn query_identity(input: &Value) -> Result<Vec<Value>, JQError> {
-    Ok(vec![input.to_owned()])
+pub fn query_identity(input: &Value) -> Result<Selection<'_>, JQError> {
+    Ok(vec![Cow::Borrowed(input)])
 }
 
 /// Used by [fn_has]
-pub fn query_ident(input: &Value, id: &str, silent: bool) -> Result<Vec<Value>, JQError> {
+pub fn query_ident<'v>(input: &'v Value, id: &str, silent: bool) -> Result<Selection<'v>, JQError> {
     match input {
         Value::Object(object) => query_object_ident(object, id),
         _ => {
             if silent {
-                let v: Vec<Value> = Vec::new();
-                return Ok(v);
+                return Ok(Selection::new());
             }
             Err(JQError::IdentMismatch(format!(
                 "cannot index {}",
@@ -171,118 +193,746 @@ pub fn query_ident(input: &Value, id: &str, silent: bool) -> Result<Vec<Value>,
 }
 
 /// Used by [fn_has]
-pub fn query_range(input: &Value, range: &RangeType) -> Result<Vec<Value>, JQError> {
+pub fn query_range<'v>(input: &'v Value, range: &RangeType) -> Result<Selection<'v>, JQError> {
     match input {
         Value::Object(object) => query_object_range(object, range),
         Value::Array(array) => query_array_range(array, range),
+        _ if range.is_empty() => Err(JQError::CannotIterate(value_name(input).to_string())),
         Value::String(s) => query_string_range(s, range),
-        _ => Ok(vec![input.to_owned()]),
+        _ => Ok(vec![Cow::Borrowed(input)]),
     }
 }
 
 /// Used by [fn_has]
-pub fn query_index(input: &Value, index: &IndexType) -> Result<Vec<Value>, JQError> {
+pub fn query_index<'v>(input: &'v Value, index: &IndexType) -> Result<Selection<'v>, JQError> {
     match input {
         Value::Object(object) => query_object_index(object, index),
         Value::Array(array) => query_array_index(array, index),
         Value::String(s) => query_string_index(s, index),
-        _ => Ok(vec![input.to_owned()]),
+        _ => Ok(vec![Cow::Borrowed(input)]),
     }
 }
 
-/// Query each input with the given token
-fn query_single_token(inputs: &Vec<Value>, token: &Token) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = Vec::new();
+/// A path component, such as `.foo`, `.[0]` or `.[]`
+type PathFn<'p> = dyn for<'v> Fn(&'v Value) -> Result<Selection<'v>, JQError> + 'p;
+
+/// Apply a path component to the input.  The results borrow from a borrowed
+/// input, and are only cloned from an owned input.
+fn query_path<'a>(input: Input<'a>, path: &PathFn) -> ValueIter<'a> {
+    let results: Result<Vec<Input<'a>>, JQError> = match input {
+        Input::Borrowed(value) => {
+            path(value).map(|selection| selection.into_iter().map(Input::from).collect())
+        }
+        Input::Owned(value) => path(&value).map(|selection| {
+            selection
+                .into_iter()
+                .map(|value| Input::from(value.into_owned()))
+                .collect()
+        }),
+    };
+    match results {
+        Ok(results) => Box::new(results.into_iter().map(Ok)),
+        Err(error) => once(Err(error)),
+    }
+}
+
+pub(crate) fn query_function<'a>(
+    input: Input<'a>,
+    func: &'a Function,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let inputs = std::slice::from_ref(&*input);
+
+    match func {
+        Function::Length => from_results(fn_length(inputs)),
+        Function::Has(has) => from_results(fn_has(inputs, has)),
+        Function::Recurse => match input {
+            Input::Borrowed(value) => Box::new(fn_recurse(value).map(|value| Ok(value.into()))),
+            Input::Owned(value) => from_results(Ok(fn_recurse(&value).cloned().collect())),
+        },
+        Function::RecurseWith(filter) => fn_recurse_with(input, filter, env),
+        Function::Keys(sort) => from_results(fn_keys(inputs, *sort)),
+        Function::Not => from_results(fn_not(inputs)),
+        Function::Select(filter) => fn_select(input, filter, env),
+        Function::Map(filter) => from_results(fn_map(inputs, filter, env)),
+        Function::MapValues(filter) => from_results(fn_map_values(inputs, filter, env)),
+        Function::First(filter) => fn_first(input, filter, env),
+        Function::Limit(count, filter) => fn_limit(input, count, filter, env),
+        Function::ModuleMeta(loader) => from_results(fn_modulemeta(inputs, loader)),
+        Function::Error(message) => fn_error(input, message.as_deref(), env),
+        Function::Path(filter) => fn_path(input, filter, env),
+        Function::Paths(filter) => fn_paths(input, filter.as_deref(), env),
+        Function::LeafPaths => fn_leaf_paths(input),
+        Function::GetPath(paths) => fn_getpath(input, paths, env),
+        Function::SetPath(paths, value) => fn_setpath(input, paths, value, env),
+        Function::DelPaths(paths) => fn_delpaths(input, paths, env),
+    }
+}
+
+/// Iterate the values of an array or object, or slice an array or string.
+/// Values of a borrowed input are borrowed, and values of an owned input are
+/// only cloned as they are pulled.
+fn query_range_iter<'a>(input: Input<'a>, range: &RangeType) -> ValueIter<'a> {
+    match input {
+        Input::Borrowed(Value::Array(array)) => {
+            let (start, end) = range.as_slice(array.len());
+            Box::new(
+                array[start..end]
+                    .iter()
+                    .map(|value| Ok(Input::Borrowed(value))),
+            )
+        }
+        Input::Borrowed(Value::Object(object)) if range.is_empty() => {
+            Box::new(object.values().map(|value| Ok(Input::Borrowed(value))))
+        }
+        Input::Owned(ref value) if value.is_array() => {
+            let (start, end) = range.as_slice(value.as_array().map_or(0, Vec::len));
+            Box::new((start..end).map(move |idx| Ok(Input::from(input[idx].clone()))))
+        }
+        Input::Owned(ref value) if value.is_object() && range.is_empty() => {
+            let keys: Vec<String> = value
+                .as_object()
+                .map(|object| object.keys().cloned().collect())
+                .unwrap_or_default();
+            Box::new(
+                keys.into_iter()
+                    .map(move |key| Ok(Input::from(input[key.as_str()].clone()))),
+            )
+        }
+        _ => query_path(input, &|value| query_range(value, range)),
+    }
+}
 
-    for input in inputs {
-        let mut result = match token {
-            Token::Identity => query_identity(input)?,
-            Token::Ident(ident, silent) => query_ident(input, ident, *silent)?,
-            Token::Range(range) => query_range(input, range)?,
-            Token::Index(index) => query_index(input, index)?,
+/// Apply a chain of [Operator]s to the input, from left to right.  Every
+/// operand is evaluated against the input, and each operator is applied to
+/// every combination of its left hand results and its operand's results.
+fn query_operation<'a>(
+    input: Input<'a>,
+    first: &'a Expr,
+    rest: &'a [OperatorOperand],
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let mut lhs_values: Vec<_> = match query_iter(input.clone(), first, env).collect() {
+        Ok(values) => values,
+        Err(error) => return once(Err(error)),
+    };
+    let ((op, rhs), init) = match rest.split_last() {
+        Some(last) => last,
+        None => return Box::new(lhs_values.into_iter().map(Ok)),
+    };
+    for (op, operand) in init {
+        lhs_values = match query_operator(input.clone(), lhs_values, *op, operand, env).collect() {
+            Ok(values) => values,
+            Err(error) => return once(Err(error)),
         };
-        results.append(&mut result);
     }
+    query_operator(input, lhs_values, *op, rhs, env)
+}
 
-    Ok(results)
+/// Apply `op` to each of `lhs_values` and each result of `rhs`
+fn query_operator<'a>(
+    input: Input<'a>,
+    lhs_values: Vec<Input<'a>>,
+    op: Operator,
+    rhs: &'a Expr,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    // Mirror jq, which iterates the right hand side in the outer loop
+    Box::new(query_iter(input, rhs, env).flat_map(
+        move |r_val| -> Vec<Result<Input<'a>, JQError>> {
+            match r_val {
+                Ok(r_val) => lhs_values
+                    .iter()
+                    .map(|l_val| op.apply(l_val, &r_val).map(Input::from))
+                    .collect(),
+                Err(error) => vec![Err(error)],
+            }
+        },
+    ))
 }
 
-/// We need to walk the set of Filter elements, and process each.
-/// Currently, we are doing this backward.  For each value, we are processing
-/// the filter.  But this doesn't work if a filter element can return multiple
-/// values, such as `.array[1,2]
-/// For each filter element
-///     execute the filter query and return the collection of results.
-fn query_filter(inputs: &[Value], filter: &Filter) -> Result<Vec<Value>, JQError> {
-    let mut values = inputs.to_vec();
+/// Evaluate `lhs and rhs`, or `lhs or rhs` when `is_and` is false.
+/// The right hand side is only evaluated when the left hand side does not
+/// already decide the result.
+fn query_logical<'a>(
+    input: Input<'a>,
+    lhs: &'a Expr,
+    rhs: &'a Expr,
+    is_and: bool,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_iter(input.clone(), lhs, &env.clone()).flat_map(move |l_val| -> ValueIter<'a> {
+            match l_val {
+                Ok(l_val) if is_truthy(&l_val) != is_and => once(Ok(Value::Bool(!is_and).into())),
+                Ok(_) => Box::new(
+                    query_iter(input.clone(), rhs, &env)
+                        .map(|r_val| r_val.map(|r_val| Value::Bool(is_truthy(&r_val)).into())),
+                ),
+                Err(error) => once(Err(error)),
+            }
+        }),
+    )
+}
 
-    for token in filter {
-        if token.is_identity() {
-            continue;
+/// Evaluate `lhs // rhs`.  The truthy results of `lhs` are passed through, up
+/// to its first error, and `rhs` is only evaluated if there are none.
+fn query_alternative<'a>(
+    input: Input<'a>,
+    lhs: &'a Expr,
+    rhs: &'a Expr,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let found = Rc::new(Cell::new(false));
+    let lhs_found = found.clone();
+    let rhs_input = input.clone();
+    let env = env.clone();
+    Box::new(
+        query_try(input, lhs, None, &env)
+            .filter(move |value| match value {
+                Ok(value) if is_truthy(value) => {
+                    lhs_found.set(true);
+                    true
+                }
+                Ok(_) => false,
+                Err(_) => true,
+            })
+            .chain(deferred(move || {
+                if found.get() {
+                    Box::new(std::iter::empty())
+                } else {
+                    query_iter(rhs_input, rhs, &env)
+                }
+            })),
+    )
+}
+
+/// Evaluate `label $name | body`.  The results of `body` are passed through
+/// until a `break $name` bound to this evaluation of the label.
+pub(crate) fn query_label<'a, T: 'a>(
+    input: Input<'a>,
+    name: &'a str,
+    body: &'a Expr,
+    env: &Env<'a>,
+    eval: Eval<'a, T>,
+) -> Results<'a, T> {
+    let id = NEXT_LABEL.fetch_add(1, Ordering::Relaxed);
+    Box::new(
+        eval(input, body, &env.label(name, id))
+            .take_while(move |value| !matches!(value, Err(JQError::Break(label)) if *label == id)),
+    )
+}
+
+/// Evaluate `if cond then then else otherwise end`.  Each result of `cond`
+/// selects the branch that is evaluated against the input.
+fn query_if<'a>(
+    input: Input<'a>,
+    cond: &'a Expr,
+    then: &'a Expr,
+    otherwise: &'a Expr,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_iter(input.clone(), cond, &env.clone()).flat_map(move |value| match value {
+            Ok(value) if is_truthy(&value) => query_iter(input.clone(), then, &env),
+            Ok(_) => query_iter(input.clone(), otherwise, &env),
+            Err(error) => once(Err(error)),
+        }),
+    )
+}
+
+/// Evaluate `try body catch handler`.  The results of `body` are passed
+/// through until its first error, which ends them.  The value of the error is
+/// then the input of `handler`, or without a handler it is dropped.  A `break`
+/// is passed on to its label.
+fn query_try<'a>(
+    input: Input<'a>,
+    body: &'a Expr,
+    handler: Option<&'a Expr>,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_iter(input, body, &env.clone())
+            .scan(false, move |failed, value| {
+                if *failed {
+                    return None;
+                }
+                Some(match (value, handler) {
+                    (Ok(value), _) => once(Ok(value)),
+                    (Err(error), _) if error.is_break() => {
+                        *failed = true;
+                        once(Err(error))
+                    }
+                    (Err(error), Some(handler)) => {
+                        *failed = true;
+                        query_iter(Input::from(error.into_value()), handler, &env)
+                    }
+                    (Err(_), None) => {
+                        *failed = true;
+                        Box::new(std::iter::empty())
+                    }
+                })
+            })
+            .flatten(),
+    )
+}
+
+/// Construct an object from the input.  When a key or value filter produces
+/// more than one result, an object is produced for every combination.
+fn query_object<'a>(
+    input: Input<'a>,
+    entries: &'a [ObjectEntry],
+    env: &Env<'a>,
+) -> Result<Vec<Value>, JQError> {
+    let mut objects: Vec<Map<String, Value>> = vec![Map::new()];
+
+    for (key_filter, value_filter) in entries {
+        let keys = query_iter(input.clone(), key_filter, env).collect::<Result<Vec<_>, _>>()?;
+        let values = query_iter(input.clone(), value_filter, env).collect::<Result<Vec<_>, _>>()?;
+        let mut next: Vec<Map<String, Value>> = Vec::new();
+        for object in &objects {
+            for key in &keys {
+                let key = match &**key {
+                    Value::String(key) => key,
+                    key => return Err(JQError::ObjectKey(value_name(key).to_string())),
+                };
+                for value in &values {
+                    let mut object = object.clone();
+                    object.insert(key.to_owned(), Value::clone(value));
+                    next.push(object);
+                }
+            }
         }
-        values = query_single_token(&values, token)?;
+        objects = next;
     }
-    Ok(values)
+    Ok(objects.into_iter().map(Value::Object).collect())
 }
 
-fn query_function(inputs: &Vec<Value>, func: Function) -> Result<Vec<Value>, JQError> {
-    let mut output: Vec<Value> = Vec::new();
+/// Feed each result of `lhs` into `rhs`, as it is produced
+fn query_pipe<'a>(input: Input<'a>, lhs: &'a Expr, rhs: &'a Expr, env: &Env<'a>) -> ValueIter<'a> {
+    let env = env.clone();
+    Box::new(
+        query_iter(input, lhs, &env.clone()).flat_map(move |value| match value {
+            Ok(value) => query_iter(value, rhs, &env),
+            Err(error) => once(Err(error)),
+        }),
+    )
+}
 
-    let mut results = match func {
-        Function::Length => fn_length(inputs)?,
-        Function::Has(has) => fn_has(inputs, &has)?,
-        Function::Recurse => fn_recurse(inputs)?,
-        Function::Keys(sort) => fn_keys(inputs, sort)?,
+/// Destructure `value` with the first pattern, and call `body` with the input
+/// and each binding.  If there are alternatives (`?//`), the first error,
+/// either while destructuring or in `body`, moves on to the next alternative.
+/// Results produced before the error are kept.
+pub(crate) fn query_patterns<'a, T: 'a, F>(
+    input: Input<'a>,
+    value: Input<'a>,
+    patterns: &'a [Pattern],
+    body: F,
+    env: &Env<'a>,
+) -> Results<'a, T>
+where
+    F: Fn(Input<'a>, &Env<'a>) -> Results<'a, T> + Clone + 'a,
+{
+    let (pattern, alternatives) = match patterns.split_first() {
+        Some(split) => split,
+        None => return Box::new(std::iter::empty()),
     };
-    output.append(&mut results);
+    let mut results: Results<'a, T> = match pattern.destructure(value.clone(), env) {
+        Ok(envs) => {
+            let input = input.clone();
+            let body = body.clone();
+            Box::new(
+                envs.into_iter()
+                    .flat_map(move |env| body(input.clone(), &env)),
+            )
+        }
+        Err(error) => Box::new(std::iter::once(Err(error))),
+    };
+    if alternatives.is_empty() {
+        return results;
+    }
+    let env = env.clone();
+    let mut failed = false;
+    Box::new(std::iter::from_fn(move || {
+        let result = results.next();
+        if !failed && matches!(&result, Some(Err(error)) if !error.is_break()) {
+            failed = true;
+            let body = body.clone();
+            results = query_patterns(input.clone(), value.clone(), alternatives, body, &env);
+            return results.next();
+        }
+        result
+    }))
+}
 
-    Ok(output)
+/// Evaluate `body` once for each result of `source`, with the result bound to
+/// the variables of the patterns
+fn query_bind<'a>(
+    input: Input<'a>,
+    source: &'a Expr,
+    patterns: &'a [Pattern],
+    body: &'a Expr,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = bind_alternatives(env, patterns);
+    Box::new(
+        query_iter(input.clone(), source, &env.clone()).flat_map(move |value| match value {
+            Ok(value) => {
+                let body = move |input, env: &Env<'a>| query_iter(input, body, env);
+                query_patterns(input.clone(), value, patterns, body, &env)
+            }
+            Err(error) => once(Err(error)),
+        }),
+    )
 }
 
-/// Process all the actions in a block and return the results
-fn query_block(in_values: &Vec<Value>, block: Block) -> Result<Vec<Value>, JQError> {
-    let mut results: Vec<Value> = Vec::new();
-    if block.actions.is_none() {
-        return Ok(results);
+/// When there are alternative patterns (`?//`), every variable of every
+/// alternative is visible in the body, and is null unless the matching
+/// pattern binds it
+pub(crate) fn bind_alternatives<'a>(env: &Env<'a>, patterns: &'a [Pattern]) -> Env<'a> {
+    let mut env = env.clone();
+    if patterns.len() > 1 {
+        for name in patterns.iter().flat_map(Pattern::variables) {
+            env = env.bind(name, Input::Borrowed(&NULL));
+        }
     }
-    for action in block.actions.unwrap() {
-        let mut next = match action {
-            Action::Filter(filter) => query_filter(in_values, &filter)?,
-            Action::Function(func) => query_function(in_values, func)?,
-        };
-        results.append(&mut next);
+    env
+}
+
+/// Evaluate `reduce source as patterns (init; update)`, once for each result of `init`
+fn query_reduce<'a>(
+    input: Input<'a>,
+    source: &'a Expr,
+    patterns: &'a [Pattern],
+    init: &'a Expr,
+    update: &'a Expr,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = bind_alternatives(env, patterns);
+    Box::new(
+        query_iter(input.clone(), init, &env.clone()).map(move |state| {
+            let mut state = state?;
+            for value in query_iter(input.clone(), source, &env) {
+                let mut next = Input::Borrowed(&NULL);
+                let update = move |input, env: &Env<'a>| query_iter(input, update, env);
+                for result in query_patterns(state, value?, patterns, update, &env) {
+                    next = result?;
+                }
+                state = next;
+            }
+            Ok(state)
+        }),
+    )
+}
+
+/// Evaluate `foreach source as patterns (init; update; extract)`, once for
+/// each result of `init`.  The state is the last result of `update`, and is
+/// unchanged if it has none.
+fn query_foreach<'a>(
+    input: Input<'a>,
+    source: &'a Expr,
+    patterns: &'a [Pattern],
+    init: &'a Expr,
+    update: &'a Expr,
+    extract: Option<&'a Expr>,
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    let env = bind_alternatives(env, patterns);
+    Box::new(query_iter(input.clone(), init, &env.clone()).flat_map(
+        move |state| -> ValueIter<'a> {
+            let state = match state {
+                Ok(state) => Rc::new(RefCell::new(state)),
+                Err(error) => return once(Err(error)),
+            };
+            let env = env.clone();
+            Box::new(
+                query_iter(input.clone(), source, &env.clone()).flat_map(move |value| {
+                    let value = match value {
+                        Ok(value) => value,
+                        Err(error) => return once(Err(error)),
+                    };
+                    let current = state.borrow().clone();
+                    let state = state.clone();
+                    // `extract` sees the same bindings as `update`
+                    let body = move |current, env: &Env<'a>| -> ValueIter<'a> {
+                        let state = state.clone();
+                        let env = env.clone();
+                        Box::new(query_iter(current, update, &env.clone()).flat_map(
+                            move |result| {
+                                let result = match result {
+                                    Ok(result) => result,
+                                    Err(error) => return once(Err(error)),
+                                };
+                                *state.borrow_mut() = result.clone();
+                                match extract {
+                                    Some(extract) => query_iter(result, extract, &env),
+                                    None => once(Ok(result)),
+                                }
+                            },
+                        ))
+                    };
+                    query_patterns(current, value, patterns, body, &env)
+                }),
+            )
+        },
+    ))
+}
+
+/// Bind the arguments of a call to the parameters of a function, in the scope of
+/// the definition, then evaluate the body with `eval`.  A `$name` parameter
+/// evaluates its argument in the scope of the caller, and the body runs once
+/// for each result.
+pub(crate) fn query_params<'a, T: 'a>(
+    input: Input<'a>,
+    definition: &'a Definition,
+    params: &'a [Param],
+    args: &'a [Expr],
+    scope: Env<'a>,
+    caller: &Env<'a>,
+    eval: Eval<'a, T>,
+) -> Results<'a, T> {
+    let ((param, params), (arg, args)) = match (params.split_first(), args.split_first()) {
+        (Some(param), Some(arg)) => (param, arg),
+        _ => return eval(input, definition.body(), &scope),
+    };
+    let scope = scope.bind_closure(param.name(), arg, caller.clone());
+    match param {
+        Param::Filter(_) => query_params(input, definition, params, args, scope, caller, eval),
+        Param::Variable(name) => {
+            let caller = caller.clone();
+            Box::new(
+                query_iter(input.clone(), arg, &caller.clone()).flat_map(
+                    move |value| match value {
+                        Ok(value) => query_params(
+                            input.clone(),
+                            definition,
+                            params,
+                            args,
+                            scope.bind(name, value),
+                            &caller,
+                            eval,
+                        ),
+                        Err(error) => Box::new(std::iter::once(Err(error))),
+                    },
+                ),
+            )
+        }
     }
-    if block.collect {
-        let collect = Value::from(results);
-        results = vec![collect];
+}
+
+/// Call a native function once for each combination of the results of its
+/// arguments, which are evaluated against the input.  The first argument
+/// changes slowest.
+fn query_native<'a>(
+    input: Input<'a>,
+    function: &dyn NativeFunction,
+    args: &'a [Expr],
+    env: &Env<'a>,
+) -> Result<Vec<Value>, JQError> {
+    let mut combinations: Vec<Vec<Value>> = vec![Vec::new()];
+    for arg in args {
+        let values = query_iter(input.clone(), arg, env)
+            .map(|value| value.map(Input::into_value))
+            .collect::<Result<Vec<_>, _>>()?;
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.push(value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    combinations
+        .iter()
+        .map(|args| function.call(&input, args))
+        .collect()
+}
+
+/// Call a user defined function, a filter parameter or a native function
+fn query_call<'a>(
+    input: Input<'a>,
+    name: &'a str,
+    args: &'a [Expr],
+    env: &Env<'a>,
+) -> ValueIter<'a> {
+    if let Err(error) = env.check_depth() {
+        return once(Err(error));
+    }
+    match env.function(name, args.len()) {
+        Some(Callable::Definition(definition, scope)) => query_params(
+            input,
+            definition,
+            definition.params(),
+            args,
+            scope,
+            env,
+            query_iter,
+        ),
+        Some(Callable::Closure(filter, scope)) => query_iter(input, filter, &scope),
+        Some(Callable::Native(function)) => from_results(query_native(input, function, args, env)),
+        None => once(Err(JQError::UndefinedFunction(format!(
+            "{}/{}",
+            name,
+            args.len()
+        )))),
     }
-    Ok(results)
 }
 
-/// Queries a series of blocks.  The output of one block becomes the input for
-/// the next block.
-pub fn query(in_values: &[Value], blocks: Vec<Block>) -> Result<Vec<Value>, JQError> {
-    let mut values = in_values.to_vec();
+/// Lazily evaluate a single expression against a single input
+pub fn query_iter<'a>(input: Input<'a>, expr: &'a Expr, env: &Env<'a>) -> ValueIter<'a> {
+    match expr {
+        Expr::Identity => once(Ok(input)),
+        Expr::Ident(ident, silent) => {
+            query_path(input, &|value| query_ident(value, ident, *silent))
+        }
+        Expr::Index(index) => query_path(input, &|value| query_index(value, index)),
+        Expr::Range(range) => query_range_iter(input, range),
+        Expr::Function(func) => query_function(input, func, env),
+        Expr::Literal(value) => once(Ok(Input::Borrowed(value))),
+        Expr::Operation(first, rest) => query_operation(input, first, rest, env),
+        Expr::And(lhs, rhs) => query_logical(input, lhs, rhs, true, env),
+        Expr::Or(lhs, rhs) => query_logical(input, lhs, rhs, false, env),
+        Expr::Alternative(lhs, rhs) => query_alternative(input, lhs, rhs, env),
+        Expr::Array(filter) => once(
+            query_iter(input, filter, env)
+                .map(|value| value.map(Input::into_value))
+                .collect::<Result<Vec<_>, _>>()
+                .map(|values| Value::from(values).into()),
+        ),
+        Expr::Object(entries) => from_results(query_object(input, entries, env)),
+        Expr::Pipe(lhs, rhs) => query_pipe(input, lhs, rhs, env),
+        Expr::If(cond, then, otherwise) => query_if(input, cond, then, otherwise, env),
+        Expr::Try(body, handler) => query_try(input, body, handler.as_deref(), env),
+        Expr::Label(name, body) => query_label(input, name, body, env, query_iter),
+        Expr::Break(name) => match env.get_label(name) {
+            Some(id) => once(Err(JQError::Break(id))),
+            None => once(Err(JQError::UndefinedLabel(name.to_string()))),
+        },
+        Expr::Comma(exprs) => {
+            let env = env.clone();
+            Box::new(
+                exprs
+                    .iter()
+                    .flat_map(move |expr| query_iter(input.clone(), expr, &env)),
+            )
+        }
+        Expr::Variable(name) => match env.get(name) {
+            Some(value) => once(Ok(value.clone())),
+            None => once(Err(JQError::UndefinedVariable(name.to_string()))),
+        },
+        Expr::Bind(source, patterns, body) => query_bind(input, source, patterns, body, env),
+        Expr::Reduce(source, patterns, init, update) => {
+            query_reduce(input, source, patterns, init, update, env)
+        }
+        Expr::Foreach(source, patterns, init, update, extract) => query_foreach(
+            input,
+            source,
+            patterns,
+            init,
+            update,
+            extract.as_deref(),
+            env,
+        ),
+        Expr::Define(definition, rest) => query_iter(input, rest, &env.define(definition)),
+        Expr::Call(name, args) => query_call(input, name, args, env),
+        Expr::Import(library, alias, rest) => {
+            let module = library_env(library, module_root(env));
+            query_iter(input, rest, &env.import(alias, module))
+        }
+    }
+}
 
-    for block in blocks {
-        // The output from the last query_block is the input for the next
-        values = query_block(&values, block)?;
+/// The environment that an imported module starts from: the predefined
+/// variables, and the native functions of the importer
+pub(crate) fn module_root<'a>(env: &Env<'a>) -> Env<'a> {
+    match env.functions() {
+        Some(functions) => predefined().with_functions(functions),
+        None => predefined(),
     }
-    Ok(values)
+}
+
+/// The environment of a library linked into an [Expr::Import]: its data
+/// variables, the modules it imports, and its definitions
+pub(crate) fn library_env<'a>(library: &'a Expr, env: Env<'a>) -> Env<'a> {
+    match library {
+        Expr::Define(definition, rest) => library_env(rest, env.define(definition)),
+        Expr::Import(inner, alias, rest) => {
+            let module = library_env(inner, module_root(&env));
+            library_env(rest, env.import(alias, module))
+        }
+        Expr::Bind(source, patterns, rest) => match (&**source, patterns.as_slice()) {
+            (Expr::Literal(value), [Pattern::Variable(name)]) => {
+                library_env(rest, env.bind(name, Input::Borrowed(value)))
+            }
+            // `$d`, bound to the same data as `$d::d`
+            (Expr::Variable(source), [Pattern::Variable(name)]) => match env.get(source) {
+                Some(value) => {
+                    let value = value.clone();
+                    library_env(rest, env.bind(name, value))
+                }
+                None => env,
+            },
+            _ => env,
+        },
+        _ => env,
+    }
+}
+
+/// Evaluate a single expression against a single input, and collect the
+/// results.  Stops at the first error.  Only the predefined variables are defined.
+pub fn query_expr(input: &Value, expr: &Expr) -> Result<Vec<Value>, JQError> {
+    query_iter(Input::from(input), expr, &predefined())
+        .map(|value| value.map(Input::into_value))
+        .collect()
+}
+
+/// Lazily evaluate a single expression against a single input.  Results that
+/// are part of the input are borrowed rather than cloned.
+pub fn query_ref<'a>(
+    input: &'a Value,
+    expr: &'a Expr,
+) -> impl Iterator<Item = Result<Cow<'a, Value>, JQError>> + 'a {
+    query_iter(Input::from(input), expr, &predefined()).map(|value| value.map(Input::into_cow))
+}
+
+/// Runs a parsed query against each of the inputs, and returns all of the results
+pub fn query(in_values: &[Value], expr: &Expr) -> Result<Vec<Value>, JQError> {
+    let mut results: Vec<Value> = Vec::new();
+
+    for input in in_values {
+        results.append(&mut query_expr(input, expr)?);
+    }
+    Ok(results)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{parse, IndexType, RangeType};
+    use crate::{parse, parse_with_options, CompileOptions, IndexType, RangeType, MAX_CALL_DEPTH};
     use serde_json::json;
 
+    fn parse_modules(query_str: &str) -> Result<Expr, JQError> {
+        let options = CompileOptions::new()
+            .with_search_path(concat!(env!("CARGO_MANIFEST_DIR"), "/test/modules"));
+        parse_with_options(query_str, &options)
+    }
+
+    fn owned(selection: Selection) -> Vec<Value> {
+        selection.into_iter().map(Cow::into_owned).collect()
+    }
+
     #[test]
     fn test_array_filter() {
         let json = r#"[[1,2], "string", {"a":2}, null]"#;
         let input: Value = serde_json::from_str(json).expect("Failed to parse json");
-        let blocks = parse(r#".[] | length"#).expect("failed to parse query");
-        let result = query(&[input], blocks).expect("failed");
+        let expr = parse(r#".[] | length"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
         // dbg!(&result);
         assert_eq!(
             result,
@@ -295,19 +945,203 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_literals() {
+        let input = json!({"a": 1, "b": 2});
+        let expr = parse(r#".a, "sep", .b, null, true, 1.5"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!(1),
+                json!("sep"),
+                json!(2),
+                json!(null),
+                json!(true),
+                json!(1.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literal_per_input() {
+        let input = json!([1, 2, 3]);
+        let expr = parse(r#".[] | "x""#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!("x"), json!("x"), json!("x")]);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let input = json!({"price": 2.5, "qty": 4, "name": "widget", "tags": ["a"]});
+        let expr = parse(r#".price * .qty, .qty + 1 * 2, .name + "s", .tags + .tags, 7 % 4 - 1"#)
+            .expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!(10),
+                json!(6),
+                json!("widgets"),
+                json!(["a", "a"]),
+                json!(2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_per_input() {
+        let input = json!([{"a": 1, "b": 2}, {"a": 3, "b": 4}]);
+        let expr = parse(r#".[] | .a + .b"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!(3), json!(7)]);
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let expr = parse(r#".a / 0"#).expect("failed to parse query");
+        let result = query(&[json!({"a": 1})], &expr);
+        assert!(matches!(result, Err(JQError::DivisionByZero(..))));
+
+        let expr = parse(r#".a - "x""#).expect("failed to parse query");
+        let result = query(&[json!({"a": 1})], &expr);
+        assert!(matches!(result, Err(JQError::OperandMismatch(..))));
+
+        let expr = parse(r#"-.name"#).expect("failed to parse query");
+        let result = query(&[json!({"name": "x"})], &expr);
+        assert!(matches!(result, Err(JQError::OperandMismatch(..))));
+    }
+
+    #[test]
+    fn test_unary_minus() {
+        let input = json!({"a": 2, "b": [1, -3]});
+        let expr = parse(r#"-.a, -(1), -.a * 3, 1 - -.a, [.b[] | -.], -.b[-1]"#)
+            .expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!(-2),
+                json!(-1),
+                json!(-6),
+                json!(3),
+                json!([-1, 3]),
+                json!(3)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comparison() {
+        let input = json!({"a": 1, "b": 2, "s": "x"});
+        let expr = parse(r#".a < .b, .a + 1 == .b, .s != "x", null < false, .b >= 3"#)
+            .expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!(true),
+                json!(true),
+                json!(false),
+                json!(true),
+                json!(false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_and_or_not() {
+        let input = json!({"a": true, "b": null});
+        let expr = parse(r#".a and .b, .a or .b, .b or .a and .a"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!(false), json!(true), json!(true)]);
+
+        let expr = parse(r#".b | not"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!(true)]);
+    }
+
+    #[test]
+    fn test_and_short_circuits() {
+        // The right hand side would fail if it were evaluated
+        let expr = parse(r#"false and .a.b, true or .a.b"#).expect("failed to parse query");
+        let result = query(&[json!("not an object")], &expr).expect("failed");
+        assert_eq!(result, [json!(false), json!(true)]);
+    }
+
+    #[test]
+    fn test_select() {
+        let input = json!([
+            {"id": 1, "status": "active"},
+            {"id": 2, "status": "closed"},
+            {"id": 3, "status": "active"}
+        ]);
+        let expr =
+            parse(r#".[] | select(.status == "active") | .id"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!(1), json!(3)]);
+    }
+
+    #[test]
+    fn test_map() {
+        let input = json!({"items": [{"price": 2, "qty": 3}, {"price": 5, "qty": 1}]});
+        let expr = parse(r#".items | map(.price * .qty)"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!([6, 5])]);
+
+        let expr = parse(r#".items[0] | map_values(. + 1)"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!({"price": 3, "qty": 4})]);
+    }
+
+    #[test]
+    fn test_object_construction() {
+        let input = json!({"user": "stedolan", "titles": ["JQ Primer", "More JQ"], "k": "key"});
+        let expr = parse(r#"{user, title: .titles[]}"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!({"user": "stedolan", "title": "JQ Primer"}),
+                json!({"user": "stedolan", "title": "More JQ"})
+            ]
+        );
+
+        let expr =
+            parse(r#"{(.k): 1, "a b": .user | length, "user"}"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!({"key": 1, "a b": 8, "user": "stedolan"})]);
+    }
+
+    #[test]
+    fn test_object_cartesian_product() {
+        let expr = parse(r#"{a: .[], b: .[] * 10}"#).expect("failed to parse query");
+        let result = query(&[json!([1, 2])], &expr).expect("failed");
+        assert_eq!(
+            result,
+            [
+                json!({"a": 1, "b": 10}),
+                json!({"a": 1, "b": 20}),
+                json!({"a": 2, "b": 10}),
+                json!({"a": 2, "b": 20})
+            ]
+        );
+    }
+
+    #[test]
+    fn test_object_key_must_be_string() {
+        let expr = parse(r#"{(.a): 1}"#).expect("failed to parse query");
+        let result = query(&[json!({"a": 1})], &expr);
+        assert!(matches!(result, Err(JQError::ObjectKey(_))));
+    }
+
     #[test]
     fn test_empty_query() {
         let json = include_str!("../test/basic.json");
         let input: Value = serde_json::from_str(json).expect("Failed to parse");
 
-        let filter = vec![];
-        let action = Action::Filter(filter);
-        let block = Block {
-            actions: Some(vec![action]),
-            collect: false,
-        };
-        let blocks = vec![block];
-        let result = query(&[input.clone()], blocks).expect("Failed query");
+        let expr = Expr::path(vec![]);
+        let result = query(std::slice::from_ref(&input), &expr).expect("Failed query");
         // dbg!(&result);
         assert_eq!(&result, &[input]);
     }
@@ -317,77 +1151,725 @@ mod tests {
         let json = include_str!("../test/basic.json");
         let input: Value = serde_json::from_str(json).expect("Failed to parse");
 
-        let filter = vec![
-            Token::Ident("object_1", false),
-            Token::Ident("elem_1", false),
-        ];
-        let action = Action::Filter(filter);
-        let block = Block {
-            actions: Some(vec![action]),
-            collect: false,
-        };
-        let blocks = vec![block];
+        let expr = Expr::path(vec![
+            Expr::Ident("object_1".to_string(), false),
+            Expr::Ident("elem_1".to_string(), false),
+        ]);
 
-        let result = query(&[input], blocks).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
         //dbg!(&result);
         assert_eq!(&result, &[json!("Object 1 Element 1")]);
     }
 
     #[test]
-    fn test_query_2_blocks() {
+    fn test_query_pipe() {
         let json = include_str!("../test/basic.json");
         let input: Value = serde_json::from_str(json).expect("Failed to parse");
 
         //let query_str = r#" .object_1 | .elem_1 "#;
-        let blocks = vec![
-            Block {
-                actions: Some(vec![Action::Filter(vec![Token::Ident("object_1", false)])]),
-                collect: false,
-            },
-            Block {
-                actions: Some(vec![Action::Filter(vec![Token::Ident("elem_1", false)])]),
-                collect: false,
-            },
-        ];
+        let expr = Expr::Pipe(
+            Box::new(Expr::Ident("object_1".to_string(), false)),
+            Box::new(Expr::Ident("elem_1".to_string(), false)),
+        );
 
-        let result = query(&[input], blocks).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
 
         //dbg!(&result);
         assert_eq!(&result, &[json!("Object 1 Element 1")]);
     }
 
     #[test]
-    fn test_collect_block() {
+    fn test_collect() {
         let json = json!([1, 2, 3]);
-        let filter = vec![Token::Identity, Token::Range(RangeType::new())];
-        let action = Action::Filter(filter);
-        let block = Block {
-            actions: Some(vec![action]),
-            collect: true,
-        };
-        let result = query_block(&vec![json], block).expect("Failed query");
+        let expr = Expr::Array(Box::new(Expr::Range(RangeType::new())));
+        let result = query_expr(&json, &expr).expect("Failed query");
 
-        dbg!(&result);
+        assert_eq!(result, [json!([1, 2, 3])]);
     }
 
     #[test]
-    fn test_bock() {
-        let json = include_str!("../test/basic.json");
-        let input: Value = serde_json::from_str(json).expect("Failed to parse");
+    fn test_array_anywhere() {
+        let input = json!([{"id": 1, "b": 2, "c": 3}, {"id": 4, "b": 5, "c": 6}]);
+        let expr = parse(r#"{ids: [.[] | .id]}"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!({"ids": [1, 4]})]);
+
+        let expr = parse(r#".[] | [.b, .c] | length"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!(2), json!(2)]);
+
+        let expr = parse(r#"map([.id, []]), [1, 2] + [3]"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!([[1, []], [4, []]]), json!([1, 2, 3])]);
+    }
+
+    #[test]
+    fn test_comma_per_input() {
+        let input = json!([{"a": 1, "b": 2}, {"a": 3, "b": 4}]);
+        let expr = parse(r#".[] | .a, .b"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!(1), json!(2), json!(3), json!(4)]);
+    }
+
+    #[test]
+    fn test_first_and_limit() {
+        let input = json!({"items": [1, 2, 3], "map": {"a": 1, "b": 2}});
+        let expr = parse(r#"first(.items[] | select(. > 1)), limit(2; .items[] * 10)"#)
+            .expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!(2), json!(10), json!(20)]);
+
+        // Iterate values produced by the query, as well as the input
+        let expr = parse(r#"[.map | .[]], first(.items | .[1:])"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!([1, 2]), json!(2)]);
+    }
+
+    #[test]
+    fn test_lazy_evaluation() {
+        // The error in `.[] | .a` is never reached
+        let input = json!([{"a": 1}, 2]);
+        let expr = parse(r#"first(.[] | .a)"#).expect("failed to parse query");
+        let mut results = query_iter(Input::from(&input), &expr, &Env::new());
+        assert_eq!(*results.next().expect("missing").expect("failed"), json!(1));
+        assert!(results.next().is_none());
+
+        let expr = parse(r#".[] | .a"#).expect("failed to parse query");
+        let mut results = query_iter(Input::from(&input), &expr, &Env::new());
+        assert_eq!(*results.next().expect("missing").expect("failed"), json!(1));
+        assert!(results.next().expect("missing").is_err());
+    }
+
+    #[test]
+    fn test_query_ref_borrows() {
+        let input = json!({"a": {"b": [1, 2]}, "c": [{"d": true}]});
+        let expr = parse(r#".a.b[1], .a.b[], (.c[] | select(.d)), .., .a.b[5]"#)
+            .expect("failed to parse query");
+        let results: Vec<Cow<Value>> = query_ref(&input, &expr)
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert_eq!(results.len(), 13);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Cow::Borrowed(_))));
+        assert!(std::ptr::eq(results[0].as_ref(), &input["a"]["b"][1]));
+        assert!(std::ptr::eq(results[3].as_ref(), &input["c"][0]));
+
+        let expr = parse(r#".a.b[0] + 1, [.c[]], ({e: .a} | .e)"#).expect("failed to parse query");
+        let results: Vec<Cow<Value>> = query_ref(&input, &expr)
+            .collect::<Result<_, _>>()
+            .expect("failed");
+        assert!(matches!(results[0], Cow::Owned(_)));
+        assert!(matches!(results[1], Cow::Owned(_)));
+        assert_eq!(results[2].as_ref(), &json!({"b": [1, 2]}));
+    }
+
+    #[test]
+    fn test_variables() {
+        let input = json!({"owner": "ann", "items": [{"name": "a"}, {"name": "b"}]});
+        let expr = parse(r#". as $root | .items[] | {name, owner: $root.owner}"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [
+                json!({"name": "a", "owner": "ann"}),
+                json!({"name": "b", "owner": "ann"})
+            ]
+        );
+
+        let expr = parse(r#".owner as $x | {$x}"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [json!({"x": "ann"})]
+        );
+    }
+
+    #[test]
+    fn test_bind_each_result() {
+        let expr = parse(r#".[] as $x | . | length + $x"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2]), &expr).expect("failed"),
+            [json!(3), json!(4)]
+        );
+    }
+
+    #[test]
+    fn test_variable_shadowing() {
+        let expr = parse(r#"1 as $x | (2 as $x | $x), $x"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(2), json!(1)]
+        );
+        let expr = parse(r#"1 as $x | [$x, (. as $y | $x + $y)]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(10), &expr).expect("failed"),
+            [json!([1, 11])]
+        );
+    }
+
+    #[test]
+    fn test_variable_in_function_args() {
+        let expr = parse(r#".limit as $n | [limit($n; .items[])] | map(. * $n)"#)
+            .expect("failed to parse query");
+        let input = json!({"limit": 2, "items": [1, 2, 3]});
+        assert_eq!(query_expr(&input, &expr).expect("failed"), [json!([2, 4])]);
+    }
+
+    #[test]
+    fn test_destructuring() {
+        let expr =
+            parse(r#". as [$a, $b, {c: $c}] | $a + $b + $c"#).expect("failed to parse query");
+        let input = json!([2, 3, {"c": 4, "d": 5}]);
+        assert_eq!(query_expr(&input, &expr).expect("failed"), [json!(9)]);
+
+        let expr = parse(r#".[] as [$a, $b] | {a: $a, b: $b}"#).expect("failed to parse query");
+        let input = json!([[0], [0, 1], [2, 1, 0]]);
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [
+                json!({"a": 0, "b": null}),
+                json!({"a": 0, "b": 1}),
+                json!({"a": 2, "b": 1})
+            ]
+        );
+
+        let expr =
+            parse(r#". as {$a, $b: [$c, $d]} | [$a, $b, $c, $d]"#).expect("failed to parse query");
+        let input = json!({"a": 1, "b": [2, {"d": 3}]});
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [json!([1, [2, {"d": 3}], 2, {"d": 3}])]
+        );
+    }
+
+    #[test]
+    fn test_destructuring_computed_keys() {
+        let input = json!({"k": "a", "a": 5});
+        let expr = parse(r#". as {$k, ($k): $v} | $v"#).expect("failed to parse query");
+        assert_eq!(query_expr(&input, &expr).expect("failed"), [json!(5)]);
+
+        // A key that produces several results binds once for each
+        let expr = parse(r#". as {("k", "a"): $v} | $v"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [json!("a"), json!(5)]
+        );
+    }
+
+    #[test]
+    fn test_destructuring_mismatch() {
+        let expr = parse(r#". as [$a] | $a"#).expect("failed to parse query");
+        let error = query_expr(&json!({"a": 1}), &expr).expect_err("should fail");
+        assert_eq!(error.to_string(), "Cannot index object with number");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [Value::Null]
+        );
+    }
+
+    #[test]
+    fn test_destructuring_alternatives() {
+        let expr = parse(r#".[] as {$a, $b, c: {$d}} ?// {$a, $b, c: [{$e}]} | {$a, $b, $d, $e}"#)
+            .expect("failed to parse query");
+        let input = json!([
+            {"a": 1, "b": 2, "c": {"d": 3, "e": 4}},
+            {"a": 1, "b": 2, "c": [{"d": 3, "e": 4}]}
+        ]);
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [
+                json!({"a": 1, "b": 2, "d": 3, "e": null}),
+                json!({"a": 1, "b": 2, "d": null, "e": 4})
+            ]
+        );
+    }
+
+    #[test]
+    fn test_destructuring_alternative_on_body_error() {
+        // The error in the body of the first alternative moves on to the
+        // second, keeping the results produced so far
+        let expr =
+            parse(r#".[] as [$a] ?// [$b] | {$a, $b}, $a + "s""#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([[3]]), &expr).expect("failed"),
+            [
+                json!({"a": 3, "b": null}),
+                json!({"a": null, "b": 3}),
+                json!("s")
+            ]
+        );
+
+        // An error in the last alternative is reported
+        let expr = parse(r#". as [$a] ?// [$b] | $b"#).expect("failed to parse query");
+        assert!(query_expr(&json!({"a": 1}), &expr).is_err());
+    }
 
-        let filter = vec![
-            Token::Ident("object_1", false),
-            Token::Ident("elem_1", false),
+    #[test]
+    fn test_definitions() {
+        let expr = parse(r#"def double: . * 2; def addvalue(f): f as $x | map(. + $x); [.[] | double] | addvalue(.[0])"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2]), &expr).expect("failed"),
+            [json!([4, 6])]
+        );
+
+        // A name may start with the name of a builtin
+        let expr = parse(
+            r#"def lengthy: length > 1; def keys_of: [keys[] | "k" + .]; def recurse_all: [recurse]; [lengthy, keys_of, (.b | recurse_all)]"#,
+        )
+        .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!({"a": 1, "b": [2]}), &expr).expect("failed"),
+            [json!([true, ["ka", "kb"], [[2], 2]])]
+        );
+    }
+
+    #[test]
+    fn test_definition_params() {
+        // A filter parameter is evaluated against the input where it is used,
+        // and a $ parameter against the input of the call
+        let expr = parse(r#"def f(g): [.[] | g]; def h($v): [.[] | $v]; f(length), h(length)"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(["ab", "c"]), &expr).expect("failed"),
+            [json!([2, 1]), json!([2, 2])]
+        );
+
+        // Each result of a $ parameter runs the body
+        let expr =
+            parse(r#"def f($a; $b): [$a, $b]; f(1, 2; 3, 4)"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1, 3]), json!([1, 4]), json!([2, 3]), json!([2, 4])]
+        );
+
+        // Calling a $ parameter as a filter produces every result of the argument
+        let expr = parse(r#"def f($a): [$a, a]; f(1, 2)"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1, 1, 2]), json!([2, 1, 2])]
+        );
+    }
+
+    #[test]
+    fn test_definition_recursion() {
+        // `first` stops before the recursive call when the base case matches
+        let expr =
+            parse(r#"def fact: first((select(. <= 1) | 1), . * (. - 1 | fact)); [.[] | fact]"#)
+                .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 3, 5]), &expr).expect("failed"),
+            [json!([1, 6, 120])]
+        );
+
+        // A function can only call functions defined before it, or itself
+        assert!(parse(r#"def f: g; def g: 0; f"#).is_err());
+    }
+
+    #[test]
+    fn test_definition_closure() {
+        // The body sees the scope of the definition, not of the caller
+        let expr = parse(r#"1 as $x | def f: $x; 2 as $x | f, $x"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(1), json!(2)]
+        );
+        let expr = parse(r#"def f: 1; def g: f; def f: 2; g, f"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(1), json!(2)]
+        );
+
+        // A filter argument sees the scope of the caller
+        let expr =
+            parse(r#"def f(g): 1 as $x | g; 2 as $x | f($x)"#).expect("failed to parse query");
+        assert_eq!(query_expr(&Value::Null, &expr).expect("failed"), [json!(2)]);
+    }
+
+    #[test]
+    fn test_import() {
+        let expr = parse_modules(r#"import "lib" as lib; lib::increment | lib::double"#)
+            .expect("failed to parse query");
+        assert_eq!(query_expr(&json!(2), &expr).expect("failed"), [json!(6)]);
+
+        // The data and included functions of a module are not visible to the importer
+        assert!(parse_modules(r#"import "lib" as lib; one"#).is_err());
+        assert!(parse_modules(r#"import "lib" as lib; $data::data"#).is_err());
+        assert!(parse_modules(r#"import "lib" as lib; lib::first_datum"#).is_ok());
+
+        // Data is bound to both `$d` and `$d::d`, as in jq
+        let expr = parse_modules(r#"import "data" as $d; $d[1].a, $d::d[0].a"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(2), json!(1)]
+        );
+
+        // A module can import other modules
+        let expr =
+            parse_modules(r#"import "nested" as n; n::quadruple"#).expect("failed to parse query");
+        assert_eq!(query_expr(&json!(1), &expr).expect("failed"), [json!(4)]);
+        assert!(parse_modules(r#"import "nested" as n; n::lib::double"#).is_err());
+    }
+
+    #[test]
+    fn test_import_scope() {
+        // A module does not see the functions or variables of the importer
+        let expr = parse_modules(r#"def one: 100; import "lib" as lib; 1 | lib::increment, one"#);
+        assert!(expr.is_err());
+        let expr = parse_modules(r#"import "lib" as lib; def one: 100; lib::increment, one"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(1), &expr).expect("failed"),
+            [json!(2), json!(100)]
+        );
+        let expr = parse_modules(r#"import "lib" as lib; lib::first_datum"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(1), &expr).expect("failed"),
+            [json!({"a": 1})]
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let expr = parse_modules(r#"include "lib"; increment | double, $data::data[1]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(1), &expr).expect("failed"),
+            [json!(4), json!({"a": 2})]
+        );
+    }
+
+    #[test]
+    fn test_modulemeta() {
+        let expr = parse_modules(r#""lib" | modulemeta | .defs"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(["double/0", "increment/0", "first_datum/0"])]
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        let expr = parse(r#"if .a then "yes" elif .b then "maybe" else "no" end"#)
+            .expect("failed to parse query");
+        let inputs = [
+            json!({"a": 1}),
+            json!({"a": false, "b": 0}),
+            json!({"a": null, "b": null}),
         ];
-        let action = Action::Filter(filter);
-        let block = Block {
-            actions: Some(vec![action]),
-            collect: false,
+        let result = query(&inputs, &expr).expect("failed");
+        assert_eq!(result, [json!("yes"), json!("maybe"), json!("no")]);
+
+        // Without else, the input is the result
+        let expr = parse(r#"[.[] | if . > 1 then . * 10 end]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3]), &expr).expect("failed"),
+            [json!([1, 20, 30])]
+        );
+    }
+
+    #[test]
+    fn test_if_each_condition() {
+        // Each result of the condition selects a branch
+        let expr =
+            parse(r#"[if (true, false, 1) then "t" else "f" end]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(["t", "f", "t"])]
+        );
+
+        // Only the selected branch is evaluated
+        let expr = parse(r#"if . then 1 else .x.y end"#).expect("failed to parse query");
+        assert_eq!(query_expr(&json!(true), &expr).expect("failed"), [json!(1)]);
+        assert!(query_expr(&json!(false), &expr).is_err());
+    }
+
+    #[test]
+    fn test_try_catch() {
+        // The handler is a single term, like the body
+        let expr = parse(r#"[.[] | try error("bad " + .) catch ("caught: " + .)]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!(["a", "b"]), &expr).expect("failed"),
+            [json!(["caught: bad a", "caught: bad b"])]
+        );
+
+        // The handler receives the value given to error
+        let expr = parse(r#"try error({code: 1}) catch .code"#).expect("failed to parse query");
+        assert_eq!(query_expr(&Value::Null, &expr).expect("failed"), [json!(1)]);
+        let expr = parse(r#"try (.a | error) catch ."#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!({"a": [1]}), &expr).expect("failed"),
+            [json!([1])]
+        );
+
+        // Other errors are caught as their message
+        let expr = parse(r#"try (1 + "a") catch ."#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(r#"number (1) and string ("a") cannot be added"#)]
+        );
+    }
+
+    #[test]
+    fn test_try_stops_at_error() {
+        // Results before the error are kept, and the rest are dropped
+        let expr = parse(r#"[try (1, error("x"), 3) catch .]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1, "x"])]
+        );
+        let expr = parse(r#"[try (1, error("x"), 3)], [(1, error("x"), 3)?]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1]), json!([1])]
+        );
+
+        // Errors in the handler are not caught
+        let expr = parse(r#"try error("x") catch error("y")"#).expect("failed to parse query");
+        let error = query_expr(&Value::Null, &expr).expect_err("should fail");
+        assert_eq!(error.to_string(), "y");
+
+        // An uncaught error ends the query
+        let expr = parse(r#"1, error("x"), 2"#).expect("failed to parse query");
+        assert!(matches!(query_expr(&Value::Null, &expr), Err(JQError::UserError(v)) if v == "x"));
+    }
+
+    #[test]
+    fn test_alternative() {
+        let expr = parse(r#"[.[] | .port // 8080]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([{"port": 80}, {}, {"port": null}]), &expr).expect("failed"),
+            [json!([80, 8080, 8080])]
+        );
+
+        // Only the truthy results of the lhs, and the rhs is not evaluated
+        let expr = parse(r#"[(false, 1, null, 2) // error("x")]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1, 2])]
+        );
+        let expr = parse(r#"[(false, null) // (3, 4)]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([3, 4])]
+        );
+        let expr = parse(r#"[.[] // 5]"#).expect("failed to parse query");
+        assert_eq!(query_expr(&json!([]), &expr).expect("failed"), [json!([5])]);
+    }
+
+    #[test]
+    fn test_alternative_errors() {
+        // Errors in the lhs end it, like `try`
+        let expr = parse(r#"[.a // "none"], [(1, error("x"), 2) // 3], [error("x") // 3]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1]), &expr).expect("failed"),
+            [json!(["none"]), json!([1]), json!([3])]
+        );
+
+        // Errors in the rhs are not dropped
+        let expr = parse(r#"null // error("y")"#).expect("failed to parse query");
+        assert!(query_expr(&Value::Null, &expr).is_err());
+    }
+
+    #[test]
+    fn test_reduce() {
+        let expr = parse(r#"reduce .[] as $x (0; . + $x), [reduce .[] as $x (0, 10; . + $x)]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3]), &expr).expect("failed"),
+            [json!(6), json!([6, 16])]
+        );
+
+        // The last result of update is used, and none is null
+        let expr = parse(r#"reduce .[] as $x (0; ., $x), reduce .[] as $x (0; select(. > 0))"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3]), &expr).expect("failed"),
+            [json!(3), Value::Null]
+        );
+
+        // Destructuring, and building an object
+        let expr = parse(r#"reduce .[] as {k: $k, $n} ({}; . + {($k): $n})"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([{"k": "a", "n": 1}, {"k": "b", "n": 2}]), &expr).expect("failed"),
+            [json!({"a": 1, "b": 2})]
+        );
+    }
+
+    #[test]
+    fn test_foreach() {
+        let expr =
+            parse(r#"[foreach .[] as $x (0; . + $x)], [foreach .[] as $x (0; . + $x; [$x, .])]"#)
+                .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3]), &expr).expect("failed"),
+            [json!([1, 3, 6]), json!([[1, 1], [2, 3], [3, 6]])]
+        );
+
+        // Every result of update is extracted, and the last is the next state
+        let expr = parse(r#"[foreach .[] as $x (1; (. + $x), (. * 10); select(. > 1))]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2]), &expr).expect("failed"),
+            [json!([2, 10, 12, 100])]
+        );
+
+        // Results are produced lazily, so the last element is never added
+        let expr = parse(r#"first(foreach .[] as $x (0; . + $x; select(. > 2)))"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3, "x"]), &expr).expect("failed"),
+            [json!(3)]
+        );
+        let expr =
+            parse(r#"[limit(2; foreach .[] as $x (0; . + $x))]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3, "x"]), &expr).expect("failed"),
+            [json!([1, 3])]
+        );
+    }
+
+    #[test]
+    fn test_label_break() {
+        let expr = parse(r#"[label $out | .[] | if . > 2 then ., break $out else . end]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([1, 2, 3, 4]), &expr).expect("failed"),
+            [json!([1, 2, 3])]
+        );
+
+        // Only the label that is broken stops
+        let expr = parse(r#"[label $a | 1, (label $b | 2, break $b, 3), 4, break $a, 5], 6"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1, 2, 4]), json!(6)]
+        );
+
+        // `try` and `?` do not catch a break
+        let expr = parse(r#"[label $out | try (1, break $out, 2) catch "caught", 3]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1])]
+        );
+        let expr =
+            parse(r#"[label $out | (1, break $out)?, 2], [label $out | null // break $out, 3]"#)
+                .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([1]), json!([])]
+        );
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let expr = parse(r#"def f: if . < 100 then .+1|f else . end; 0|f"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(100)]
+        );
+
+        // Recursion that is too deep is an error, rather than a stack overflow
+        let deepest = |n: usize| format!("def f: if . < {} then .+1|f else . end; 0|f", n);
+        let expr = parse(&deepest(MAX_CALL_DEPTH - 1)).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!(MAX_CALL_DEPTH - 1)]
+        );
+        let expr = parse(&deepest(MAX_CALL_DEPTH)).expect("failed to parse query");
+        assert!(matches!(
+            query_expr(&Value::Null, &expr),
+            Err(JQError::RecursionLimit(depth)) if depth == MAX_CALL_DEPTH
+        ));
+        let expr = parse(&deepest(100000)).expect("failed to parse query");
+        assert!(matches!(
+            query_expr(&Value::Null, &expr),
+            Err(JQError::RecursionLimit(_))
+        ));
+
+        // The limit can be changed
+        let expr = parse(&deepest(100)).expect("failed to parse query");
+        let results = |max_depth| {
+            let env = Env::new().with_max_depth(max_depth);
+            query_iter(Input::from(Value::Null), &expr, &env)
+                .map(|value| value.map(Input::into_value))
+                .collect::<Result<Vec<_>, _>>()
         };
+        assert!(matches!(results(100), Err(JQError::RecursionLimit(100))));
+        assert_eq!(results(101).expect("failed"), [json!(100)]);
+        for query_str in [
+            r#"def f(g): g | f(g); 1 | f(. + 1)"#,
+            r#"def h: .[0] | h; path(h)"#,
+        ] {
+            let expr = parse(query_str).expect("failed to parse query");
+            assert!(matches!(
+                query_expr(&Value::Null, &expr),
+                Err(JQError::RecursionLimit(_))
+            ));
+        }
+    }
 
-        let result = query_block(&vec![input], block).expect("Failed query");
-        //dbg!(&result);
-        assert_eq!(&result, &[json!("Object 1 Element 1")]);
+    #[test]
+    fn test_break_scope() {
+        // Each call has its own label
+        let expr =
+            parse(r#"def f(n): label $l | if n > 2 then break $l else n, f(n + 1) end; [f(0)]"#)
+                .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([0, 1, 2])]
+        );
+
+        // A break passed as an argument stops the caller's label, not the
+        // label with the same name in the function
+        let expr = parse(r#"def f(g): label $l | g, 2; [label $l | f(break $l), 3]"#)
+            .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&Value::Null, &expr).expect("failed"),
+            [json!([])]
+        );
+    }
+
+    #[test]
+    fn test_optional() {
+        // Skip the records that fail, rather than aborting
+        let expr = parse(r#"[.[] | (.a + 1)?]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([{"a": 1}, {"a": "x"}, {"a": 2}]), &expr).expect("failed"),
+            [json!([2, 3])]
+        );
+
+        // `?` on a stream drops the results after the first error
+        let expr = parse(r#"[.[] | (.a)?], [(.[] | .a)?]"#).expect("failed to parse query");
+        assert_eq!(
+            query_expr(&json!([{"a": 1}, [2], {"a": 3}]), &expr).expect("failed"),
+            [json!([1, 3]), json!([1])]
+        );
+    }
+
+    #[test]
+    fn test_parentheses() {
+        let input = json!({"a": [1, 2], "b": "xyz", "x": {"y": {"z": 1}}, "w": 2});
+        let expr = parse(r#"(.a, .b) | length"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!(2), json!(3)]);
+
+        let expr = parse(r#".x | (.y | .z), 2"#).expect("failed to parse query");
+        let result = query(std::slice::from_ref(&input), &expr).expect("failed");
+        assert_eq!(result, [json!(1), json!(2)]);
+
+        let expr =
+            parse(r#"(1 + 2) * 3, [.a[], .w][1:], (.x).y["z"]"#).expect("failed to parse query");
+        let result = query(&[input], &expr).expect("failed");
+        assert_eq!(result, [json!(9), json!(2), json!(2), json!(1)]);
     }
 
     #[test]
@@ -395,8 +1877,8 @@ mod tests {
         let json = include_bytes!("../test/basic.json");
         let input: Value = serde_json::from_slice(json).expect("Failed to parse json");
 
-        let filter = vec![Token::Identity];
-        let result = query_filter(&[input.clone()], &filter).expect("Failed query");
+        let expr = Expr::Identity;
+        let result = query(std::slice::from_ref(&input), &expr).expect("Failed query");
 
         //dbg!(&result);
         assert_eq!(&result, &vec![input]);
@@ -407,13 +1889,13 @@ mod tests {
         let json = include_str!("../test/basic.json");
         let input: Value = serde_json::from_str(json).expect("Failed to parse");
 
-        let filter = vec![
-            Token::Identity,
-            Token::Ident("object_1", false),
-            Token::Ident("elem_1", false),
-        ];
+        let expr = Expr::path(vec![
+            Expr::Identity,
+            Expr::Ident("object_1".to_string(), false),
+            Expr::Ident("elem_1".to_string(), false),
+        ]);
 
-        let result = query_filter(&[input], &filter).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
         assert_eq!(&result, &[json!("Object 1 Element 1")]);
     }
 
@@ -422,12 +1904,12 @@ mod tests {
         let json = include_str!("../test/basic.json");
         let input: Value = serde_json::from_str(json).expect("Failed to parse");
 
-        let filter = vec![
-            Token::Ident("object_1", false),
-            Token::Ident("elem_1", false),
-        ];
+        let expr = Expr::path(vec![
+            Expr::Ident("object_1".to_string(), false),
+            Expr::Ident("elem_1".to_string(), false),
+        ]);
 
-        let result = query_filter(&[input], &filter).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
         assert_eq!(&result, &[json!("Object 1 Element 1")]);
     }
 
@@ -435,9 +1917,12 @@ mod tests {
     fn test_filter_array_with_identity() {
         let input = json!([{"name":"JSON", "good":true}, {"name":"XML", "good":false}]);
 
-        let filter = vec![Token::Identity, Token::Index(IndexType::from((0, false)))];
+        let expr = Expr::path(vec![
+            Expr::Identity,
+            Expr::Index(IndexType::from((0, false))),
+        ]);
 
-        let result = query_filter(&[input], &filter).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
         //dbg!(&result);
         assert_eq!(
             &result,
@@ -454,9 +1939,9 @@ mod tests {
     fn test_filter_array() {
         let input = json!([{"name":"JSON", "good":true}, {"name":"XML", "good":false}]);
 
-        let filter = vec![Token::Index(IndexType::from((0, false)))];
+        let expr = Expr::Index(IndexType::from((0, false)));
 
-        let result = query_filter(&[input], &filter).expect("Failed query");
+        let result = query(&[input], &expr).expect("Failed query");
         //dbg!(&result);
         assert_eq!(
             &result,
@@ -472,16 +1957,18 @@ mod tests {
     #[test]
     fn test_query_object_by_ident() {
         let value = json!({"elem1":"element 1"});
-        let result =
-            query_object_ident(value.as_object().unwrap(), "elem1").expect("Failed to query");
+        let result = owned(
+            query_object_ident(value.as_object().unwrap(), "elem1").expect("Failed to query"),
+        );
         assert_eq!(result, vec![json!("element 1")]);
     }
 
     #[test]
     fn test_query_nested_object_by_ident() {
         let value = json!({"object_1":{"elem1":"element 1"}});
-        let result =
-            query_object_ident(value.as_object().unwrap(), "object_1").expect("query failed");
+        let result = owned(
+            query_object_ident(value.as_object().unwrap(), "object_1").expect("query failed"),
+        );
 
         assert_eq!(result, vec![json!({"elem1":"element 1"})]);
     }
@@ -490,7 +1977,8 @@ mod tests {
     fn test_object_by_index() {
         let value = json!({"object_1":{"elem1":"element 1"}});
         let index = IndexType::from(("object_1", false));
-        let result = query_object_index(value.as_object().unwrap(), &index).expect("query failed");
+        let result =
+            owned(query_object_index(value.as_object().unwrap(), &index).expect("query failed"));
         // dbg!(&result);
         assert_eq!(result, vec![json!({"elem1":"element 1"})]);
     }
@@ -499,7 +1987,7 @@ mod tests {
     fn test_by_by_index_oob_silent() {
         let value = json!(["0", "1", "2"]);
         let index = IndexType::from((3, true));
-        let result = query_array_index(value.as_array().unwrap(), &index).expect("Failed");
+        let result = owned(query_array_index(value.as_array().unwrap(), &index).expect("Failed"));
         //dbg!(&result);
         assert_eq!(&result, &[json!(null)]);
     }
@@ -508,7 +1996,8 @@ mod tests {
     fn test_object_by_empty_index() {
         let object = json!({"a":"elem a", "b":"elem b"});
         let range = RangeType::new();
-        let result = query_object_range(object.as_object().unwrap(), &range).expect("query failed");
+        let result =
+            owned(query_object_range(object.as_object().unwrap(), &range).expect("query failed"));
         //dbg!(&result);
         assert_eq!(result, vec![json!("elem a"), json!("elem b")]);
     }
@@ -517,7 +2006,8 @@ mod tests {
     fn test_array_by_index() {
         let value = json!(["0", "1", "2"]);
         let index = IndexType::from((0, false));
-        let result = query_array_index(value.as_array().unwrap(), &index).expect("query failed");
+        let result =
+            owned(query_array_index(value.as_array().unwrap(), &index).expect("query failed"));
         //dbg!(&result);
         assert_eq!(result, vec![json!("0")]);
     }
@@ -526,7 +2016,8 @@ mod tests {
     fn test_array_negative_index() {
         let value = json!(["0", "1", "2"]);
         let index = IndexType::from((-2, false));
-        let result = query_array_index(value.as_array().unwrap(), &index).expect("query failed");
+        let result =
+            owned(query_array_index(value.as_array().unwrap(), &index).expect("query failed"));
         //dbg!(&result);
         assert_eq!(result, vec![json!("1")]);
     }
@@ -535,7 +2026,7 @@ mod tests {
     fn test_array_by_index_oob() {
         let value = json!(["0", "1", "2"]);
         let index = IndexType::from((3, false));
-        let result = query_array_index(value.as_array().unwrap(), &index).expect("Failed");
+        let result = owned(query_array_index(value.as_array().unwrap(), &index).expect("Failed"));
         //dbg!(&result);
         assert_eq!(&result, &[json!(null)]);
     }
@@ -544,7 +2035,7 @@ mod tests {
     fn test_array_by_index_oob_silent() {
         let value = json!(["0", "1", "2"]);
         let index = IndexType::from((3, true));
-        let result = query_array_index(value.as_array().unwrap(), &index).expect("Failed");
+        let result = owned(query_array_index(value.as_array().unwrap(), &index).expect("Failed"));
         //dbg!(&result);
         assert_eq!(&result, &[json!(null)]);
     }
@@ -553,7 +2044,57 @@ mod tests {
     fn test_array_by_empty_range() {
         let array = json!(["0", "1", "2"]);
         let range = RangeType::new();
-        let result = query_array_range(array.as_array().unwrap(), &range).expect("query failed");
+        let result =
+            owned(query_array_range(array.as_array().unwrap(), &range).expect("query failed"));
         assert_eq!(result, vec![json!("0"), json!("1"), json!("2")]);
     }
+
+    #[test]
+    fn test_iterate_scalar() {
+        // Only arrays and objects can be iterated, as in jq
+        for (input, name) in [
+            (json!(null), "null"),
+            (json!(1), "number"),
+            (json!(true), "boolean"),
+            (json!("ab"), "string"),
+        ] {
+            let expr = parse(".[]").expect("failed to parse query");
+            assert!(matches!(
+                query_expr(&input, &expr),
+                Err(JQError::CannotIterate(result)) if result == name
+            ));
+            let expr = parse("[.[]?], [[.][][]?]").expect("failed to parse query");
+            assert_eq!(
+                query_expr(&input, &expr).expect("failed"),
+                [json!([]), json!([])]
+            );
+        }
+    }
+
+    #[test]
+    fn test_range_out_of_bounds() {
+        let input = json!({"a": [1, 2, 3], "s": "abc"});
+        let expr = parse(
+            "[.a[5:]], [.a[-10:10]], [.a[2:1]], [.a[:-10]], [.a | getpath(path(.[-10:1]))], .s[5:], .s[-10:2]",
+        )
+        .expect("failed to parse query");
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [
+                json!([]),
+                json!([1, 2, 3]),
+                json!([]),
+                json!([]),
+                json!([[1]]),
+                json!(""),
+                json!("ab")
+            ]
+        );
+        // The same, when the array is owned
+        let expr = parse("[[.a[]][5:]], [[.a[]][-10:10]]").expect("failed to parse query");
+        assert_eq!(
+            query_expr(&input, &expr).expect("failed"),
+            [json!([]), json!([1, 2, 3])]
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::JQError;
@@ -9,7 +10,7 @@ use crate::JQError;
 /// * `[isize:]` : returns all elements from stop to end of the array
 /// * `[:isize]`
 /// * `[:]`
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
 pub struct RangeType {
     start: Option<isize>,
     end: Option<isize>,
@@ -58,44 +59,39 @@ impl RangeType {
         }
     }
 
+    /// The start index, if there is one
+    pub fn start(&self) -> Option<isize> {
+        self.start
+    }
+
+    /// The end index, if there is one
+    pub fn end(&self) -> Option<isize> {
+        self.end
+    }
+
     /// True if start and end are both None
     pub fn is_empty(&self) -> bool {
         self.start.is_none() && self.end.is_none()
     }
 
-    /// Return the values as a slice
+    /// Return the values as a slice.  As in jq, out of range indexes are
+    /// clamped to the array, so `start <= end <= len`.
     pub fn as_slice(&self, len: usize) -> (usize, usize) {
         let ilen = len as isize;
-        let mut start = self.start.unwrap_or(0);
-        let mut end = self.end.unwrap_or(ilen);
-
-        // If start is negative, it is an inclusive reference from the end of the array
-        if start < 0 {
-            start += ilen;
-        }
-
-        // If end is negative, it is an exclusive reference from the end of the array
-        if end < 0 {
-            end += ilen;
-        }
-
-        if end > ilen {
-            end = ilen
-        }
-
-        // At this point, start and end should never be negative
-        assert!(start >= 0);
-        assert!(end >= 0);
-        (start as usize, end as usize)
+        // A negative index is a reference from the end of the array
+        let clamp = |index: isize| {
+            let index = if index < 0 { index + ilen } else { index };
+            index.clamp(0, ilen) as usize
+        };
+        let start = clamp(self.start.unwrap_or(0));
+        let end = clamp(self.end.unwrap_or(ilen));
+        (start, end.max(start))
     }
 }
 
 impl From<Option<RangeType>> for RangeType {
     fn from(r: Option<RangeType>) -> Self {
-        match r {
-            Some(range) => range,
-            _ => Self::new(),
-        }
+        r.unwrap_or_default()
     }
 }
 
@@ -106,7 +102,7 @@ impl From<Option<RangeType>> for RangeType {
 /// to index `15` (exclusive). Either index may be negative (in which case
 /// it counts backwards from the end of the array), or omitted (in which
 /// case it refers to the start or end of the array).
-pub fn from_range(array: &Vec<Value>, range: &RangeType) -> Result<Vec<Value>, JQError> {
+pub fn from_range<'v>(array: &'v [Value], range: &RangeType) -> Result<&'v [Value], JQError> {
     let len = array.len();
     let (start, end) = range.as_slice(len);
     // Inclusive start = 0 <= x < len
@@ -118,9 +114,7 @@ pub fn from_range(array: &Vec<Value>, range: &RangeType) -> Result<Vec<Value>, J
     //        return Ok(Vec::new());
     //    }
 
-    println!("RangeType {:?} - {:?}:{:?}", array.len(), &start, &end);
-    let val = array[start as usize..end as usize].to_vec();
-    Ok(val)
+    Ok(&array[start..end])
 }
 
 #[cfg(test)]
@@ -212,5 +206,17 @@ mod tests {
         let result = from_range(&array, &range).expect("Failed");
         let cmp_array = make_array(r#"["9"]"#);
         assert_eq!(&result, &cmp_array);
+
+        // Out of range bounds are clamped to the array, as in jq
+        assert_eq!(RangeType::from_start(15).as_slice(10), (10, 10));
+        assert_eq!(RangeType::from_both(15, 5).as_slice(10), (10, 10));
+        assert_eq!(RangeType::from_both(-20, 20).as_slice(10), (0, 10));
+        assert_eq!(RangeType::from_end(-20).as_slice(10), (0, 0));
+        assert_eq!(RangeType::from_both(8, -5).as_slice(10), (8, 8));
+
+        let range = RangeType::from_both(-20, 2);
+        let result = from_range(&array, &range).expect("Failed");
+        let cmp_array = make_array(r#"["0", "1"]"#);
+        assert_eq!(&result, &cmp_array);
     }
 }
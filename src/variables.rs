@@ -0,0 +1,172 @@
+/// Variables defined outside of a query.
+///
+/// Values are bound to `$name` variables when the query runs, so untrusted
+/// input never needs to be formatted into the query string.  Every query can
+/// also use the predefined variables:
+/// * `$ENV` : an object of the process environment variables
+/// * `$ARGS` : `{"positional": [...], "named": {...}}`, the positional arguments
+///   and every named variable
+/// * `$__prog_args` : the same as `$ARGS.positional`
+/// * `$__named` : the same as `$ARGS.named`
+use std::sync::OnceLock;
+
+use serde_json::{json, Map, Value};
+
+use crate::{Env, Input};
+
+/// The names of the variables that every query can use
+pub const PREDEFINED: [&str; 4] = ["ENV", "ARGS", "__prog_args", "__named"];
+
+/// `$ARGS` with no positional or named arguments
+fn no_args() -> &'static Value {
+    static NO_ARGS: OnceLock<Value> = OnceLock::new();
+    NO_ARGS.get_or_init(|| json!({"positional": [], "named": {}}))
+}
+
+/// `$ENV`, read from the process environment the first time it is needed
+fn environment() -> &'static Value {
+    static ENVIRONMENT: OnceLock<Value> = OnceLock::new();
+    ENVIRONMENT.get_or_init(|| {
+        Value::Object(
+            std::env::vars()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect::<Map<String, Value>>(),
+        )
+    })
+}
+
+/// Bind the predefined variables, with `args` as `$ARGS`
+fn bind_predefined(args: &Value) -> Env<'_> {
+    Env::new()
+        .bind("ENV", Input::Borrowed(environment()))
+        .bind("ARGS", Input::Borrowed(args))
+        .bind("__prog_args", Input::Borrowed(&args["positional"]))
+        .bind("__named", Input::Borrowed(&args["named"]))
+}
+
+/// An environment with only the predefined variables
+pub(crate) fn predefined<'a>() -> Env<'a> {
+    bind_predefined(no_args())
+}
+
+/// Named and positional values for a query, ie: `$name` and `$__prog_args`.
+/// They are kept in the shape of `$ARGS`, so that every variable can borrow its value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variables {
+    args: Value,
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Variables {
+    /// No variables besides the predefined ones
+    pub fn new() -> Self {
+        Self {
+            args: no_args().clone(),
+        }
+    }
+
+    /// The named variables, `$ARGS.named`
+    fn named(&self) -> &Map<String, Value> {
+        match &self.args["named"] {
+            Value::Object(named) => named,
+            _ => unreachable!("$ARGS.named is always an object"),
+        }
+    }
+
+    /// Bind `value` to `$name`.  A later value for the same name replaces an earlier one.
+    pub fn with_var(mut self, name: &str, value: Value) -> Self {
+        if let Value::Object(named) = &mut self.args["named"] {
+            named.insert(name.to_string(), value);
+        }
+        self
+    }
+
+    /// Set the positional arguments, `$ARGS.positional`
+    pub fn with_positional(mut self, values: Vec<Value>) -> Self {
+        self.args["positional"] = Value::Array(values);
+        self
+    }
+
+    /// The names of the variables, including the predefined ones
+    pub fn names(&self) -> Vec<&str> {
+        PREDEFINED
+            .into_iter()
+            .chain(self.named().keys().map(String::as_str))
+            .collect()
+    }
+
+    /// The value of the named variable `$name`
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.named().get(name)
+    }
+
+    /// `$ARGS`, the positional arguments and the named variables
+    pub fn args(&self) -> &Value {
+        &self.args
+    }
+
+    /// An environment with the predefined variables, followed by these variables
+    pub(crate) fn env(&self) -> Env<'_> {
+        self.named()
+            .iter()
+            .fold(bind_predefined(&self.args), |env, (name, value)| {
+                env.bind(name, Input::Borrowed(value))
+            })
+    }
+}
+
+impl<'s> From<&[(&'s str, Value)]> for Variables {
+    fn from(vars: &[(&'s str, Value)]) -> Self {
+        vars.iter().fold(Self::new(), |vars, (name, value)| {
+            vars.with_var(name, value.clone())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_names() {
+        let vars = Variables::new()
+            .with_var("a", json!(1))
+            .with_var("b", json!(2));
+        assert_eq!(
+            vars.names(),
+            ["ENV", "ARGS", "__prog_args", "__named", "a", "b"]
+        );
+    }
+
+    #[test]
+    fn test_env() {
+        let vars = Variables::from(&[("a", json!(1)), ("a", json!(2))][..])
+            .with_positional(vec![json!("x")]);
+        let env = vars.env();
+        assert_eq!(env.get("a").map(|x| &**x), Some(&json!(2)));
+        assert_eq!(vars.get("a"), Some(&json!(2)));
+        assert_eq!(env.get("__prog_args").map(|x| &**x), Some(&json!(["x"])));
+        assert!(env.get("ENV").expect("missing").is_object());
+        assert_eq!(
+            env.get("ARGS").map(|x| &**x),
+            Some(&json!({"positional": ["x"], "named": {"a": 2}}))
+        );
+        assert_eq!(env.get("__named").map(|x| &**x), Some(&json!({"a": 2})));
+    }
+
+    #[test]
+    fn test_predefined() {
+        let env = predefined();
+        assert_eq!(env.get("__prog_args").map(|x| &**x), Some(&json!([])));
+        assert_eq!(env.get("__named").map(|x| &**x), Some(&json!({})));
+        if let Ok(path) = std::env::var("PATH") {
+            assert_eq!(env.get("ENV").expect("missing")["PATH"], json!(path));
+        }
+    }
+}